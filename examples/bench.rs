@@ -0,0 +1,133 @@
+// Times the scalar multiplications with precomputed tables and wNAF against
+// the plain double-and-add they replaced, and signature verification one by
+// one against the batches blocks are verified with.
+//
+//     cargo run --release --example bench [ITERATIONS]
+use std::{cell::RefCell, hint::black_box, time::{Duration, Instant}};
+use crypto::{
+    blockchain::{
+        script::{verify_script, Script, SignatureBatch, TransactionChecker},
+        transaction::{Transaction, TxInput, TxOutput, SEQUENCE_FINAL}
+    },
+    ecdsa::{self, point::{self, AffinePoint, JacobianPoint}, secp256k1::{self, BARRET_MU_N, G}, ECDSAPublicKey, RecoverableSignature},
+    math::{algorithms, big_int::{BigInt, BigIntMod}, random},
+    sha256::Sha256
+};
+
+// Signatures per batch, about the inputs of a full block of small transactions
+const BATCH_SIZE: usize = 64;
+
+fn random_scalar() -> BigInt<4> {
+    let mut bytes = [0u8; 32];
+    random::get_random_bytes(&mut bytes).expect("Failed to get random bytes");
+    BigInt::from_bytes_be(&bytes)
+}
+
+fn time<T, F: FnMut(usize) -> T>(iterations: usize, mut f: F) -> Duration {
+    let start = Instant::now();
+    for i in 0..iterations {
+        black_box(f(i));
+    }
+    start.elapsed() / iterations as u32
+}
+
+fn report(name: &str, old: Duration, new: Duration) {
+    println!("{:<24} {:>12?} {:>12?} {:>8.1}x", name, old, new, old.as_secs_f64() / new.as_secs_f64());
+}
+
+// ecdsa::verify as it was before Shamir's trick
+fn verify_double_and_add(signature: AffinePoint, message: &[u8], public_key: &ECDSAPublicKey) -> bool {
+    let n = || secp256k1::N.resize::<12>();
+    let z: BigInt<4> = Sha256::hash(message).to_bigint().resize();
+    let z = BigIntMod::<12>::new_reduce(z.resize(), n(), BARRET_MU_N);
+    let s_inv = algorithms::mod_inverse(signature.y.resize::<12>(), n());
+    let s_inv = BigIntMod::<12>::new_with_mu(s_inv, n(), BARRET_MU_N);
+    let u1 = z * s_inv;
+    let u2 = BigIntMod::new_with_mu(signature.x.resize(), n(), BARRET_MU_N) * s_inv;
+    let p = (G.scalar_multiply_double_and_add(u1.integer.resize()) + public_key.key.scalar_multiply_double_and_add(u2.integer.resize())).to_affine();
+    BigIntMod::<12>::new_reduce(p.x.resize(), n(), BARRET_MU_N).integer == signature.x.resize()
+}
+
+// A transaction spending BATCH_SIZE P2PKH outputs the way the wallet signs them
+fn signed_transaction() -> (Transaction, Script) {
+    let (public_key, private_key) = ecdsa::generate_keypair();
+    let lock = Script::p2pkh(&public_key);
+    let mut tx = Transaction::new();
+    for vout in 0..BATCH_SIZE {
+        tx.add_input(TxInput { txid: Sha256::hash(b"previous"), vout: vout as u32, script_sig: Script::new(), sequence: SEQUENCE_FINAL });
+    }
+    tx.add_output(TxOutput { value: 10, script_pubkey: Script::new(), spent: false });
+    let signatures: Vec<RecoverableSignature> = (0..BATCH_SIZE)
+        .map(|i| ecdsa::sign_recoverable(tx.get_input_hash(i, &lock).bytes(), &private_key))
+        .collect();
+    for (input, signature) in tx.inputs.iter_mut().zip(&signatures) {
+        input.script_sig = Script::p2pkh_unlock_recoverable(signature, &public_key);
+    }
+    (tx, lock)
+}
+
+fn main() {
+    let iterations: usize = std::env::args().nth(1).map_or(100, |arg| arg.parse().expect("ITERATIONS has to be a number"));
+    let scalars: Vec<BigInt<4>> = (0..iterations.max(BATCH_SIZE)).map(|_| random_scalar()).collect();
+    let points: Vec<AffinePoint> = scalars.iter().map(|scalar| G.scalar_multiply(*scalar).to_affine()).collect();
+
+    println!("{:<24} {:>12} {:>12} {:>9}", "", "old", "new", "speedup");
+    report(
+        "k * G",
+        time(iterations, |i| G.scalar_multiply_double_and_add(scalars[i])),
+        time(iterations, |i| G.scalar_multiply(scalars[i])),
+    );
+    report(
+        "k * P",
+        time(iterations, |i| points[i].scalar_multiply_double_and_add(scalars[i])),
+        time(iterations, |i| points[i].scalar_multiply(scalars[i])),
+    );
+    report(
+        "u1 * G + u2 * Q",
+        time(iterations, |i| G.scalar_multiply_double_and_add(scalars[i]) + points[i].scalar_multiply_double_and_add(scalars[i])),
+        time(iterations, |i| point::shamir_multiply(scalars[i], &points[i], scalars[i])),
+    );
+    let terms: Vec<(AffinePoint, BigInt<4>)> = points.iter().copied().zip(scalars.iter().copied()).take(BATCH_SIZE).collect();
+    report(
+        &format!("sum of {} k * P", terms.len()),
+        time(iterations, |_| {
+            terms.iter().fold(JacobianPoint::from_affine(&AffinePoint::infinity()), |sum, (point, scalar)| sum + point.scalar_multiply_double_and_add(*scalar))
+        }),
+        time(iterations, |_| JacobianPoint::multi_scalar_multiply(&terms)),
+    );
+
+    // Old is double-and-add for ECDSA and checking one by one for batches
+    let (public_key, private_key) = ecdsa::generate_keypair();
+    let messages: Vec<[u8; 8]> = (0..iterations.max(BATCH_SIZE)).map(|i| i.to_le_bytes()).collect();
+    let signatures: Vec<RecoverableSignature> = messages.iter().map(|message| ecdsa::sign_recoverable(message, &private_key)).collect();
+    println!();
+    println!("ECDSA sign               {:>12?}", time(iterations, |i| ecdsa::sign(&messages[i], &private_key)));
+    report(
+        "ECDSA verify",
+        time(iterations, |i| assert!(verify_double_and_add(signatures[i].signature, &messages[i], &public_key))),
+        time(iterations, |i| assert!(ecdsa::verify(signatures[i].signature, &messages[i], &public_key))),
+    );
+    let items: Vec<(&[u8], RecoverableSignature, &ECDSAPublicKey)> = messages.iter().zip(&signatures)
+        .take(BATCH_SIZE)
+        .map(|(message, signature)| (&message[..], *signature, &public_key))
+        .collect();
+    report(
+        &format!("{} ECDSA signatures", items.len()),
+        time(iterations, |_| assert!(items.iter().all(|(message, signature, public_key)| ecdsa::verify(signature.signature, message, public_key)))),
+        time(iterations, |_| assert!(ecdsa::verify_batch(&items))),
+    );
+
+    // The signatures of a block as verify_new_block collects them
+    let (tx, lock) = signed_transaction();
+    let batch = RefCell::new(SignatureBatch::new());
+    for (i, input) in tx.inputs.iter().enumerate() {
+        let checker = TransactionChecker { transaction: &tx, input: i, script_code: &lock, strict: false, batch: Some(&batch) };
+        verify_script(&input.script_sig, &lock, &checker).expect("The wallet signatures are valid");
+    }
+    let batch = batch.into_inner();
+    report(
+        &format!("block of {} inputs", batch.len()),
+        time(iterations, |_| assert!(batch.invalid_inputs().is_empty())),
+        time(iterations, |_| assert!(batch.verify())),
+    );
+}
@@ -1,3 +1,313 @@
+// A full node daemon. Keeps its chain and key in a data directory, connects
+// to the configured peers and optionally mines, until SIGINT or SIGTERM.
+//
+//     cargo run --release --example node -- [--datadir DIR] [--config FILE]
+//
+// The config file defaults to node.conf in the data directory and is created
+// with the default settings if it does not exist.
+use std::{
+    fmt, net::SocketAddr, path::{Path, PathBuf}, process,
+    sync::Arc, thread, time::{Duration, Instant}
+};
+use crypto::{
+    blockchain::{encoding::{Decode, Encode}, Blockchain},
+    ecdsa::{self, ECDSAPrivateKey, ECDSAPublicKey},
+    net::Network, node::Node, rpc::RpcServer, util
+};
+
+const DEFAULT_DATA_DIR: &str = "node-data";
+const CONFIG_FILE: &str = "node.conf";
+const KEY_FILE: &str = "node.key";
+const CHAIN_DIR: &str = "chain";
+
+const DEFAULT_CONFIG: &str = "\
+# Address the node accepts peer connections on
+listen = 0.0.0.0:8333
+
+# Address of the JSON-RPC server, leave empty to disable it
+rpc = 127.0.0.1:8332
+
+# Peers to connect to, one line each
+# peer = 192.168.1.2:8333
+
+# Mine blocks, paying the rewards to miner_key if set and to the node key otherwise
+mine = false
+# miner_key = <hex encoded public key>
+
+# error, warn, info or debug
+log_level = info
+";
+
+const LOOP_INTERVAL: Duration = Duration::from_millis(100);
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "error" => Some(LogLevel::Error),
+            "warn" => Some(LogLevel::Warn),
+            "info" => Some(LogLevel::Info),
+            "debug" => Some(LogLevel::Debug),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        };
+        write!(f, "{:<5}", name)
+    }
+}
+
+struct Logger {
+    level: LogLevel,
+}
+
+impl Logger {
+    fn log(&self, level: LogLevel, message: fmt::Arguments) {
+        if level <= self.level {
+            eprintln!("{} {} {}", util::timestamp(), level, message);
+        }
+    }
+}
+
+macro_rules! error { ($log:expr, $($arg:tt)*) => { $log.log(LogLevel::Error, format_args!($($arg)*)) } }
+macro_rules! warn { ($log:expr, $($arg:tt)*) => { $log.log(LogLevel::Warn, format_args!($($arg)*)) } }
+macro_rules! info { ($log:expr, $($arg:tt)*) => { $log.log(LogLevel::Info, format_args!($($arg)*)) } }
+macro_rules! debug { ($log:expr, $($arg:tt)*) => { $log.log(LogLevel::Debug, format_args!($($arg)*)) } }
+
+struct Config {
+    listen: SocketAddr,
+    rpc: Option<SocketAddr>,
+    peers: Vec<SocketAddr>,
+    mine: bool,
+    miner_key: Option<ECDSAPublicKey>,
+    log_level: LogLevel,
+}
+
+impl Config {
+    // key = value lines, # starts a comment
+    fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config {
+            listen: "0.0.0.0:8333".parse().unwrap(),
+            rpc: None,
+            peers: Vec::new(),
+            mine: false,
+            miner_key: None,
+            log_level: LogLevel::Info,
+        };
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: &str| format!("line {}: {}", idx + 1, message);
+            let (key, value) = line.split_once('=').ok_or_else(|| error("expected key = value"))?;
+            let value = value.trim();
+            let address = || value.parse::<SocketAddr>().map_err(|_| error("invalid address"));
+            match key.trim() {
+                "listen" => config.listen = address()?,
+                "rpc" if value.is_empty() => config.rpc = None,
+                "rpc" => config.rpc = Some(address()?),
+                "peer" => config.peers.push(address()?),
+                "mine" => config.mine = value.parse().map_err(|_| error("mine must be true or false"))?,
+                "miner_key" => {
+                    let key = util::hex_decode(value).and_then(|bytes| ECDSAPublicKey::from_bytes(&bytes).ok());
+                    config.miner_key = Some(key.ok_or_else(|| error("invalid public key"))?);
+                }
+                "log_level" => config.log_level = LogLevel::parse(value).ok_or_else(|| error("unknown log level"))?,
+                key => return Err(error(&format!("unknown setting {}", key)))
+            }
+        }
+        Ok(config)
+    }
+}
+
+// Sets a flag on SIGINT and SIGTERM so the main loop can shut down cleanly
+#[cfg(unix)]
+mod signal {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+
+    extern "C" fn handle(_: i32) {
+        RECEIVED.store(true, Ordering::SeqCst);
+    }
+
+    pub fn install() {
+        unsafe {
+            signal(SIGINT, handle);
+            signal(SIGTERM, handle);
+        }
+    }
+
+    pub fn received() -> bool {
+        RECEIVED.load(Ordering::SeqCst)
+    }
+}
+
+// Elsewhere the process is simply killed
+#[cfg(not(unix))]
+mod signal {
+    pub fn install() {}
+
+    pub fn received() -> bool {
+        false
+    }
+}
+
+fn fail(message: fmt::Arguments) -> ! {
+    eprintln!("{} {} {}", util::timestamp(), LogLevel::Error, message);
+    process::exit(1);
+}
+
+fn parse_args() -> (PathBuf, Option<PathBuf>) {
+    let mut data_dir = PathBuf::from(DEFAULT_DATA_DIR);
+    let mut config = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--datadir", Some(value)) => data_dir = PathBuf::from(value),
+            ("--config", Some(value)) => config = Some(PathBuf::from(value)),
+            _ => fail(format_args!("usage: node [--datadir DIR] [--config FILE]"))
+        }
+    }
+    (data_dir, config)
+}
+
+fn load_config(path: &Path) -> Config {
+    if !path.exists() {
+        if let Err(e) = std::fs::write(path, DEFAULT_CONFIG) {
+            fail(format_args!("could not write {}: {}", path.display(), e));
+        }
+    }
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| fail(format_args!("could not read {}: {}", path.display(), e)));
+    Config::parse(&text).unwrap_or_else(|e| fail(format_args!("{}: {}", path.display(), e)))
+}
+
+fn load_or_create_key(path: &Path, log: &Logger) -> (ECDSAPublicKey, ECDSAPrivateKey) {
+    if path.exists() {
+        let private_key = ECDSAPrivateKey::load(path.to_str().unwrap());
+        return (private_key.public_key(), private_key);
+    }
+    let keys = ecdsa::generate_keypair();
+    keys.1.save(path.to_str().unwrap());
+    info!(log, "created node key {}", path.display());
+    keys
+}
+
 fn main() {
-    
-}
\ No newline at end of file
+    signal::install();
+    let (data_dir, config_path) = parse_args();
+    if let Err(e) = std::fs::create_dir_all(&data_dir) {
+        fail(format_args!("could not create {}: {}", data_dir.display(), e));
+    }
+    let config = load_config(&config_path.unwrap_or_else(|| data_dir.join(CONFIG_FILE)));
+    let log = Logger { level: config.log_level };
+
+    let keys = load_or_create_key(&data_dir.join(KEY_FILE), &log);
+    info!(log, "node key {}", util::hex_encode(&keys.0.to_bytes()));
+    let blockchain = Blockchain::open(&data_dir.join(CHAIN_DIR))
+        .unwrap_or_else(|e| fail(format_args!("could not open the chain in {}: {:?}", data_dir.display(), e)));
+    info!(log, "loaded {} blocks from {}", blockchain.blocks.len(), data_dir.display());
+
+    let mut node = Node::new("node", blockchain, keys);
+    if let Some(miner_key) = config.miner_key.clone() {
+        node.set_miner_key(miner_key);
+    }
+    let network = Network::start(node, config.listen).unwrap_or_else(|e| fail(format_args!("could not listen on {}: {:?}", config.listen, e)));
+    let network = Arc::new(network);
+    info!(log, "listening on {}", network.local_addr());
+    let mut rpc = config.rpc.map(|addr| {
+        let server = RpcServer::start(network.clone(), addr).unwrap_or_else(|e| fail(format_args!("could not start RPC on {}: {}", addr, e)));
+        info!(log, "RPC server on {}", server.local_addr());
+        server
+    });
+
+    let mut last_connect: Option<Instant> = None;
+    let mut last_status = Instant::now();
+    let mut last_progress = None;
+    let mut last_peer_count = 0;
+    while !signal::received() {
+        // Peers that are not connected are retried now and then
+        if last_connect.is_none_or(|last| last.elapsed() >= RECONNECT_INTERVAL) {
+            let connected = network.peers();
+            for peer in config.peers.iter().filter(|peer| !connected.contains(peer)) {
+                match network.connect(*peer) {
+                    Ok(()) => debug!(log, "connecting to {}", peer),
+                    Err(e) => warn!(log, "could not connect to {}: {:?}", peer, e)
+                }
+            }
+            last_connect = Some(Instant::now());
+        }
+
+        let progress = network.sync_progress();
+        if last_status.elapsed() >= STATUS_INTERVAL {
+            let peer_count = network.peer_count();
+            if peer_count != last_peer_count {
+                info!(log, "{} peers connected", peer_count);
+                last_peer_count = peer_count;
+            }
+            if last_progress.as_ref() != Some(&progress) {
+                if !progress.is_synced() {
+                    info!(log, "syncing: {}", progress);
+                } else {
+                    debug!(log, "chain at {} blocks", progress.blocks);
+                }
+                last_progress = Some(progress.clone());
+            }
+            last_status = Instant::now();
+        }
+
+        // Mining before catching up would only build on an outdated tip. A
+        // node without a chain that has peers to ask never mines a genesis
+        // block of its own.
+        let can_mine = progress.is_synced() && (progress.blocks > 0 || config.peers.is_empty() || network.peer_count() > 0);
+        if config.mine && can_mine {
+            // Peers are served while mining, a block from one of them
+            // restarts the work on the new tip
+            match network.mine(signal::received) {
+                Ok(Some(block)) => info!(log, "mined block {} at height {}", block.hash, progress.blocks),
+                Ok(None) => {}
+                Err(e) => {
+                    error!(log, "could not mine a block: {:?}", e);
+                    thread::sleep(LOOP_INTERVAL);
+                }
+            }
+        } else {
+            thread::sleep(LOOP_INTERVAL);
+        }
+    }
+
+    info!(log, "shutting down");
+    if let Some(rpc) = rpc.as_mut() {
+        rpc.shutdown();
+    }
+    match Arc::try_unwrap(network) {
+        Ok(mut network) => network.shutdown(),
+        Err(_) => error!(log, "network still in use, exiting without waiting for it")
+    }
+    info!(log, "stopped");
+}
@@ -1,3 +1,217 @@
+// A wallet holding a single key. Its coins are looked up through the JSON-RPC
+// server of a node or by reading the chain directory of a node directly.
+//
+//     cargo run --example wallet -- [--wallet FILE] [--rpc ADDR | --chain DIR] COMMAND
+//
+// Commands:
+//     keygen                               create the key in the wallet file
+//     address                              print the address to pay to
+//     pubkey                               print the public key, e.g. for miner_key
+//     balance                              print the confirmed balance
+//     listunspent                          print the unspent outputs
+//     pay [--fee N] ADDRESS:AMOUNT...      build and sign a payment, print its hex
+//     broadcast HEX                        send a signed transaction to the node
+//     signmessage MESSAGE                  sign a message with the key of the address
+//     verifymessage ADDRESS SIG MESSAGE    check a message signed by an address
+//
+// A payment goes to a Bech32 or Base58Check address, or to a public key in hex.
+use std::{net::SocketAddr, path::{Path, PathBuf}, process};
+use crypto::{
+    blockchain::{address::{self, Address, Network}, encoding::{Decode, Encode}, transaction::Transaction, Blockchain},
+    ecdsa::{self, ECDSAPrivateKey, ECDSAPublicKey},
+    rpc::{client::{RpcClient, RpcClientError}, json::Json},
+    sha256::Sha256, user::{User, UserError}, util
+};
+
+const DEFAULT_WALLET_FILE: &str = "wallet.key";
+const DEFAULT_RPC_ADDR: &str = "127.0.0.1:8332";
+const DEFAULT_FEE: u64 = 1;
+
+const USAGE: &str = "\
+usage: wallet [--wallet FILE] [--rpc ADDR | --chain DIR] COMMAND
+
+commands:
+    keygen                          create the key in the wallet file
+    address                         print the address to pay to
+    pubkey                          print the public key, e.g. for miner_key
+    balance                         print the confirmed balance
+    listunspent                     print the unspent outputs
+    pay [--fee N] ADDRESS:AMOUNT... build and sign a payment, print its hex,
+                                    ADDRESS can also be a public key in hex
+    broadcast HEX                   send a signed transaction to the node
+    signmessage MESSAGE             sign a message with the key of the address
+    verifymessage ADDRESS SIG MESSAGE
+                                    check a message signed by an address";
+
+// Where the unspent outputs come from
+enum Source {
+    Rpc(RpcClient),
+    Chain(Box<Blockchain>),
+}
+
+impl Source {
+    fn unspent(&mut self, pubkey: &ECDSAPublicKey) -> Vec<(Sha256, u32, u64)> {
+        match self {
+            Source::Rpc(client) => {
+                let result = call(client, "listunspent", vec![util::hex_encode(&pubkey.to_bytes()).into()]);
+                let outputs = result.as_array().unwrap_or_else(|| fail("invalid listunspent response"));
+                outputs.iter().map(|output| {
+                    let txid = output.get("txid").and_then(Json::as_str).and_then(parse_hash);
+                    let vout = output.get("vout").and_then(Json::as_u64);
+                    let value = output.get("value").and_then(Json::as_u64);
+                    match (txid, vout, value) {
+                        (Some(txid), Some(vout), Some(value)) => (txid, vout as u32, value),
+                        _ => fail("invalid listunspent response")
+                    }
+                }).collect()
+            }
+            Source::Chain(blockchain) => blockchain.get_user_funds(pubkey)
+        }
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    process::exit(1);
+}
+
+fn parse_hash(s: &str) -> Option<Sha256> {
+    let bytes: [u8; 32] = util::hex_decode(s)?.try_into().ok()?;
+    Some(Sha256::from_bytes(bytes))
+}
+
+fn call(client: &mut RpcClient, method: &str, params: Vec<Json>) -> Json {
+    match client.call(method, params) {
+        Ok(result) => result,
+        Err(RpcClientError::Rpc(error)) => match error.data {
+            Some(data) => fail(&format!("{} ({}): {}", error.message, error.code, data)),
+            None => fail(&format!("{} ({})", error.message, error.code))
+        },
+        Err(e) => fail(&format!("could not reach the node: {:?}", e))
+    }
+}
+
+fn load_keys(path: &Path) -> (ECDSAPublicKey, ECDSAPrivateKey) {
+    if !path.exists() {
+        fail(&format!("{} does not exist, create it with keygen", path.display()));
+    }
+    let private_key = ECDSAPrivateKey::load(path.to_str().unwrap());
+    (private_key.public_key(), private_key)
+}
+
+// A wallet user that knows the coins of its key
+fn load_user(path: &Path, source: &mut Source) -> User {
+    let mut user = User::new("wallet", load_keys(path));
+    let funds = source.unspent(&user.public_key);
+    user.update_funds_from_chain(&funds);
+    user
+}
+
+fn parse_recipient(arg: &str) -> (Address, u64) {
+    let (recipient, amount) = arg.split_once(':').unwrap_or_else(|| fail(&format!("expected ADDRESS:AMOUNT, got {}", arg)));
+    let address = Address::parse_for(recipient, Network::Main).unwrap_or_else(|e| {
+        let pubkey = util::hex_decode(recipient)
+            .and_then(|bytes| ECDSAPublicKey::from_bytes(&bytes).ok())
+            .unwrap_or_else(|| fail(&format!("invalid address {}: {:?}", recipient, e)));
+        Address::from_pubkey(&pubkey, Network::Main)
+    });
+    let amount = amount.parse().ok().filter(|amount| *amount > 0).unwrap_or_else(|| fail(&format!("invalid amount {}", amount)));
+    (address, amount)
+}
+
+fn pay(user: &User, args: &[String]) -> Transaction {
+    let mut fee = DEFAULT_FEE;
+    let mut recipients = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--fee" {
+            fee = args.next().and_then(|fee| fee.parse().ok()).unwrap_or_else(|| fail("--fee needs an amount"));
+        } else {
+            recipients.push(parse_recipient(arg));
+        }
+    }
+    if recipients.is_empty() {
+        fail("no recipients given");
+    }
+    match user.try_transaction_with_fee(&recipients, fee) {
+        Ok(transaction) => transaction,
+        Err(UserError::InsufficientFunds) => fail(&format!("insufficient funds, the balance is {}", user.get_funds())),
+        Err(e) => fail(&format!("could not build the payment: {:?}", e))
+    }
+}
+
 fn main() {
-    
-}
\ No newline at end of file
+    let mut wallet = PathBuf::from(DEFAULT_WALLET_FILE);
+    let mut rpc_addr = None;
+    let mut chain_dir = None;
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    while args.first().is_some_and(|arg| arg.starts_with("--")) {
+        let option = args.remove(0);
+        let value = if args.is_empty() { fail(USAGE) } else { args.remove(0) };
+        match option.as_str() {
+            "--wallet" => wallet = PathBuf::from(value),
+            "--rpc" => rpc_addr = Some(value.parse::<SocketAddr>().unwrap_or_else(|_| fail(&format!("invalid address {}", value)))),
+            "--chain" => chain_dir = Some(PathBuf::from(value)),
+            _ => fail(USAGE)
+        }
+    }
+    let Some((command, args)) = args.split_first() else {
+        fail(USAGE);
+    };
+
+    let source = || match (&rpc_addr, &chain_dir) {
+        (Some(_), Some(_)) => fail("use either --rpc or --chain"),
+        (None, Some(dir)) => {
+            let blockchain = Blockchain::open_read_only(dir)
+                .unwrap_or_else(|e| fail(&format!("could not read the chain in {}: {:?}", dir.display(), e)));
+            Source::Chain(Box::new(blockchain))
+        }
+        (addr, None) => Source::Rpc(RpcClient::new(addr.unwrap_or_else(|| DEFAULT_RPC_ADDR.parse().unwrap())))
+    };
+
+    match (command.as_str(), args) {
+        ("keygen", []) => {
+            if wallet.exists() {
+                fail(&format!("{} already exists", wallet.display()));
+            }
+            let (public_key, private_key) = ecdsa::generate_keypair();
+            private_key.save(wallet.to_str().unwrap());
+            println!("{}", Address::from_pubkey(&public_key, Network::Main));
+        }
+        ("address", []) => {
+            let address = Address::from_pubkey(&load_keys(&wallet).0, Network::Main);
+            println!("{}", address);
+            println!("{}", address.to_base58());
+        }
+        ("pubkey", []) => println!("{}", util::hex_encode(&load_keys(&wallet).0.to_bytes())),
+        ("balance", []) => println!("{}", load_user(&wallet, &mut source()).get_funds()),
+        ("listunspent", []) => {
+            for fund in load_user(&wallet, &mut source()).funds {
+                println!("{}:{} {}", fund.txid, fund.vout, fund.value);
+            }
+        }
+        ("pay", args) => {
+            let transaction = pay(&load_user(&wallet, &mut source()), args);
+            println!("{}", util::hex_encode(&transaction.to_bytes()));
+        }
+        ("broadcast", [hex]) => {
+            let Source::Rpc(mut client) = source() else {
+                fail("broadcasting needs a node, use --rpc");
+            };
+            let transaction = util::hex_decode(hex)
+                .and_then(|bytes| Transaction::from_bytes(&bytes).ok())
+                .unwrap_or_else(|| fail("invalid transaction hex"));
+            let txid = call(&mut client, "sendrawtransaction", vec![util::hex_encode(&transaction.to_bytes()).into()]);
+            println!("{}", txid.as_str().unwrap_or_default());
+        }
+        ("signmessage", [message]) => println!("{}", address::sign_message(message, &load_keys(&wallet).1)),
+        ("verifymessage", [address, signature, message]) => {
+            let address = Address::parse(address).unwrap_or_else(|e| fail(&format!("invalid address {}: {:?}", address, e)));
+            if !address.verify_message(message, signature) {
+                fail("the signature does not match");
+            }
+            println!("ok");
+        }
+        _ => fail(USAGE)
+    }
+}
@@ -0,0 +1,257 @@
+use std::str::FromStr;
+use crate::{
+    ecdsa::{self, ECDSAPrivateKey, ECDSAPublicKey, RecoverableSignature}, schnorr::SchnorrPublicKey, sha256::Sha256,
+    util::{self, base58::{self, Base58Error}, bech32::{self, Bech32Error}}
+};
+use super::script::{pubkey_hash, Script};
+
+// Signed messages are prefixed, so a message signature can never be used as
+// the signature of a transaction
+const MESSAGE_PREFIX: &[u8] = b"Signed Message:\n";
+
+// Which chain an address is meant for, so coins are not sent to an address
+// of another network by mistake, see Address::parse_for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Network {
+    Main,
+    Test,
+}
+
+impl Network {
+    const ALL: [Network; 2] = [Network::Main, Network::Test];
+
+    // First byte of the Base58Check payload
+    pub fn version(&self) -> u8 {
+        match self {
+            Network::Main => 0x00,
+            Network::Test => 0x6f,
+        }
+    }
+
+    // Human readable part of the Bech32 encoding
+    pub fn hrp(&self) -> &'static str {
+        match self {
+            Network::Main => "bk",
+            Network::Test => "tbk",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AddressError {
+    Base58(Base58Error),
+    Bech32(Bech32Error),
+    UnknownNetwork,
+    WrongNetwork,
+    InvalidLength,
+}
+
+impl From<Base58Error> for AddressError {
+    fn from(e: Base58Error) -> Self {
+        AddressError::Base58(e)
+    }
+}
+
+impl From<Bech32Error> for AddressError {
+    fn from(e: Bech32Error) -> Self {
+        AddressError::Bech32(e)
+    }
+}
+
+// The hash of a public key, which is all a P2PKH output needs. It has two
+// encodings with a checksum, so a mistyped address is rejected instead of
+// paying to a key nobody has:
+//
+//     Base58Check   version byte | hash | first 4 bytes of the double SHA-256
+//     Bech32        hrp 1 hash, with the checksum of BIP 173
+//
+// Display gives the Bech32 form, parsing accepts both.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub network: Network,
+    pub pubkey_hash: Sha256,
+}
+
+impl Address {
+    pub fn from_pubkey(pubkey: &ECDSAPublicKey, network: Network) -> Self {
+        Address { network, pubkey_hash: pubkey_hash(pubkey) }
+    }
+
+    // The address a P2PKH output pays to, None for any other script
+    pub fn from_script(script: &Script, network: Network) -> Option<Self> {
+        script.p2pkh_pubkey_hash().map(|pubkey_hash| Address { network, pubkey_hash })
+    }
+
+    pub fn script_pubkey(&self) -> Script {
+        Script::p2pkh_hash(&self.pubkey_hash)
+    }
+
+    pub fn to_base58(&self) -> String {
+        let mut payload = vec![self.network.version()];
+        payload.extend_from_slice(self.pubkey_hash.bytes());
+        base58::encode_check(&payload)
+    }
+
+    pub fn to_bech32(&self) -> String {
+        bech32::encode(self.network.hrp(), self.pubkey_hash.bytes())
+    }
+
+    fn from_hash_bytes(network: Network, hash: &[u8]) -> Result<Self, AddressError> {
+        let hash: [u8; 32] = hash.try_into().map_err(|_| AddressError::InvalidLength)?;
+        Ok(Address { network, pubkey_hash: Sha256::from_bytes(hash) })
+    }
+
+    pub fn from_base58(s: &str) -> Result<Self, AddressError> {
+        let payload = base58::decode_check(s)?;
+        let (&version, hash) = payload.split_first().ok_or(AddressError::InvalidLength)?;
+        let network = Network::ALL.into_iter().find(|network| network.version() == version).ok_or(AddressError::UnknownNetwork)?;
+        Address::from_hash_bytes(network, hash)
+    }
+
+    pub fn from_bech32(s: &str) -> Result<Self, AddressError> {
+        let (hrp, hash) = bech32::decode(s)?;
+        let network = Network::ALL.into_iter().find(|network| network.hrp() == hrp).ok_or(AddressError::UnknownNetwork)?;
+        Address::from_hash_bytes(network, &hash)
+    }
+
+    // Whether the signature from sign_message was made with the key of this
+    // address. The key is recovered from the signature, so the address is
+    // all the verifier needs.
+    pub fn verify_message(&self, message: &str, signature: &str) -> bool {
+        let Some(signature) = util::hex_decode(signature).and_then(|bytes| RecoverableSignature::from_bytes(&bytes)) else {
+            return false;
+        };
+        ecdsa::recover(&message_bytes(message), &signature)
+            .is_some_and(|pubkey| pubkey_hash(&pubkey) == self.pubkey_hash)
+    }
+
+    // Either encoding, told apart by the Bech32 prefix
+    pub fn parse(s: &str) -> Result<Self, AddressError> {
+        let lower = s.to_ascii_lowercase();
+        if Network::ALL.iter().any(|network| lower.starts_with(&format!("{}1", network.hrp()))) {
+            Address::from_bech32(s)
+        } else {
+            Address::from_base58(s)
+        }
+    }
+
+    // Like parse, but only an address of the given network is accepted.
+    // Anything that pays to an address should parse it this way.
+    pub fn parse_for(s: &str, network: Network) -> Result<Self, AddressError> {
+        let address = Address::parse(s)?;
+        if address.network != network {
+            return Err(AddressError::WrongNetwork);
+        }
+        Ok(address)
+    }
+}
+
+fn message_bytes(message: &str) -> Vec<u8> {
+    let mut bytes = MESSAGE_PREFIX.to_vec();
+    bytes.extend_from_slice(message.as_bytes());
+    bytes
+}
+
+// Proves that the owner of an address wrote the message, see
+// Address::verify_message. The recoverable signature is hex encoded.
+pub fn sign_message(message: &str, private_key: &ECDSAPrivateKey) -> String {
+    util::hex_encode(&ecdsa::sign_recoverable(&message_bytes(message), private_key).to_bytes())
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_bech32())
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Address::parse(s)
+    }
+}
+
+// Anything a payment can be made to, by a raw public key or by its address
+pub trait Recipient {
+    fn script_pubkey(&self) -> Script;
+}
+
+impl Recipient for ECDSAPublicKey {
+    fn script_pubkey(&self) -> Script {
+        Script::p2pkh(self)
+    }
+}
+
+impl Recipient for SchnorrPublicKey {
+    fn script_pubkey(&self) -> Script {
+        Script::schnorr(self)
+    }
+}
+
+impl Recipient for Address {
+    fn script_pubkey(&self) -> Script {
+        Address::script_pubkey(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ecdsa;
+    use super::*;
+
+    #[test]
+    fn test_address_encodings() {
+        let (pubkey, _) = ecdsa::generate_keypair();
+        let address = Address::from_pubkey(&pubkey, Network::Main);
+        assert_eq!(address.script_pubkey(), Script::p2pkh(&pubkey));
+        assert_eq!(Address::from_script(&Script::p2pkh(&pubkey), Network::Main), Some(address.clone()));
+        assert_eq!(Address::from_script(&Script::hash_lock(&address.pubkey_hash), Network::Main), None);
+
+        // Both encodings parse back, Bech32 in either case
+        let bech32 = address.to_bech32();
+        assert!(bech32.starts_with("bk1"));
+        assert_eq!(bech32.parse::<Address>(), Ok(address.clone()));
+        assert_eq!(Address::parse(&bech32.to_ascii_uppercase()), Ok(address.clone()));
+        assert!(address.to_base58().starts_with('1'));
+        assert_eq!(Address::parse(&address.to_base58()), Ok(address.clone()));
+        let test = Address::from_pubkey(&pubkey, Network::Test);
+        assert!(test.to_bech32().starts_with("tbk1"));
+        assert_eq!(Address::parse(&test.to_base58()).map(|address| address.network), Ok(Network::Test));
+
+        // Paying expects the address of one network
+        assert_eq!(Address::parse_for(&bech32, Network::Main), Ok(address.clone()));
+        assert_eq!(Address::parse_for(&test.to_bech32(), Network::Main), Err(AddressError::WrongNetwork));
+        assert_eq!(Address::parse_for(&test.to_base58(), Network::Main), Err(AddressError::WrongNetwork));
+        assert_eq!(Address::parse_for(&test.to_base58(), Network::Test), Ok(test.clone()));
+
+        // A changed character is caught by the checksum
+        let typo = |s: &str, idx: usize| {
+            let mut chars: Vec<char> = s.chars().collect();
+            chars[idx] = if chars[idx] == 'q' { 'p' } else { 'q' };
+            chars.into_iter().collect::<String>()
+        };
+        for idx in 3..bech32.len() {
+            assert_eq!(Address::parse(&typo(&bech32, idx)), Err(AddressError::Bech32(Bech32Error::InvalidChecksum)));
+        }
+        let base58 = address.to_base58();
+        for idx in 1..base58.len() {
+            assert!(Address::parse(&typo(&base58, idx)).is_err());
+        }
+
+        assert_eq!(Address::from_bech32(&bech32::encode("xx", address.pubkey_hash.bytes())), Err(AddressError::UnknownNetwork));
+        assert_eq!(Address::from_bech32(&bech32::encode("bk", &[1, 2, 3])), Err(AddressError::InvalidLength));
+        assert_eq!(Address::from_base58(&base58::encode_check(&[0x05; 33])), Err(AddressError::UnknownNetwork));
+    }
+
+    #[test]
+    fn test_signed_message() {
+        let (pubkey, privkey) = ecdsa::generate_keypair();
+        let address = Address::from_pubkey(&pubkey, Network::Main);
+        let signature = sign_message("I own this address", &privkey);
+        assert!(address.verify_message("I own this address", &signature));
+        assert!(!address.verify_message("I own another address", &signature));
+        assert!(!Address::from_pubkey(&ecdsa::generate_keypair().0, Network::Main).verify_message("I own this address", &signature));
+        assert!(!address.verify_message("I own this address", "zz"));
+    }
+}
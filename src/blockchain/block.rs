@@ -1,62 +1,125 @@
-use crate::{sha256::Sha256, util};
-use super::{merkle::MerkleTree, transaction::Transaction};
+use crate::{math::big_int::BigInt, sha256::Sha256, util};
+use super::{consensus, encoding::{Encode, BLOCK_VERSION}, merkle::MerkleTree, transaction::Transaction};
 
-const DEFAULT_DIFFICULTY: u64 = 10;
+// Expected number of hashes to mine a block, see consensus::difficulty_to_target
+pub const DEFAULT_DIFFICULTY: u64 = 1 << 10;
 
+// Largest encoded block, leaves room for the message framing below
+// net::message::MAX_MESSAGE_SIZE so every valid block can be relayed
+pub const MAX_BLOCK_SIZE: usize = 4_000_000;
+
+// The part of a block that is hashed. The transactions are only committed to
+// through the merkle root, so a header can be checked without them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockHeader {
+    pub version: u8,
+    pub previous_block_hash: Sha256,
+    pub merkle_root: Sha256,
+    pub timestamp: u64,
+    pub difficulty: u64,
+    pub nonce: u64,
+}
+
+impl BlockHeader {
+    pub fn new(previous_block_hash: Sha256, merkle_root: Sha256) -> BlockHeader {
+        BlockHeader {
+            version: BLOCK_VERSION,
+            previous_block_hash,
+            merkle_root,
+            timestamp: util::timestamp(),
+            difficulty: DEFAULT_DIFFICULTY,
+            nonce: 0,
+        }
+    }
+
+    // The hash of the encoded header
+    pub fn hash(&self) -> Sha256 {
+        Sha256::hash(&self.to_bytes())
+    }
+
+    pub fn target(&self) -> BigInt<4> {
+        consensus::difficulty_to_target(self.difficulty)
+    }
+
+    pub fn work(&self) -> u128 {
+        self.difficulty as u128
+    }
+
+    pub fn has_valid_work(&self) -> bool {
+        self.hash().is_valid(&self.target())
+    }
+
+    pub fn mine(&mut self) -> Sha256 {
+        loop {
+            if let Some(hash) = self.try_mine(u64::MAX) {
+                return hash;
+            }
+        }
+    }
+
+    // Tries at most attempts nonces, None if none of them gave a valid hash.
+    // The next call continues with the following nonces.
+    pub fn try_mine(&mut self, attempts: u64) -> Option<Sha256> {
+        let target = self.target();
+        for _ in 0..attempts {
+            self.timestamp = util::timestamp();
+            let hash = self.hash();
+            if hash.is_valid(&target) {
+                return Some(hash);
+            }
+            self.nonce = self.nonce.wrapping_add(1);
+        }
+        None
+    }
+}
+
+// hash is the hash of the header, kept so it does not have to be recomputed
 #[derive(Clone)]
 pub struct Block {
-    pub timestamp: u64,
+    pub header: BlockHeader,
     pub hash: Sha256,
-    pub previous_block_hash: Sha256,
-    pub nonce: u64,
-    pub difficulty: u64,
     pub merkle_tree: MerkleTree
 }
 
 impl Block {
     pub fn new_genesis(coinbase: Transaction) -> Block {
-        Block {
-            timestamp: util::timestamp(),
-            hash: Sha256::hash(&[]),
-            previous_block_hash: Sha256::hash(&[]),
-            nonce: 0,
-            difficulty: DEFAULT_DIFFICULTY,
-            merkle_tree: MerkleTree::new(vec![coinbase])
-        }
+        Block::new(Sha256::hash(&[]), vec![coinbase])
     }
 
     pub fn new(previous_block_hash: Sha256, transactions: Vec<Transaction>) -> Block {
         let merkle_tree = MerkleTree::new(transactions);
         Block {
-            timestamp: util::timestamp(),
+            header: BlockHeader::new(previous_block_hash, merkle_tree.root_hash().clone()),
             hash: Sha256::hash(&[]),
-            previous_block_hash,
-            nonce: 0,
-            difficulty: DEFAULT_DIFFICULTY,
             merkle_tree
         }
     }
 
     pub fn mine(&mut self) {
-        loop {
-            self.timestamp = util::timestamp();
-            let hash = self.hash();
-            if hash.is_valid(self.difficulty) {
+        self.hash = self.header.mine();
+    }
+
+    // See BlockHeader::try_mine, true once the block is mined
+    pub fn try_mine(&mut self, attempts: u64) -> bool {
+        match self.header.try_mine(attempts) {
+            Some(hash) => {
                 self.hash = hash;
-                break;
+                true
             }
-            self.nonce += 1;
+            None => false
         }
     }
 
+    pub fn target(&self) -> BigInt<4> {
+        self.header.target()
+    }
+
+    pub fn work(&self) -> u128 {
+        self.header.work()
+    }
+
     pub fn hash(&self) -> Sha256 {
-        let mut bytes = Vec::new();
-        bytes.extend_from_slice(self.previous_block_hash.bytes());
-        bytes.extend_from_slice(self.merkle_tree.root_hash().bytes());
-        bytes.extend_from_slice(&self.timestamp.to_be_bytes());
-        bytes.extend_from_slice(&self.nonce.to_be_bytes());
-        bytes.extend_from_slice(&self.difficulty.to_be_bytes());
-        Sha256::hash(&bytes)
+        self.header.hash()
     }
 }
 
@@ -67,12 +130,12 @@ impl std::fmt::Debug for Block {
             .map(|tx| format!("{:?}", tx))
             .collect();
         write!(f, "Block:\n timestamp: {},\n hash: {},\n previous_block_hash: {},\n nonce: {},\n merkle_root: {},\n transactions: [{}]",
-            self.timestamp,
+            self.header.timestamp,
             self.hash,
-            self.previous_block_hash,
-            self.nonce,
-            self.merkle_tree.root_hash(),
+            self.header.previous_block_hash,
+            self.header.nonce,
+            self.header.merkle_root,
             transactions.join(", ")
         )
     }
-}
\ No newline at end of file
+}
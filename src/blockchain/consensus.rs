@@ -0,0 +1,194 @@
+use crate::math::big_int::BigInt;
+use super::{block::DEFAULT_DIFFICULTY, MINING_REWARD};
+
+// Number of previous blocks used for the median time past
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+// Blocks may not claim a timestamp more than this far in the future
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+// The difficulty of a block is the expected number of hashes needed to mine
+// it. Every retarget_interval blocks it is scaled by how much faster or slower
+// than target_block_interval the previous blocks were found, by at most a
+// factor of MAX_RETARGET_FACTOR in each direction. A retarget_interval of 0
+// keeps the initial difficulty forever.
+//
+// The block subsidy starts at initial_reward and is halved every
+// halving_interval blocks, which caps the total supply of coins. A
+// halving_interval of 0 never halves it and leaves the supply uncapped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsensusParams {
+    pub initial_difficulty: u64,
+    pub min_difficulty: u64,
+    pub target_block_interval: u64,
+    pub retarget_interval: usize,
+    pub initial_reward: u64,
+    pub halving_interval: usize,
+}
+
+pub const MAX_RETARGET_FACTOR: u64 = 4;
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        ConsensusParams {
+            initial_difficulty: DEFAULT_DIFFICULTY,
+            min_difficulty: 1,
+            target_block_interval: 10,
+            retarget_interval: 10,
+            initial_reward: MINING_REWARD,
+            halving_interval: 210,
+        }
+    }
+}
+
+impl ConsensusParams {
+    // first_timestamp and last_timestamp are the timestamps of the first and
+    // the last block of the finished window, which spans retarget_interval blocks
+    pub fn retarget(&self, difficulty: u64, first_timestamp: u64, last_timestamp: u64) -> u64 {
+        if self.retarget_interval == 0 {
+            return difficulty;
+        }
+        let expected = self.target_block_interval * (self.retarget_interval as u64 - 1);
+        let actual = last_timestamp.saturating_sub(first_timestamp).max(1);
+        let next = (difficulty as u128 * expected as u128 / actual as u128).clamp(
+            difficulty as u128 / MAX_RETARGET_FACTOR as u128,
+            difficulty as u128 * MAX_RETARGET_FACTOR as u128
+        );
+        next.clamp(self.min_difficulty as u128, u64::MAX as u128) as u64
+    }
+
+    // New coins a block at the given height may create, on top of the fees
+    pub fn block_subsidy(&self, height: usize) -> u64 {
+        if self.halving_interval == 0 {
+            return self.initial_reward;
+        }
+        let halvings = height / self.halving_interval;
+        if halvings >= 64 {
+            return 0;
+        }
+        self.initial_reward >> halvings
+    }
+
+    // Coins created by all blocks up to and including the given height,
+    // saturating at u64::MAX
+    pub fn total_supply(&self, height: usize) -> u64 {
+        if self.halving_interval == 0 {
+            return self.initial_reward.saturating_mul((height as u64).saturating_add(1));
+        }
+        let mut supply = 0;
+        let mut era_start = 0;
+        while era_start <= height {
+            let subsidy = self.block_subsidy(era_start);
+            if subsidy == 0 {
+                break;
+            }
+            let blocks = (height - era_start).min(self.halving_interval - 1) + 1;
+            supply = subsidy.saturating_mul(blocks as u64).saturating_add(supply);
+            era_start = match era_start.checked_add(self.halving_interval) {
+                Some(next) => next,
+                None => break
+            };
+        }
+        supply
+    }
+
+    // The supply once the subsidy has dropped to zero, u64::MAX if it never
+    // does or does not fit
+    pub fn max_supply(&self) -> u64 {
+        if self.halving_interval == 0 && self.initial_reward != 0 {
+            return u64::MAX;
+        }
+        let mut supply = 0;
+        let mut subsidy = self.initial_reward;
+        while subsidy != 0 {
+            supply = subsidy.saturating_mul(self.halving_interval as u64).saturating_add(supply);
+            subsidy >>= 1;
+        }
+        supply
+    }
+}
+
+// A hash is valid for a difficulty when, read as a 256-bit big endian number,
+// it is at most (2^256 - 1) / difficulty
+pub fn difficulty_to_target(difficulty: u64) -> BigInt<4> {
+    let max = BigInt::<4>::from_parts([u64::MAX; 4]);
+    max / BigInt::from_num(difficulty.max(1) as u128)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sha256::Sha256;
+    use super::*;
+
+    #[test]
+    fn test_target() {
+        assert_eq!(difficulty_to_target(1), BigInt::from_parts([u64::MAX; 4]));
+        assert_eq!(difficulty_to_target(1 << 10), BigInt::from_parts([u64::MAX, u64::MAX, u64::MAX, u64::MAX >> 10]));
+
+        // A difficulty that is not a power of two gives a target in between
+        let target = difficulty_to_target(3 << 9);
+        assert!(target < difficulty_to_target(1 << 10) && target > difficulty_to_target(1 << 11));
+
+        let hash = Sha256::from_bytes([0x00, 0x3F, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        assert!(hash.is_valid(&difficulty_to_target(1 << 10)));
+        assert!(!hash.is_valid(&difficulty_to_target((1 << 10) + 1)));
+    }
+
+    #[test]
+    fn test_retarget() {
+        let params = ConsensusParams::default();
+        let expected = params.target_block_interval * (params.retarget_interval as u64 - 1);
+
+        assert_eq!(params.retarget(1000, 100, 100 + expected), 1000);
+        assert_eq!(params.retarget(1000, 100, 100 + 2 * expected), 500);
+        assert_eq!(params.retarget(1000, 100, 100 + expected / 2), 2000);
+
+        // Adjustments are clamped, also for timestamps going backwards
+        assert_eq!(params.retarget(1000, 100, 100), 4000);
+        assert_eq!(params.retarget(1000, 100, 50), 4000);
+        assert_eq!(params.retarget(1000, 100, 100 + 100 * expected), 250);
+        assert_eq!(params.retarget(1, 100, 100 + 100 * expected), params.min_difficulty);
+
+        let never = ConsensusParams { retarget_interval: 0, ..Default::default() };
+        assert_eq!(never.retarget(1000, 100, 100), 1000);
+    }
+
+    #[test]
+    fn test_subsidy_halving() {
+        let params = ConsensusParams { initial_reward: 50, halving_interval: 10, ..Default::default() };
+        assert_eq!(params.block_subsidy(0), 50);
+        assert_eq!(params.block_subsidy(9), 50);
+        assert_eq!(params.block_subsidy(10), 25);
+        assert_eq!(params.block_subsidy(25), 12);
+        assert_eq!(params.block_subsidy(60), 0);
+        assert_eq!(params.block_subsidy(10 * 64), 0);
+    }
+
+    #[test]
+    fn test_total_supply() {
+        let params = ConsensusParams { initial_reward: 50, halving_interval: 10, ..Default::default() };
+        assert_eq!(params.total_supply(0), 50);
+        assert_eq!(params.total_supply(9), 500);
+        assert_eq!(params.total_supply(12), 500 + 3 * 25);
+
+        let summed: u64 = (0..=200).map(|height| params.block_subsidy(height)).sum();
+        assert_eq!(params.total_supply(200), summed);
+        assert_eq!(params.max_supply(), (50 + 25 + 12 + 6 + 3 + 1) * 10);
+        assert_eq!(params.total_supply(1_000_000), params.max_supply());
+
+        let flat = ConsensusParams { initial_reward: 50, halving_interval: 0, ..Default::default() };
+        assert_eq!(flat.block_subsidy(1_000_000), 50);
+        assert_eq!(flat.total_supply(9), 500);
+        assert_eq!(flat.max_supply(), u64::MAX);
+
+        // Supplies that do not fit saturate
+        let large = ConsensusParams { initial_reward: u64::MAX / 2, halving_interval: usize::MAX, ..Default::default() };
+        assert_eq!(large.total_supply(1), u64::MAX - 1);
+        assert_eq!(large.total_supply(usize::MAX), u64::MAX);
+        assert_eq!(large.max_supply(), u64::MAX);
+        let short = ConsensusParams { initial_reward: u64::MAX, halving_interval: 2, ..Default::default() };
+        assert_eq!(short.total_supply(3), u64::MAX);
+        assert_eq!(short.max_supply(), u64::MAX);
+    }
+}
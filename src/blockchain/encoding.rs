@@ -0,0 +1,479 @@
+use crate::{ecdsa::{point::{AffinePoint, SEC1_EVEN, SEC1_INFINITY, SEC1_ODD, SEC1_UNCOMPRESSED}, ECDSAPublicKey}, sha256::Sha256};
+use super::{block::{Block, BlockHeader}, merkle::MerkleTree, script::{Script, MAX_SCRIPT_SIZE}, transaction::{Transaction, TxInput, TxOutput}};
+
+// Every encoded transaction and block starts with its format version so the
+// layout can change later without old data being misread. Transactions:
+//
+//     1   inputs with a signature and public key, outputs with a public key
+//     2   scripts in inputs and outputs
+//     3   input sequence numbers and the transaction lock time
+pub const TRANSACTION_VERSION: u8 = 3;
+pub const BLOCK_VERSION: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    UnexpectedEnd,
+    TrailingBytes,
+    NonCanonicalVarInt,
+    LengthTooLarge,
+    UnsupportedVersion(u8),
+    InvalidTag(u8),
+    InvalidPoint,
+}
+
+pub trait Encode {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>;
+
+    // Strict decoding, the whole input has to be consumed
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(bytes);
+        let value = Self::decode(&mut reader)?;
+        reader.finish()?;
+        Ok(value)
+    }
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if len > self.remaining() {
+            return Err(DecodeError::UnexpectedEnd);
+        }
+        let bytes = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.read_array::<1>()?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_be_bytes(self.read_array()?))
+    }
+
+    // Reads a varint and rejects encodings that are longer than needed, so
+    // every value has exactly one valid encoding
+    pub fn read_varint(&mut self) -> Result<u64, DecodeError> {
+        let value = match self.read_u8()? {
+            0xFD => {
+                let value = u16::from_be_bytes(self.read_array()?) as u64;
+                if value < 0xFD { return Err(DecodeError::NonCanonicalVarInt); }
+                value
+            }
+            0xFE => {
+                let value = self.read_u32()? as u64;
+                if value <= u16::MAX as u64 { return Err(DecodeError::NonCanonicalVarInt); }
+                value
+            }
+            0xFF => {
+                let value = self.read_u64()?;
+                if value <= u32::MAX as u64 { return Err(DecodeError::NonCanonicalVarInt); }
+                value
+            }
+            byte => byte as u64
+        };
+        Ok(value)
+    }
+
+    // Reads a varint used as an element count. Every element takes at least
+    // one byte, so larger counts can be rejected before allocating anything
+    pub fn read_length(&mut self) -> Result<usize, DecodeError> {
+        let len = self.read_varint()?;
+        if len > self.remaining() as u64 {
+            return Err(DecodeError::LengthTooLarge);
+        }
+        Ok(len as usize)
+    }
+
+    pub fn finish(&self) -> Result<(), DecodeError> {
+        if self.remaining() != 0 {
+            return Err(DecodeError::TrailingBytes);
+        }
+        Ok(())
+    }
+}
+
+pub fn write_varint(out: &mut Vec<u8>, value: u64) {
+    if value < 0xFD {
+        out.push(value as u8);
+    } else if value <= u16::MAX as u64 {
+        out.push(0xFD);
+        out.extend_from_slice(&(value as u16).to_be_bytes());
+    } else if value <= u32::MAX as u64 {
+        out.push(0xFE);
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    } else {
+        out.push(0xFF);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+pub fn write_list<T: Encode>(out: &mut Vec<u8>, items: &[T]) {
+    write_varint(out, items.len() as u64);
+    for item in items {
+        item.encode(out);
+    }
+}
+
+pub fn read_list<T: Decode>(reader: &mut Reader) -> Result<Vec<T>, DecodeError> {
+    let len = reader.read_length()?;
+    let mut items = Vec::with_capacity(len);
+    for _ in 0..len {
+        items.push(T::decode(reader)?);
+    }
+    Ok(items)
+}
+
+impl Encode for Sha256 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.bytes());
+    }
+}
+
+impl Decode for Sha256 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Sha256::from_bytes(reader.read_array()?))
+    }
+}
+
+// Points are SEC1 encoded, see AffinePoint::from_bytes
+impl Encode for AffinePoint {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.get_bytes());
+    }
+}
+
+impl Decode for AffinePoint {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let prefix = reader.read_u8()?;
+        let len = match prefix {
+            SEC1_INFINITY => 0,
+            SEC1_EVEN | SEC1_ODD => 32,
+            SEC1_UNCOMPRESSED => 64,
+            tag => return Err(DecodeError::InvalidTag(tag))
+        };
+        let mut bytes = vec![prefix];
+        bytes.extend_from_slice(reader.read_bytes(len)?);
+        AffinePoint::from_bytes(&bytes).ok_or(DecodeError::InvalidPoint)
+    }
+}
+
+// Public keys go into every output and signature script, so they are written
+// in the 33 byte compressed form. The uncompressed form is still accepted.
+impl Encode for ECDSAPublicKey {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.key.get_compressed_bytes());
+    }
+}
+
+impl Decode for ECDSAPublicKey {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let key = AffinePoint::decode(reader)?;
+        if key.is_infinity() {
+            return Err(DecodeError::InvalidPoint);
+        }
+        Ok(ECDSAPublicKey { key })
+    }
+}
+
+impl Encode for Script {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_varint(out, self.len() as u64);
+        out.extend_from_slice(self.bytes());
+    }
+}
+
+impl Decode for Script {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let len = reader.read_varint()?;
+        if len > MAX_SCRIPT_SIZE as u64 {
+            return Err(DecodeError::LengthTooLarge);
+        }
+        Ok(Script::from_bytes(reader.read_bytes(len as usize)?.to_vec()))
+    }
+}
+
+impl Encode for TxInput {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.txid.encode(out);
+        out.extend_from_slice(&self.vout.to_be_bytes());
+        self.script_sig.encode(out);
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+    }
+}
+
+impl Decode for TxInput {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(TxInput {
+            txid: Sha256::decode(reader)?,
+            vout: reader.read_u32()?,
+            script_sig: Script::decode(reader)?,
+            sequence: reader.read_u32()?,
+        })
+    }
+}
+
+// The spent flag is local chain state and is never part of the encoding
+impl Encode for TxOutput {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.value.to_be_bytes());
+        self.script_pubkey.encode(out);
+    }
+}
+
+impl Decode for TxOutput {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(TxOutput {
+            value: reader.read_u64()?,
+            script_pubkey: Script::decode(reader)?,
+            spent: false,
+        })
+    }
+}
+
+impl Encode for Transaction {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(TRANSACTION_VERSION);
+        match &self.coinbase_padding {
+            Some(padding) => {
+                out.push(1);
+                for part in padding {
+                    out.extend_from_slice(&part.to_be_bytes());
+                }
+            }
+            None => out.push(0)
+        }
+        write_list(out, &self.inputs);
+        write_list(out, &self.outputs);
+        out.extend_from_slice(&self.lock_time.to_be_bytes());
+    }
+}
+
+impl Decode for Transaction {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let version = reader.read_u8()?;
+        if version != TRANSACTION_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let coinbase_padding = match reader.read_u8()? {
+            0 => None,
+            1 => Some([reader.read_u64()?, reader.read_u64()?, reader.read_u64()?, reader.read_u64()?]),
+            tag => return Err(DecodeError::InvalidTag(tag))
+        };
+        Ok(Transaction {
+            coinbase_padding,
+            inputs: read_list(reader)?,
+            outputs: read_list(reader)?,
+            lock_time: reader.read_u64()?,
+        })
+    }
+}
+
+// Only the transactions are encoded, the tree itself is rebuilt on decode
+impl Encode for MerkleTree {
+    fn encode(&self, out: &mut Vec<u8>) {
+        write_list(out, self.transactions());
+    }
+}
+
+impl Decode for MerkleTree {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(MerkleTree::new(read_list(reader)?))
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(self.version);
+        self.previous_block_hash.encode(out);
+        self.merkle_root.encode(out);
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.difficulty.to_be_bytes());
+        out.extend_from_slice(&self.nonce.to_be_bytes());
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let version = reader.read_u8()?;
+        if version != BLOCK_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        Ok(BlockHeader {
+            version,
+            previous_block_hash: Sha256::decode(reader)?,
+            merkle_root: Sha256::decode(reader)?,
+            timestamp: reader.read_u64()?,
+            difficulty: reader.read_u64()?,
+            nonce: reader.read_u64()?,
+        })
+    }
+}
+
+// The block hash is derived data, it is recomputed when decoding. Whether the
+// merkle root matches the transactions is checked with the rest of the block.
+impl Encode for Block {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.header.encode(out);
+        self.merkle_tree.encode(out);
+    }
+}
+
+impl Decode for Block {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let header = BlockHeader::decode(reader)?;
+        Ok(Block {
+            hash: header.hash(),
+            header,
+            merkle_tree: MerkleTree::decode(reader)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{blockchain::transaction::SEQUENCE_FINAL, ecdsa};
+    use super::*;
+
+    fn signed_transaction(inputs: usize) -> Transaction {
+        let (pubkey, privkey) = ecdsa::generate_keypair();
+        let mut tx = Transaction::new();
+        for i in 0..inputs {
+            tx.add_input(TxInput {
+                txid: Sha256::hash(&(i as u64).to_be_bytes()),
+                vout: i as u32,
+                script_sig: Script::new(),
+                sequence: SEQUENCE_FINAL,
+            });
+        }
+        let lock = Script::p2pkh(&pubkey);
+        tx.add_output(TxOutput { value: 25, script_pubkey: lock.clone(), spent: false });
+        let signature = ecdsa::sign(tx.get_input_hash(0, &lock).bytes(), &privkey);
+        tx.inputs[0].script_sig = Script::p2pkh_unlock(&signature, &pubkey);
+        tx
+    }
+
+    #[test]
+    fn test_varint_roundtrip() {
+        for value in [0, 0xFC, 0xFD, 0xFFFF, 0x10000, 0xFFFFFFFF, 0x100000000, u64::MAX] {
+            let mut out = Vec::new();
+            write_varint(&mut out, value);
+            let mut reader = Reader::new(&out);
+            assert_eq!(reader.read_varint(), Ok(value));
+            assert_eq!(reader.finish(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_non_canonical_varint() {
+        let mut reader = Reader::new(&[0xFD, 0x00, 0x10]);
+        assert_eq!(reader.read_varint(), Err(DecodeError::NonCanonicalVarInt));
+        let mut reader = Reader::new(&[0xFE, 0x00, 0x00, 0xFF, 0xFF]);
+        assert_eq!(reader.read_varint(), Err(DecodeError::NonCanonicalVarInt));
+    }
+
+    #[test]
+    fn test_transaction_roundtrip() {
+        let tx = signed_transaction(3);
+        let decoded = Transaction::from_bytes(&tx.to_bytes()).unwrap();
+        assert_eq!(decoded, tx);
+        assert_eq!(decoded.hash(), tx.hash());
+
+        let coinbase = Transaction::get_coinbase(ecdsa::generate_keypair().0, 50);
+        assert_eq!(Transaction::from_bytes(&coinbase.to_bytes()).unwrap(), coinbase);
+    }
+
+    #[test]
+    fn test_more_than_255_inputs() {
+        let tx = signed_transaction(300);
+        let decoded = Transaction::from_bytes(&tx.to_bytes()).unwrap();
+        assert_eq!(decoded.inputs.len(), 300);
+        assert_eq!(decoded, tx);
+    }
+
+    #[test]
+    fn test_block_roundtrip() {
+        let (pubkey, _) = ecdsa::generate_keypair();
+        let transactions = vec![Transaction::get_coinbase(pubkey.clone(), 50), Transaction::get_coinbase(pubkey, 10)];
+        let mut block = Block::new(Sha256::hash(b"previous"), transactions);
+        block.mine();
+
+        let decoded = Block::from_bytes(&block.to_bytes()).unwrap();
+        assert_eq!(decoded.hash, block.hash);
+        assert_eq!(decoded.merkle_tree.root_hash(), block.merkle_tree.root_hash());
+        assert_eq!(decoded.merkle_tree.transactions(), block.merkle_tree.transactions());
+
+        // The header alone hashes to the block hash
+        let header = BlockHeader::from_bytes(&block.header.to_bytes()).unwrap();
+        assert_eq!(header.hash(), block.hash);
+        assert!(header.has_valid_work());
+    }
+
+    #[test]
+    fn test_strict_decoding() {
+        let tx = Transaction::get_coinbase(ecdsa::generate_keypair().0, 50);
+        let bytes = tx.to_bytes();
+
+        assert_eq!(Transaction::from_bytes(&bytes[..bytes.len() - 1]), Err(DecodeError::UnexpectedEnd));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert_eq!(Transaction::from_bytes(&trailing), Err(DecodeError::TrailingBytes));
+
+        // Transactions of an older layout are rejected instead of misread
+        let mut version = bytes.clone();
+        for old in 1..TRANSACTION_VERSION {
+            version[0] = old;
+            assert_eq!(Transaction::from_bytes(&version), Err(DecodeError::UnsupportedVersion(old)));
+        }
+        version[0] = TRANSACTION_VERSION + 1;
+        assert_eq!(Transaction::from_bytes(&version), Err(DecodeError::UnsupportedVersion(TRANSACTION_VERSION + 1)));
+
+        let mut padding_tag = bytes;
+        padding_tag[1] = 7;
+        assert_eq!(Transaction::from_bytes(&padding_tag), Err(DecodeError::InvalidTag(7)));
+
+        // A count larger than the remaining input must not be trusted
+        assert_eq!(Transaction::from_bytes(&[TRANSACTION_VERSION, 0, 0xFE, 0xFF, 0xFF, 0xFF, 0xFF]), Err(DecodeError::LengthTooLarge));
+
+        // Public keys are written compressed, both forms are read back
+        let pubkey = ecdsa::generate_keypair().0;
+        assert_eq!(pubkey.to_bytes().len(), 33);
+        assert_eq!(ECDSAPublicKey::from_bytes(&pubkey.key.get_bytes()), Ok(pubkey.clone()));
+        let mut off_curve = pubkey.key.get_bytes();
+        off_curve[64] ^= 1;
+        assert_eq!(ECDSAPublicKey::from_bytes(&off_curve), Err(DecodeError::InvalidPoint));
+        assert_eq!(ECDSAPublicKey::from_bytes(&[0x00]), Err(DecodeError::InvalidPoint));
+    }
+}
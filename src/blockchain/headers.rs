@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use crate::{sha256::Sha256, util};
+use super::{block::BlockHeader, consensus::{ConsensusParams, MAX_FUTURE_BLOCK_TIME, MEDIAN_TIME_SPAN}, BlockError};
+
+#[derive(Clone)]
+struct HeaderEntry {
+    header: BlockHeader,
+    height: usize,
+    total_work: u128,
+    // Order in which the headers were accepted, breaks ties in total_work
+    sequence: u64,
+}
+
+// A tree of block headers checked against the proof of work, difficulty and
+// timestamp rules, which only need the headers. The best chain is the branch
+// with the most cumulative work, ties go to the branch seen first.
+#[derive(Clone)]
+pub struct HeaderChain {
+    params: ConsensusParams,
+    entries: HashMap<Sha256, HeaderEntry>,
+    best: Option<Sha256>,
+    next_sequence: u64,
+}
+
+impl HeaderChain {
+    pub fn new(params: ConsensusParams) -> Self {
+        HeaderChain { params, entries: HashMap::new(), best: None, next_sequence: 0 }
+    }
+
+    pub fn params(&self) -> &ConsensusParams {
+        &self.params
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains(&self, hash: &Sha256) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &Sha256) -> Option<&BlockHeader> {
+        self.entries.get(hash).map(|entry| &entry.header)
+    }
+
+    pub fn height(&self, hash: &Sha256) -> Option<usize> {
+        self.entries.get(hash).map(|entry| entry.height)
+    }
+
+    // Work of the chain from the genesis block up to and including hash
+    pub fn total_work(&self, hash: &Sha256) -> Option<u128> {
+        self.entries.get(hash).map(|entry| entry.total_work)
+    }
+
+    pub fn best_hash(&self) -> Option<&Sha256> {
+        self.best.as_ref()
+    }
+
+    pub fn best_header(&self) -> Option<&BlockHeader> {
+        self.best.as_ref().map(|best| &self.entries[best].header)
+    }
+
+    // Number of headers in the best chain
+    pub fn best_len(&self) -> usize {
+        self.best.as_ref().map_or(0, |best| self.entries[best].height + 1)
+    }
+
+    // Walks from the given header back to the genesis block
+    pub fn ancestors<'a>(&'a self, hash: &Sha256) -> impl Iterator<Item = (&'a Sha256, &'a BlockHeader)> + 'a {
+        let mut next = self.entries.get_key_value(hash);
+        std::iter::from_fn(move || {
+            let (hash, entry) = next?;
+            next = self.entries.get_key_value(&entry.header.previous_block_hash);
+            Some((hash, &entry.header))
+        })
+    }
+
+    // The difficulty required for a block building on parent, which is None
+    // for the genesis block
+    pub fn next_difficulty(&self, parent: Option<&Sha256>) -> u64 {
+        let Some((hash, parent)) = parent.and_then(|hash| self.entries.get_key_value(hash)) else {
+            return self.params.initial_difficulty;
+        };
+        let height = parent.height + 1;
+        if self.params.retarget_interval == 0 || height % self.params.retarget_interval != 0 {
+            return parent.header.difficulty;
+        }
+        let (_, first) = self.ancestors(hash).nth(self.params.retarget_interval - 1).unwrap();
+        self.params.retarget(parent.header.difficulty, first.timestamp, parent.header.timestamp)
+    }
+
+    // Median timestamp of the last MEDIAN_TIME_SPAN blocks ending in hash
+    pub fn median_time_past(&self, hash: &Sha256) -> u64 {
+        let mut timestamps: Vec<u64> = self.ancestors(hash).take(MEDIAN_TIME_SPAN).map(|(_, header)| header.timestamp).collect();
+        timestamps.sort();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    // Checks a header against its parent, which has to be in the tree unless
+    // the tree is empty and the header is a genesis block
+    pub fn check(&self, header: &BlockHeader) -> Result<(), BlockError> {
+        if header.difficulty == 0 {
+            return Err(BlockError::InvalidDifficulty);
+        }
+        if !header.has_valid_work() {
+            return Err(BlockError::InvalidHash);
+        }
+
+        let parent = match self.entries.get(&header.previous_block_hash) {
+            Some(_) => Some(&header.previous_block_hash),
+            None if self.entries.is_empty() && header.previous_block_hash == Sha256::hash(&[]) => None,
+            None => return Err(BlockError::InvalidPreviousBlockHash)
+        };
+        if header.difficulty != self.next_difficulty(parent) {
+            return Err(BlockError::InvalidDifficulty);
+        }
+        if header.timestamp > util::timestamp() + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockError::InvalidTimestamp);
+        }
+        if parent.is_some_and(|parent| header.timestamp < self.median_time_past(parent)) {
+            return Err(BlockError::InvalidTimestamp);
+        }
+        Ok(())
+    }
+
+    // Checks and adds a header, returns false if it was already known
+    pub fn accept(&mut self, header: BlockHeader) -> Result<bool, BlockError> {
+        let hash = header.hash();
+        if self.entries.contains_key(&hash) {
+            return Ok(false);
+        }
+        self.check(&header)?;
+
+        let (height, total_work) = match self.entries.get(&header.previous_block_hash) {
+            Some(parent) => (parent.height + 1, parent.total_work + header.work()),
+            None => (0, header.work())
+        };
+        let better = self.best.as_ref().is_none_or(|best| total_work > self.entries[best].total_work);
+        self.entries.insert(hash.clone(), HeaderEntry { header, height, total_work, sequence: self.next_sequence });
+        self.next_sequence += 1;
+        if better {
+            self.best = Some(hash);
+        }
+        Ok(true)
+    }
+
+    // Removes a header and every header building on it, returns their hashes
+    pub fn remove_branch(&mut self, hash: &Sha256) -> Vec<Sha256> {
+        if !self.entries.contains_key(hash) {
+            return vec![];
+        }
+        let mut children: HashMap<&Sha256, Vec<&Sha256>> = HashMap::new();
+        for (child, entry) in &self.entries {
+            children.entry(&entry.header.previous_block_hash).or_default().push(child);
+        }
+        let mut removed = vec![hash.clone()];
+        let mut idx = 0;
+        while idx < removed.len() {
+            removed.extend(children.get(&removed[idx]).into_iter().flatten().map(|child| (*child).clone()));
+            idx += 1;
+        }
+
+        for hash in &removed {
+            self.entries.remove(hash);
+        }
+        if self.best.as_ref().is_some_and(|best| !self.entries.contains_key(best)) {
+            self.best = self.entries.iter()
+                .max_by_key(|(_, entry)| (entry.total_work, std::cmp::Reverse(entry.sequence)))
+                .map(|(hash, _)| hash.clone());
+        }
+        removed
+    }
+
+    // Hashes along the best chain, dense near the tip and then exponentially
+    // further apart, ending with the genesis block. Whoever receives it can
+    // find the last block both sides have in common.
+    pub fn locator(&self) -> Vec<Sha256> {
+        let Some(best) = &self.best else {
+            return vec![];
+        };
+        let chain: Vec<&Sha256> = self.ancestors(best).map(|(hash, _)| hash).collect();
+        let mut locator = Vec::new();
+        let mut idx = 0;
+        let mut step = 1;
+        while idx < chain.len() {
+            locator.push(chain[idx].clone());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            idx += step;
+        }
+        if locator.last() != chain.last().copied() {
+            locator.push(chain[chain.len() - 1].clone());
+        }
+        locator
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{blockchain::{block::Block, consensus::difficulty_to_target, transaction::Transaction, Blockchain, MINING_REWARD}, ecdsa};
+    use super::*;
+
+    #[test]
+    fn test_header_chain() {
+        let (pubkey, _) = ecdsa::generate_keypair();
+        let mut blockchain = Blockchain::new(Transaction::get_coinbase(pubkey.clone(), MINING_REWARD));
+        for _ in 0..11 {
+            let mut block = blockchain.create_block(Transaction::get_coinbase(pubkey.clone(), MINING_REWARD), vec![]);
+            block.mine();
+            blockchain.add_block(block).unwrap();
+        }
+        let headers: Vec<BlockHeader> = blockchain.blocks.iter().map(|block| block.header.clone()).collect();
+
+        // No transactions are needed to follow the chain
+        let mut chain = HeaderChain::new(blockchain.params().clone());
+        assert_eq!(chain.accept(headers[1].clone()), Err(BlockError::InvalidPreviousBlockHash));
+        for header in &headers[..5] {
+            assert_eq!(chain.accept(header.clone()), Ok(true));
+        }
+        assert_eq!(chain.accept(headers[4].clone()), Ok(false));
+
+        let mut invalid = headers[5].clone();
+        invalid.nonce += 1;
+        while invalid.has_valid_work() {
+            invalid.nonce += 1;
+        }
+        assert_eq!(chain.accept(invalid), Err(BlockError::InvalidHash));
+        let mut invalid = headers[5].clone();
+        invalid.difficulty = 1;
+        assert!(invalid.hash().is_valid(&difficulty_to_target(1)));
+        assert_eq!(chain.accept(invalid), Err(BlockError::InvalidDifficulty));
+
+        // Past the retarget the difficulty is recomputed from the headers alone
+        for header in &headers[5..] {
+            assert_eq!(chain.accept(header.clone()), Ok(true));
+        }
+        assert_eq!(chain.best_len(), 12);
+        assert_eq!(chain.best_hash(), Some(&blockchain.blocks[11].hash));
+        assert_eq!(chain.total_work(&blockchain.blocks[11].hash), Some(blockchain.total_work()));
+        assert_eq!(chain.locator().first(), Some(&blockchain.blocks[11].hash));
+        assert_eq!(chain.locator().last(), Some(&blockchain.blocks[0].hash));
+
+        // A side branch with more work becomes the best chain, removing it falls back
+        let mut fork = Block::new(blockchain.blocks[10].hash.clone(), vec![Transaction::get_coinbase(pubkey.clone(), MINING_REWARD)]);
+        fork.header.difficulty = chain.next_difficulty(Some(&blockchain.blocks[10].hash));
+        fork.mine();
+        chain.accept(fork.header.clone()).unwrap();
+        assert_eq!(chain.best_hash(), Some(&blockchain.blocks[11].hash));
+        let mut extension = Block::new(fork.hash.clone(), vec![Transaction::get_coinbase(pubkey, MINING_REWARD)]);
+        extension.header.difficulty = chain.next_difficulty(Some(&fork.hash));
+        extension.mine();
+        chain.accept(extension.header.clone()).unwrap();
+        assert_eq!(chain.best_hash(), Some(&extension.hash));
+
+        // Of the branches left with equal work the one seen first wins
+        let mut later = Block::new(blockchain.blocks[10].hash.clone(), vec![Transaction::get_coinbase(ecdsa::generate_keypair().0, MINING_REWARD)]);
+        later.header.difficulty = fork.header.difficulty;
+        later.mine();
+        chain.accept(later.header.clone()).unwrap();
+        assert_eq!(chain.remove_branch(&fork.hash).len(), 2);
+        assert_eq!(chain.best_hash(), Some(&blockchain.blocks[11].hash));
+        assert_eq!(chain.remove_branch(&blockchain.blocks[11].hash), vec![blockchain.blocks[11].hash.clone()]);
+        assert_eq!(chain.best_hash(), Some(&later.hash));
+        assert!(chain.remove_branch(&fork.hash).is_empty());
+    }
+}
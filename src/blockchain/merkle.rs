@@ -134,10 +134,8 @@ impl MerkleTree {
                 .collect::<Vec<u8>>();
 
             node_hash = Sha256::hash(&concat);
-            println!("{}", node_hash);
         }
 
-        println!("{}", root_hash);
         node_hash == root_hash
     }
 }
@@ -1,21 +1,49 @@
-use std::collections::HashMap;
-use block::Block;
-use merkle::MerkleTree;
-use transaction::{Transaction, TxOutput};
+use std::{cell::RefCell, collections::{HashMap, HashSet}, path::Path};
+use block::{Block, BlockHeader, MAX_BLOCK_SIZE};
+use consensus::ConsensusParams;
+use encoding::Encode;
+use headers::HeaderChain;
+use script::{Script, ScriptError, SignatureBatch, TransactionChecker};
+use storage::{ChainStore, StorageError};
+use transaction::{Transaction, TxOutput, SEQUENCE_LOCKTIME_DISABLE_FLAG, SEQUENCE_LOCKTIME_GRANULARITY, SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG};
 use crate::{ecdsa, sha256::Sha256};
 
+pub mod address;
 pub mod block;
+pub mod consensus;
+pub mod encoding;
+pub mod headers;
 pub mod merkle;
+pub mod script;
+pub mod storage;
 pub mod transaction;
 
+// Subsidy of the first blocks, see ConsensusParams::block_subsidy
 pub const MINING_REWARD: u64 = 50;
 
 #[derive(Debug, PartialEq)]
 pub enum TransactionError {
     InvalidSignature,
+    // The signature of the input of the transaction with the txid, found
+    // invalid after the signature batch of a block failed
+    InvalidInputSignature(Sha256, usize),
     InsufficientFunds,
     UnallowedTransaction,
-    MismatchedOutput
+    MismatchedOutput,
+    InvalidScript(ScriptError),
+    LockTimeNotReached,
+    SequenceLockNotReached
+}
+
+// A present but invalid signature keeps its own error, anything else that
+// makes a script fail is reported as an invalid script
+impl From<ScriptError> for TransactionError {
+    fn from(e: ScriptError) -> Self {
+        match e {
+            ScriptError::InvalidSignature => TransactionError::InvalidSignature,
+            e => TransactionError::InvalidScript(e)
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -24,21 +52,53 @@ pub enum BlockError {
     InvalidMerkleRoot,
     InvalidPreviousBlockHash,
     InvalidCoinbase,
-    InvalidTransactions(Vec<TransactionError>)
+    InvalidDifficulty,
+    InvalidTimestamp,
+    TooLarge,
+    InvalidTransactions(Vec<TransactionError>),
+    DuplicateBlock,
+    Storage(String)
+}
+
+// How a new block changed the chain. On a reorg the blocks that were removed
+// from the active chain are returned, tip first
+#[derive(Debug)]
+pub enum BlockStatus {
+    Extended,
+    Fork,
+    Reorg { disconnected: Vec<Block> }
+}
+
+// The previous value of every UTXO entry a block touched, in the order they
+// were modified. Restoring them in reverse order disconnects the block
+#[derive(Default)]
+struct BlockUndo {
+    previous: Vec<(Sha256, Option<Vec<TxOutput>>)>,
+}
+
+impl BlockUndo {
+    fn record(&mut self, utxo: &HashMap<Sha256, Vec<TxOutput>>, txid: &Sha256) {
+        self.previous.push((txid.clone(), utxo.get(txid).cloned()));
+    }
 }
 
-#[derive(Clone)]
 pub struct Blockchain {
     pub blocks: Vec<Block>,
     utxo: HashMap<Sha256, Vec<TxOutput>>,
+    tx_heights: HashMap<Sha256, usize>,
+    tree: HashMap<Sha256, Block>,
+    headers: HeaderChain,
+    undo: Vec<BlockUndo>,
+    store: Option<ChainStore>,
 }
 
 impl Blockchain {
     pub fn new(coinbase: Transaction) -> Self {
-        let mut blockchain = Self {
-            blocks: vec![],
-            utxo: HashMap::new(),
-        };
+        Self::new_with_params(coinbase, ConsensusParams::default())
+    }
+
+    pub fn new_with_params(coinbase: Transaction, params: ConsensusParams) -> Self {
+        let mut blockchain = Self::empty_with_params(params);
         let mut block = blockchain.create_block(coinbase, vec![]);
         block.mine();
 
@@ -48,10 +108,72 @@ impl Blockchain {
     }
 
     pub fn empty() -> Self {
+        Self::empty_with_params(ConsensusParams::default())
+    }
+
+    pub fn empty_with_params(params: ConsensusParams) -> Self {
         Self {
             blocks: vec![],
             utxo: HashMap::new(),
+            tx_heights: HashMap::new(),
+            tree: HashMap::new(),
+            headers: HeaderChain::new(params),
+            undo: vec![],
+            store: None,
+        }
+    }
+
+    // Opens the chain stored in dir, creating an empty one if there is none.
+    // The block tree and UTXO set are rebuilt by replaying the stored blocks,
+    // afterwards every accepted block is appended to the store.
+    pub fn open(dir: &Path) -> Result<Self, StorageError> {
+        Self::open_with_params(dir, ConsensusParams::default())
+    }
+
+    pub fn open_with_params(dir: &Path, params: ConsensusParams) -> Result<Self, StorageError> {
+        let mut store = ChainStore::open(dir)?;
+        let mut blockchain = Self::replay(params, store.read_blocks()?, store.chain())?;
+        let chain: Vec<Sha256> = blockchain.blocks.iter().map(|block| block.hash.clone()).collect();
+        store.set_chain(&chain)?;
+        blockchain.store = Some(store);
+        Ok(blockchain)
+    }
+
+    // A copy of the chain stored in dir that is not written back, see
+    // ChainStore::read_only_blocks
+    pub fn open_read_only(dir: &Path) -> Result<Self, StorageError> {
+        Self::open_read_only_with_params(dir, ConsensusParams::default())
+    }
+
+    pub fn open_read_only_with_params(dir: &Path, params: ConsensusParams) -> Result<Self, StorageError> {
+        Self::replay(params, ChainStore::read_only_blocks(dir)?, &ChainStore::read_only_chain(dir)?)
+    }
+
+    // The blocks of the stored active chain have all been accepted before, so
+    // any of them failing now means the data is corrupt or was written under
+    // other consensus rules. Side branch blocks are stored before their
+    // transactions are verified, a block that was rejected during a reorg is
+    // rejected again here without failing the open. The chain file may lag
+    // behind after a crash, the blocks it is missing are among the side
+    // branch blocks and extend the chain again.
+    fn replay(params: ConsensusParams, blocks: Vec<Block>, chain: &[Sha256]) -> Result<Self, StorageError> {
+        let active: HashSet<&Sha256> = chain.iter().collect();
+        let (active_blocks, side_blocks): (Vec<Block>, Vec<Block>) = blocks.into_iter().partition(|block| active.contains(&block.hash));
+        let stored: HashSet<&Sha256> = active_blocks.iter().map(|block| &block.hash).collect();
+        if let Some(missing) = chain.iter().find(|hash| !stored.contains(hash)) {
+            return Err(StorageError::MissingBlock(missing.clone()));
+        }
+
+        // Parents are stored before their children, so the active blocks are
+        // in the order of their heights
+        let mut blockchain = Self::empty_with_params(params);
+        for block in active_blocks {
+            blockchain.add_block(block).map_err(StorageError::InvalidChain)?;
         }
+        for block in side_blocks {
+            let _ = blockchain.add_block(block);
+        }
+        Ok(blockchain)
     }
 
     pub fn create_block(&self, coinbase: Transaction, transactions: Vec<Transaction>) -> Block {
@@ -60,19 +182,175 @@ impl Blockchain {
         } else {
             self.blocks.last().unwrap().hash()
         };
-        Block::new(previous_block_hash, {
+        let mut block = Block::new(previous_block_hash, {
             let mut txs = Vec::with_capacity(1 + transactions.len());
             txs.push(coinbase);
             txs.extend(transactions);
             txs
-        })
+        });
+        block.header.difficulty = self.next_difficulty(self.blocks.last().map(|tip| &tip.hash));
+        block
+    }
+
+    pub fn params(&self) -> &ConsensusParams {
+        self.headers.params()
+    }
+
+    // The headers of every block in the block tree
+    pub fn headers(&self) -> &HeaderChain {
+        &self.headers
+    }
+
+    // Subsidy for the block that would extend the current tip
+    pub fn next_block_subsidy(&self) -> u64 {
+        self.params().block_subsidy(self.blocks.len())
+    }
+
+    // Coins created by the active chain up to and including its tip
+    pub fn total_supply(&self) -> u64 {
+        match self.blocks.len() {
+            0 => 0,
+            len => self.params().total_supply(len - 1)
+        }
+    }
+
+    // The difficulty required for a block building on parent, which is None
+    // for the genesis block
+    pub fn next_difficulty(&self, parent: Option<&Sha256>) -> u64 {
+        self.headers.next_difficulty(parent)
+    }
+
+    // Median timestamp of the last MEDIAN_TIME_SPAN blocks ending in hash
+    pub fn median_time_past(&self, hash: &Sha256) -> u64 {
+        self.headers.median_time_past(hash)
+    }
+
+    // Median time past of the block before height on the active chain, the
+    // time lock times are compared against for a block at height
+    pub fn median_time_before(&self, height: usize) -> u64 {
+        match height.checked_sub(1).and_then(|previous| self.blocks.get(previous)) {
+            Some(block) => self.median_time_past(&block.hash),
+            None => 0
+        }
+    }
+
+    // Adds a block to the block tree. A block extending the active chain is
+    // connected directly, a block on another branch only becomes active once
+    // that branch has more cumulative work than the active chain.
+    pub fn add_block(&mut self, block: Block) -> Result<BlockStatus, BlockError> {
+        self.check_block(&block)?;
+        if self.tree.contains_key(&block.hash) {
+            return Err(BlockError::DuplicateBlock);
+        }
+
+        // The difficulty and timestamp of a block depend on its branch, they
+        // are checked against its parent before the block enters the block tree
+        self.headers.accept(block.header.clone())?;
+        let hash = block.hash.clone();
+        let extends_tip = self.blocks.last().is_none_or(|tip| tip.hash == block.header.previous_block_hash);
+        self.tree.insert(hash.clone(), block.clone());
+
+        if extends_tip {
+            if let Err(e) = self.connect_block(block.clone()) {
+                self.remove_branch(&hash);
+                return Err(e);
+            }
+            if let Err(e) = self.persist(&block) {
+                self.disconnect_tip();
+                self.remove_branch(&hash);
+                return Err(e);
+            }
+            return Ok(BlockStatus::Extended);
+        }
+
+        if let Err(e) = self.persist(&block) {
+            self.remove_branch(&hash);
+            return Err(e);
+        }
+        if self.headers.total_work(&hash).unwrap() <= self.total_work() {
+            return Ok(BlockStatus::Fork);
+        }
+        let disconnected = self.reorganize(&hash)?;
+        self.persist_chain()?;
+        Ok(BlockStatus::Reorg { disconnected })
+    }
+
+    fn persist(&mut self, block: &Block) -> Result<(), BlockError> {
+        if let Some(store) = self.store.as_mut() {
+            store.append_block(block).map_err(|e| BlockError::Storage(format!("{:?}", e)))?;
+        }
+        self.persist_chain()
+    }
+
+    // Records the active chain in the store. If this fails the store lags
+    // behind, which the next open catches up on, see replay.
+    fn persist_chain(&mut self) -> Result<(), BlockError> {
+        if let Some(store) = self.store.as_mut() {
+            let chain: Vec<Sha256> = self.blocks.iter().map(|block| block.hash.clone()).collect();
+            store.set_chain(&chain).map_err(|e| BlockError::Storage(format!("{:?}", e)))?;
+        }
+        Ok(())
+    }
+
+    // Switches the active chain to the branch ending in new_tip. If a block on
+    // the new branch turns out to be invalid, it is dropped from the tree
+    // together with its descendants and the old chain is restored.
+    fn reorganize(&mut self, new_tip: &Sha256) -> Result<Vec<Block>, BlockError> {
+        let mut branch = Vec::new();
+        let mut cursor = new_tip.clone();
+        while !self.is_active(&cursor) {
+            let block = &self.tree[&cursor];
+            branch.push(block.clone());
+            cursor = block.header.previous_block_hash.clone();
+        }
+        branch.reverse();
+
+        let fork_height = self.headers.height(&cursor).unwrap();
+        let mut disconnected = Vec::new();
+        while self.blocks.len() > fork_height + 1 {
+            disconnected.push(self.disconnect_tip());
+        }
+
+        for (connected, block) in branch.into_iter().enumerate() {
+            let hash = block.hash.clone();
+            if let Err(e) = self.connect_block(block) {
+                self.remove_branch(&hash);
+                for _ in 0..connected {
+                    self.disconnect_tip();
+                }
+                for block in disconnected.into_iter().rev() {
+                    self.connect_block(block).expect("Previously active block failed to reconnect");
+                }
+                return Err(e);
+            }
+        }
+        Ok(disconnected)
+    }
+
+    fn is_active(&self, hash: &Sha256) -> bool {
+        match self.headers.height(hash) {
+            Some(height) => self.blocks.get(height).is_some_and(|block| block.hash == *hash),
+            None => false
+        }
+    }
+
+    // Removes a block and every block building on it from the block tree
+    fn remove_branch(&mut self, hash: &Sha256) {
+        for removed in self.headers.remove_branch(hash) {
+            self.tree.remove(&removed);
+        }
     }
 
-    pub fn add_block(&mut self, block: Block) -> Result<(), BlockError> {
+    fn connect_block(&mut self, block: Block) -> Result<(), BlockError> {
         self.verify_new_block(&block)?;
+        let mut undo = BlockUndo::default();
         for transaction in block.merkle_tree.transactions() {
-            self.utxo.insert(transaction.hash(), transaction.outputs.clone());
+            let txid = transaction.hash();
+            self.tx_heights.insert(txid.clone(), self.blocks.len());
+            undo.record(&self.utxo, &txid);
+            self.utxo.insert(txid, transaction.outputs.clone());
             for input in &transaction.inputs {
+                undo.record(&self.utxo, &input.txid);
                 let v = self.utxo.get_mut(&input.txid).unwrap();
                 v[input.vout as usize].spent = true;
                 if v.iter().all(|output| output.spent) {
@@ -81,82 +359,245 @@ impl Blockchain {
             }
         }
         self.blocks.push(block);
+        self.undo.push(undo);
         Ok(())
     }
 
-    pub fn verify_new_transaction(&self, tx: &transaction::Transaction) -> Result<(), TransactionError> {
-        let mut total_input = 0;
+    fn disconnect_tip(&mut self) -> Block {
+        let undo = self.undo.pop().expect("No block to disconnect");
+        for (txid, previous) in undo.previous.into_iter().rev() {
+            match previous {
+                Some(outputs) => self.utxo.insert(txid, outputs),
+                None => self.utxo.remove(&txid)
+            };
+        }
+        let block = self.blocks.pop().unwrap();
+        for transaction in block.merkle_tree.transactions() {
+            self.tx_heights.remove(&transaction.hash());
+        }
+        block
+    }
+
+    pub fn total_work(&self) -> u128 {
+        self.blocks.last().map_or(0, |tip| self.headers.total_work(&tip.hash).unwrap())
+    }
+
+    pub fn get_block(&self, hash: &Sha256) -> Option<&Block> {
+        self.tree.get(hash)
+    }
+
+    pub fn contains_block(&self, hash: &Sha256) -> bool {
+        self.tree.contains_key(hash)
+    }
+
+    // The headers of the active chain following the first locator hash on it,
+    // starting from the genesis block if none is
+    pub fn headers_after(&self, locator: &[Sha256], max: usize) -> Vec<BlockHeader> {
+        let start = locator.iter().find_map(|hash| self.block_height(hash)).map_or(0, |height| height + 1);
+        self.blocks.iter().skip(start).take(max).map(|block| block.header.clone()).collect()
+    }
+
+    // Height of the block if it is part of the active chain
+    pub fn block_height(&self, hash: &Sha256) -> Option<usize> {
+        self.headers.height(hash).filter(|_| self.is_active(hash))
+    }
+
+    pub fn get_unspent_output(&self, txid: &Sha256, vout: u32) -> Option<&TxOutput> {
+        self.utxo.get(txid)
+            .and_then(|outputs| outputs.get(vout as usize))
+            .filter(|output| !output.spent)
+    }
+
+    // Verifies a loose transaction against the UTXO set and returns its fee.
+    // Coinbase transactions are only valid as part of a block. Signatures are
+    // checked strictly, so a malleated copy of a transaction is not relayed
+    pub fn verify_new_transaction(&self, tx: &transaction::Transaction) -> Result<u64, TransactionError> {
+        if tx.is_coinbase() {
+            return Err(TransactionError::UnallowedTransaction);
+        }
+        self.verify_transaction_with(tx, true, |txid, vout| self.get_unspent_output(txid, vout).cloned())
+    }
+
+    // Relative lock time of an input spending an output confirmed at
+    // coin_height, for a transaction in the block at height
+    fn sequence_lock_reached(&self, sequence: u32, coin_height: usize, height: usize) -> bool {
+        if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return true;
+        }
+        let value = sequence & SEQUENCE_LOCKTIME_MASK;
+        if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            let required = self.median_time_before(coin_height) + ((value as u64) << SEQUENCE_LOCKTIME_GRANULARITY);
+            self.median_time_before(height) >= required
+        } else {
+            height >= coin_height + value as usize
+        }
+    }
+
+    // Verifies a transaction against the outputs returned by lookup, which
+    // only returns outputs that are still unspent. The fee is whatever the
+    // inputs carry on top of the outputs, a coinbase has no fee and its value
+    // is checked together with the rest of its block.
+    //
+    // Lock times are checked as if the transaction was in the next block.
+    // Outputs that are not confirmed yet count as confirmed in that block.
+    // strict selects the checks for unconfirmed transactions, see
+    // TransactionChecker, blocks only need signatures to be valid.
+    pub fn verify_transaction_with<F>(&self, tx: &Transaction, strict: bool, lookup: F) -> Result<u64, TransactionError>
+    where
+        F: Fn(&Sha256, u32) -> Option<TxOutput>
+    {
+        self.verify_transaction_batched(tx, strict, None, lookup)
+    }
+
+    // Like verify_transaction_with, with the signatures of OP_CHECKSIG only
+    // recorded in batch if one is given. The result then only holds if the
+    // batch turns out valid.
+    fn verify_transaction_batched<F>(&self, tx: &Transaction, strict: bool, batch: Option<&RefCell<SignatureBatch>>, lookup: F) -> Result<u64, TransactionError>
+    where
+        F: Fn(&Sha256, u32) -> Option<TxOutput>
+    {
+        let height = self.blocks.len();
+        if !tx.is_final(height as u64, self.median_time_before(height)) {
+            return Err(TransactionError::LockTimeNotReached);
+        }
+
+        let mut total_input: u64 = 0;
+        let mut spent = HashSet::new();
         for (i, input) in tx.inputs.iter().enumerate() {
-            let ref_output = self.utxo.get(&input.txid);
+            let ref_output = lookup(&input.txid, input.vout);
             if ref_output.is_none() {
                 return Err(TransactionError::InsufficientFunds);
             }
-            let ref_output = ref_output.unwrap().get(input.vout as usize);
-            if ref_output.is_none() || ref_output.unwrap().spent {
-                return Err(TransactionError::InsufficientFunds);
-            }
             let ref_output = ref_output.unwrap();
-            if ref_output.script_pubkey != input.script_sig.1 {
-                return Err(TransactionError::UnallowedTransaction);
+            let checker = TransactionChecker {
+                transaction: tx,
+                input: i,
+                script_code: &ref_output.script_pubkey,
+                strict,
+                batch,
+            };
+            script::verify_script(&input.script_sig, &ref_output.script_pubkey, &checker)?;
+
+            let coin_height = self.tx_heights.get(&input.txid).copied().unwrap_or(height);
+            if !self.sequence_lock_reached(input.sequence, coin_height, height) {
+                return Err(TransactionError::SequenceLockNotReached);
             }
 
-            let hash = tx.get_input_hash(i, &ref_output.script_pubkey);
-            if !ecdsa::verify(input.script_sig.0, hash.bytes(), &input.script_sig.1) {
-                return Err(TransactionError::InvalidSignature);
+            // The same output can not be spent twice by one transaction
+            if !spent.insert((input.txid.clone(), input.vout)) {
+                return Err(TransactionError::InsufficientFunds);
             }
 
-            total_input += ref_output.value;
+            total_input = total_input.checked_add(ref_output.value).ok_or(TransactionError::MismatchedOutput)?;
         }
 
-        let mut total_output = 0;
-        for output in &tx.outputs {
-            total_output += output.value;
-        }
+        let total_output = tx.output_value().ok_or(TransactionError::MismatchedOutput)?;
 
-        if tx.is_coinbase() && total_output == MINING_REWARD {
-            return Ok(());
+        if tx.is_coinbase() {
+            return Ok(0);
         }
 
-        if total_input != total_output {
+        if total_input < total_output {
             return Err(TransactionError::MismatchedOutput);
         }
 
-        return Ok(());
+        Ok(total_input - total_output)
     }
 
-    fn verify_new_block(&self, block: &Block) -> Result<(), BlockError> {
-        if !self.blocks.is_empty() && block.previous_block_hash != self.blocks.last().unwrap().hash {
-            return Err(BlockError::InvalidPreviousBlockHash);
+    // Checks that do not depend on the position of the block in the chain
+    fn check_block(&self, block: &Block) -> Result<(), BlockError> {
+        if block.header.difficulty == 0 {
+            return Err(BlockError::InvalidDifficulty);
         }
 
-        if block.hash() != block.hash || !block.hash.is_valid(block.difficulty) {
+        if block.hash() != block.hash || !block.hash.is_valid(&block.target()) {
             return Err(BlockError::InvalidHash);
         }
 
-        if MerkleTree::new(block.merkle_tree.transactions().clone()).root_hash() != block.merkle_tree.root_hash() {
+        if block.header.merkle_root != *block.merkle_tree.root_hash() {
             return Err(BlockError::InvalidMerkleRoot);
         }
 
-        let mut transaction_errors = Vec::new();
-        let mut coinbase_cnt = 0;
-        for tx in block.merkle_tree.transactions() {
-            if tx.is_coinbase() {
-                coinbase_cnt += 1;
-            }
-            let _ = self.verify_new_transaction(tx).map_err(|e| {
-                transaction_errors.push(e);
-            });
+        if block.to_bytes().len() > MAX_BLOCK_SIZE {
+            return Err(BlockError::TooLarge);
         }
 
+        let coinbase_cnt = block.merkle_tree.transactions().iter().filter(|tx| tx.is_coinbase()).count();
         if coinbase_cnt != 1 {
             return Err(BlockError::InvalidCoinbase)
         }
+        Ok(())
+    }
+
+    // Verifies the block on top of the current tip. Transactions are checked
+    // in order, so a transaction may spend outputs created earlier in the
+    // same block but no output can be spent twice within the block.
+    fn verify_new_block(&self, block: &Block) -> Result<(), BlockError> {
+        if !self.blocks.is_empty() && block.header.previous_block_hash != self.blocks.last().unwrap().hash {
+            return Err(BlockError::InvalidPreviousBlockHash);
+        }
+
+        self.check_block(block)?;
+
+        // The signatures are only collected while the scripts run and then
+        // checked together. If the batch fails, its signatures are checked
+        // one at a time to find the inputs whose signature is invalid.
+        let batch = RefCell::new(SignatureBatch::new());
+        let (fees, mut errors) = match self.verify_block_transactions(block, &batch) {
+            Ok(fees) => (fees, Vec::new()),
+            Err(errors) => (0, errors)
+        };
+        let batch = batch.into_inner();
+        if !batch.verify() {
+            errors.extend(batch.invalid_inputs().into_iter().map(|(txid, input)| TransactionError::InvalidInputSignature(txid, input)));
+        }
+        if !errors.is_empty() {
+            return Err(BlockError::InvalidTransactions(errors));
+        }
+
+        // The coinbase may claim the subsidy and the fees of every other
+        // transaction in the block, but nothing more
+        let subsidy = self.params().block_subsidy(self.blocks.len());
+        let coinbase = block.merkle_tree.transactions().iter().find(|tx| tx.is_coinbase()).unwrap();
+        match coinbase.output_value() {
+            Some(value) if value <= subsidy.saturating_add(fees) => Ok(()),
+            _ => Err(BlockError::InvalidCoinbase)
+        }
+    }
+
+    // Verifies the transactions of a block in order and returns their fees.
+    // The signatures of OP_CHECKSIG are only recorded in the batch.
+    fn verify_block_transactions(&self, block: &Block, batch: &RefCell<SignatureBatch>) -> Result<u64, Vec<TransactionError>> {
+        let mut transaction_errors = Vec::new();
+        let mut fees: u64 = 0;
+        let mut spent: HashSet<(Sha256, u32)> = HashSet::new();
+        let mut created: HashMap<Sha256, &Vec<TxOutput>> = HashMap::new();
+        for tx in block.merkle_tree.transactions() {
+            let lookup = |txid: &Sha256, vout: u32| {
+                if spent.contains(&(txid.clone(), vout)) {
+                    return None;
+                }
+                match created.get(txid) {
+                    Some(outputs) => outputs.get(vout as usize).cloned(),
+                    None => self.get_unspent_output(txid, vout).cloned()
+                }
+            };
+            match self.verify_transaction_batched(tx, false, Some(batch), lookup) {
+                Ok(fee) => {
+                    fees = fees.saturating_add(fee);
+                    for input in &tx.inputs {
+                        spent.insert((input.txid.clone(), input.vout));
+                    }
+                    created.insert(tx.hash(), &tx.outputs);
+                }
+                Err(e) => transaction_errors.push(e)
+            }
+        }
 
-        if transaction_errors.len() != 0 {
-            return Err(BlockError::InvalidTransactions(transaction_errors));
+        if !transaction_errors.is_empty() {
+            return Err(transaction_errors);
         }
-        
-        return Ok(());
+        Ok(fees)
     }
 
     // Builds a copy of the blockchain and verifies the integrity of the chain
@@ -165,7 +606,7 @@ impl Blockchain {
             return Ok(());
         }
 
-        let mut blockchain = Blockchain::empty();
+        let mut blockchain = Blockchain::empty_with_params(self.params().clone());
         for block in &self.blocks {
             blockchain.add_block(block.clone())?;
         }
@@ -177,6 +618,11 @@ impl Blockchain {
         Ok(())
     }
 
+    // Height of the active chain block that confirmed the transaction
+    pub fn transaction_height(&self, txid: &Sha256) -> Option<usize> {
+        self.tx_heights.get(txid).copied()
+    }
+
     pub fn has_transaction(&self, tx: &Transaction) -> bool {
         if let Some(utxo) = self.utxo.get(&tx.hash()) {
             return utxo == &tx.outputs;
@@ -186,11 +632,17 @@ impl Blockchain {
         }
     }
 
+    // Outputs paying to the public key hash of pubkey
     pub fn get_user_funds(&self, pubkey: &ecdsa::ECDSAPublicKey) -> Vec<(Sha256, u32, u64)> {
+        self.get_script_funds(&Script::p2pkh(pubkey))
+    }
+
+    // Unspent outputs locked with exactly this script
+    pub fn get_script_funds(&self, script: &Script) -> Vec<(Sha256, u32, u64)> {
         let mut funds = Vec::new();
         for (txid, outputs) in &self.utxo {
             for (vout, output) in outputs.iter().enumerate() {
-                if output.script_pubkey == *pubkey {
+                if output.script_pubkey == *script && !output.spent {
                     funds.push((txid.clone(), vout as u32, output.value));
                 }
             }
@@ -201,14 +653,6 @@ impl Blockchain {
     pub fn get_utxo(&self) -> HashMap<Sha256, Vec<TxOutput>> {
         self.utxo.clone()
     }
-
-    pub fn set_output_spent(&mut self, txid: &Sha256, vout: u32, spent: bool) {
-        if let Some(outputs) = self.utxo.get_mut(txid) {
-            if let Some(output) = outputs.get_mut(vout as usize) {
-                output.spent = spent;
-            }
-        }
-    }
 }
 
 impl std::fmt::Debug for Blockchain {
@@ -219,4 +663,306 @@ impl std::fmt::Debug for Blockchain {
         }
         write!(f, "{}", res)
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::{blockchain::{consensus::MAX_FUTURE_BLOCK_TIME, transaction::{TxInput, SEQUENCE_FINAL}}, ecdsa::{self, point::AffinePoint, secp256k1, ECDSAPublicKey}, node::Node, util};
+    use super::*;
+
+    fn mine_on(previous: &Sha256, miner: &ECDSAPublicKey, value: u64) -> Block {
+        let mut block = Block::new(previous.clone(), vec![Transaction::get_coinbase(miner.clone(), value)]);
+        block.mine();
+        block
+    }
+
+    #[test]
+    fn test_fork_and_reorg() {
+        let (miner1, _) = ecdsa::generate_keypair();
+        let (miner2, _) = ecdsa::generate_keypair();
+        let mut blockchain = Blockchain::new(Transaction::get_coinbase(miner1.clone(), MINING_REWARD));
+        let genesis = blockchain.blocks[0].hash.clone();
+
+        let a1 = mine_on(&genesis, &miner1, MINING_REWARD);
+        assert!(matches!(blockchain.add_block(a1.clone()), Ok(BlockStatus::Extended)));
+
+        // A competing block with the same amount of work does not replace the tip
+        let b1 = mine_on(&genesis, &miner2, MINING_REWARD);
+        assert!(matches!(blockchain.add_block(b1.clone()), Ok(BlockStatus::Fork)));
+        assert_eq!(blockchain.blocks.last().unwrap().hash, a1.hash);
+        assert!(blockchain.contains_block(&b1.hash));
+
+        let b2 = mine_on(&b1.hash, &miner2, MINING_REWARD);
+        match blockchain.add_block(b2.clone()) {
+            Ok(BlockStatus::Reorg { disconnected }) => {
+                assert_eq!(disconnected.len(), 1);
+                assert_eq!(disconnected[0].hash, a1.hash);
+            }
+            other => panic!("Expected a reorg, got {:?}", other)
+        }
+
+        assert_eq!(blockchain.blocks.len(), 3);
+        assert_eq!(blockchain.blocks[2].hash, b2.hash);
+        assert_eq!(blockchain.get_user_funds(&miner1).len(), 1);
+        assert_eq!(blockchain.get_user_funds(&miner2).len(), 2);
+        assert_eq!(blockchain.verify_chain(), Ok(()));
+        assert_eq!(blockchain.add_block(b2).err(), Some(BlockError::DuplicateBlock));
+    }
+
+    #[test]
+    fn test_reorg_to_invalid_branch() {
+        let (miner1, _) = ecdsa::generate_keypair();
+        let (miner2, _) = ecdsa::generate_keypair();
+        let mut blockchain = Blockchain::new(Transaction::get_coinbase(miner1.clone(), MINING_REWARD));
+        let genesis = blockchain.blocks[0].hash.clone();
+        let a1 = mine_on(&genesis, &miner1, MINING_REWARD);
+        blockchain.add_block(a1.clone()).unwrap();
+
+        // The coinbase claims too much, which is only noticed once the branch is connected
+        let b1 = mine_on(&genesis, &miner2, 2 * MINING_REWARD);
+        assert!(matches!(blockchain.add_block(b1.clone()), Ok(BlockStatus::Fork)));
+        let b2 = mine_on(&b1.hash, &miner2, MINING_REWARD);
+        assert_eq!(blockchain.add_block(b2.clone()).err(), Some(BlockError::InvalidCoinbase));
+
+        assert_eq!(blockchain.blocks.len(), 2);
+        assert_eq!(blockchain.blocks[1].hash, a1.hash);
+        assert!(!blockchain.contains_block(&b1.hash));
+        assert!(!blockchain.contains_block(&b2.hash));
+        assert_eq!(blockchain.get_user_funds(&miner1).len(), 2);
+        assert_eq!(blockchain.verify_chain(), Ok(()));
+    }
+
+    #[test]
+    fn test_double_spend_within_block() {
+        let (pubkey, privkey) = ecdsa::generate_keypair();
+        let mut blockchain = Blockchain::new(Transaction::get_coinbase(pubkey.clone(), MINING_REWARD));
+        let coinbase = blockchain.blocks[0].merkle_tree.transactions()[0].hash();
+
+        let spend = |recipient: ECDSAPublicKey| {
+            let mut tx = Transaction::new();
+            let lock = Script::p2pkh(&pubkey);
+            tx.add_input(TxInput { txid: coinbase.clone(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL });
+            tx.add_output(TxOutput { value: MINING_REWARD, script_pubkey: Script::p2pkh(&recipient), spent: false });
+            tx.inputs[0].script_sig = Script::p2pkh_unlock(&ecdsa::sign(tx.get_input_hash(0, &lock).bytes(), &privkey), &pubkey);
+            tx
+        };
+        let tx1 = spend(ecdsa::generate_keypair().0);
+        let tx2 = spend(ecdsa::generate_keypair().0);
+
+        let mut block = blockchain.create_block(Transaction::get_coinbase(pubkey.clone(), MINING_REWARD), vec![tx1, tx2]);
+        block.mine();
+        assert_eq!(
+            blockchain.add_block(block).err(),
+            Some(BlockError::InvalidTransactions(vec![TransactionError::InsufficientFunds]))
+        );
+        assert_eq!(blockchain.blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_malleated_signature() {
+        let (pubkey, privkey) = ecdsa::generate_keypair();
+        let mut blockchain = Blockchain::new(Transaction::get_coinbase(pubkey.clone(), MINING_REWARD));
+        let lock = Script::p2pkh(&pubkey);
+        let mut tx = Transaction::new();
+        tx.add_input(TxInput { txid: blockchain.blocks[0].merkle_tree.transactions()[0].hash(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL });
+        tx.add_output(TxOutput { value: MINING_REWARD, script_pubkey: Script::p2pkh(&ecdsa::generate_keypair().0), spent: false });
+        let signature = ecdsa::sign(tx.get_input_hash(0, &lock).bytes(), &privkey);
+
+        // Negating s keeps the signature valid but changes the txid
+        let mut malleated = tx.clone();
+        let high = AffinePoint::new(signature.x, (secp256k1::N.resize::<12>() - signature.y.resize()).resize());
+        malleated.inputs[0].script_sig = Script::p2pkh_unlock(&high, &pubkey);
+        tx.inputs[0].script_sig = Script::p2pkh_unlock(&signature, &pubkey);
+        assert_ne!(malleated.hash(), tx.hash());
+        assert_eq!(blockchain.verify_new_transaction(&tx), Ok(0));
+        assert_eq!(blockchain.verify_new_transaction(&malleated), Err(TransactionError::InvalidSignature));
+
+        // Blocks only need valid signatures
+        let mut block = blockchain.create_block(Transaction::get_coinbase(pubkey.clone(), MINING_REWARD), vec![malleated]);
+        block.mine();
+        blockchain.add_block(block).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_signature_in_block() {
+        let (pubkey, privkey) = ecdsa::generate_keypair();
+        let mut blockchain = Blockchain::new(Transaction::get_coinbase(pubkey.clone(), MINING_REWARD));
+        let mut tx = Transaction::new();
+        tx.add_input(TxInput { txid: blockchain.blocks[0].merkle_tree.transactions()[0].hash(), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL });
+        tx.add_output(TxOutput { value: MINING_REWARD, script_pubkey: Script::p2pkh(&ecdsa::generate_keypair().0), spent: false });
+        tx.inputs[0].script_sig = Script::p2pkh_unlock(&ecdsa::sign(b"something else", &privkey), &pubkey);
+
+        // The batch of the block fails, checking its signatures finds the input
+        let txid = tx.hash();
+        let mut block = blockchain.create_block(Transaction::get_coinbase(pubkey.clone(), MINING_REWARD), vec![tx]);
+        block.mine();
+        assert_eq!(
+            blockchain.add_block(block).err(),
+            Some(BlockError::InvalidTransactions(vec![TransactionError::InvalidInputSignature(txid, 0)]))
+        );
+        assert_eq!(blockchain.blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_mined_block_is_batched() {
+        let keys = ecdsa::generate_keypair();
+        let mut node = Node::new("Miner", Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD)), keys);
+        for _ in 0..15 {
+            node.mine().unwrap();
+        }
+        node.user.update_funds_from_chain(&node.get_funds_from_chain(&node.user.public_key));
+        let tx = node.user.try_transaction(&[(ecdsa::generate_keypair().0, 16 * MINING_REWARD)]).unwrap();
+        assert_eq!(tx.inputs.len(), 16);
+        node.add_transaction(tx).unwrap();
+        let mut block = node.block_template().unwrap();
+        block.mine();
+
+        // Every signature of the wallet goes into the multi scalar multiplication
+        let batch = RefCell::new(SignatureBatch::new());
+        assert_eq!(node.blockchain().verify_block_transactions(&block, &batch), Ok(0));
+        let batch = batch.into_inner();
+        assert_eq!(batch.len(), 16);
+        assert_eq!(batch.unbatched_len(), 0);
+        assert!(batch.verify());
+        assert!(matches!(node.accept_block(block), Ok(BlockStatus::Extended)));
+    }
+
+    #[test]
+    fn test_block_too_large() {
+        let (miner, _) = ecdsa::generate_keypair();
+        let mut blockchain = Blockchain::new(Transaction::get_coinbase(miner.clone(), MINING_REWARD));
+        let mut coinbase = Transaction::get_coinbase(miner, MINING_REWARD);
+        coinbase.outputs[0].script_pubkey = Script::from_bytes(vec![0; MAX_BLOCK_SIZE]);
+        let mut block = blockchain.create_block(coinbase, vec![]);
+        block.mine();
+        assert_eq!(blockchain.add_block(block).err(), Some(BlockError::TooLarge));
+        assert_eq!(blockchain.blocks.len(), 1);
+    }
+
+    #[test]
+    fn test_script_locked_output() {
+        let secret = b"preimage";
+        let mut coinbase = Transaction::get_coinbase(ecdsa::generate_keypair().0, MINING_REWARD);
+        coinbase.outputs[0].script_pubkey = Script::time_locked(1, &Script::hash_lock(&Sha256::hash(secret)));
+        let mut blockchain = Blockchain::new(coinbase);
+        let (miner, _) = ecdsa::generate_keypair();
+
+        let mut tx = Transaction::new();
+        let txid = blockchain.blocks[0].merkle_tree.transactions()[0].hash();
+        tx.add_input(TxInput { txid, vout: 0, script_sig: Script::new().push_data(secret), sequence: 0 });
+        tx.add_output(TxOutput { value: MINING_REWARD, script_pubkey: Script::p2pkh(&miner), spent: false });
+
+        // The script requires the transaction to commit to the lock time
+        assert_eq!(blockchain.verify_new_transaction(&tx), Err(TransactionError::InvalidScript(ScriptError::UnsatisfiedLockTime)));
+        tx.lock_time = 1;
+        assert_eq!(blockchain.verify_new_transaction(&tx), Err(TransactionError::LockTimeNotReached));
+
+        let mut block = blockchain.create_block(Transaction::get_coinbase(miner.clone(), MINING_REWARD), vec![tx.clone()]);
+        block.mine();
+        assert_eq!(blockchain.add_block(block).err(), Some(BlockError::InvalidTransactions(vec![TransactionError::LockTimeNotReached])));
+        let mut block = blockchain.create_block(Transaction::get_coinbase(miner.clone(), MINING_REWARD), vec![]);
+        block.mine();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.verify_new_transaction(&tx), Ok(0));
+
+        let mut guess = tx.clone();
+        guess.inputs[0].script_sig = Script::new().push_data(b"guess");
+        assert_eq!(blockchain.verify_new_transaction(&guess), Err(TransactionError::InvalidScript(ScriptError::EvalFalse)));
+    }
+
+    #[test]
+    fn test_relative_lock_time() {
+        // Outputs anyone can spend keep the test free of signatures
+        let (miner, _) = ecdsa::generate_keypair();
+        let mut coinbase = Transaction::get_coinbase(miner.clone(), MINING_REWARD);
+        coinbase.outputs[0].script_pubkey = Script::sequence_locked(2, &Script::new().push_number(1));
+        let mut blockchain = Blockchain::new(coinbase);
+
+        let mut tx = Transaction::new();
+        let txid = blockchain.blocks[0].merkle_tree.transactions()[0].hash();
+        tx.add_input(TxInput { txid, vout: 0, script_sig: Script::new(), sequence: 1 });
+        tx.add_output(TxOutput { value: MINING_REWARD, script_pubkey: Script::p2pkh(&miner), spent: false });
+        assert_eq!(blockchain.verify_new_transaction(&tx), Err(TransactionError::InvalidScript(ScriptError::UnsatisfiedLockTime)));
+
+        // The output was confirmed at height 0, so the input can be in block 2
+        tx.inputs[0].sequence = 2;
+        assert_eq!(blockchain.verify_new_transaction(&tx), Err(TransactionError::SequenceLockNotReached));
+        let mut block = blockchain.create_block(Transaction::get_coinbase(miner.clone(), MINING_REWARD), vec![]);
+        block.mine();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.verify_new_transaction(&tx), Ok(0));
+    }
+
+    #[test]
+    fn test_difficulty_retarget() {
+        let params = ConsensusParams { initial_difficulty: 16, retarget_interval: 3, ..Default::default() };
+        let (miner, _) = ecdsa::generate_keypair();
+        let mut blockchain = Blockchain::new_with_params(Transaction::get_coinbase(miner.clone(), MINING_REWARD), params);
+
+        for _ in 0..2 {
+            let mut block = blockchain.create_block(Transaction::get_coinbase(miner.clone(), MINING_REWARD), vec![]);
+            assert_eq!(block.header.difficulty, 16);
+            block.mine();
+            blockchain.add_block(block).unwrap();
+        }
+
+        // The first window was mined far faster than the target interval
+        let mut block = blockchain.create_block(Transaction::get_coinbase(miner.clone(), MINING_REWARD), vec![]);
+        assert_eq!(block.header.difficulty, 64);
+
+        let mut easy = block.clone();
+        easy.header.difficulty = 16;
+        easy.mine();
+        assert_eq!(blockchain.add_block(easy).err(), Some(BlockError::InvalidDifficulty));
+
+        block.mine();
+        assert!(matches!(blockchain.add_block(block), Ok(BlockStatus::Extended)));
+        assert_eq!(blockchain.total_work(), 16 * 3 + 64);
+        assert_eq!(blockchain.verify_chain(), Ok(()));
+
+        // Without retargeting the difficulty stays where it started
+        let params = ConsensusParams { initial_difficulty: 16, retarget_interval: 0, ..Default::default() };
+        let mut blockchain = Blockchain::new_with_params(Transaction::get_coinbase(miner.clone(), MINING_REWARD), params);
+        for _ in 0..3 {
+            let mut block = blockchain.create_block(Transaction::get_coinbase(miner.clone(), MINING_REWARD), vec![]);
+            assert_eq!(block.header.difficulty, 16);
+            block.mine();
+            blockchain.add_block(block).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_invalid_timestamp() {
+        let (miner, _) = ecdsa::generate_keypair();
+        let mut blockchain = Blockchain::new(Transaction::get_coinbase(miner.clone(), MINING_REWARD));
+
+        let mut block = blockchain.create_block(Transaction::get_coinbase(miner.clone(), MINING_REWARD), vec![]);
+        block.mine();
+        block.header.timestamp = util::timestamp() + 2 * MAX_FUTURE_BLOCK_TIME;
+        while !block.header.has_valid_work() {
+            block.header.nonce += 1;
+        }
+        block.hash = block.hash();
+        assert_eq!(blockchain.add_block(block).err(), Some(BlockError::InvalidTimestamp));
+    }
+
+    #[test]
+    fn test_subsidy_halving_in_blocks() {
+        let params = ConsensusParams { halving_interval: 2, ..Default::default() };
+        let (miner, _) = ecdsa::generate_keypair();
+        let mut blockchain = Blockchain::new_with_params(Transaction::get_coinbase(miner.clone(), MINING_REWARD), params);
+
+        let mut block = blockchain.create_block(Transaction::get_coinbase(miner.clone(), MINING_REWARD), vec![]);
+        block.mine();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.next_block_subsidy(), MINING_REWARD / 2);
+
+        let mut block = blockchain.create_block(Transaction::get_coinbase(miner.clone(), MINING_REWARD), vec![]);
+        block.mine();
+        assert_eq!(blockchain.add_block(block).err(), Some(BlockError::InvalidCoinbase));
+
+        let mut block = blockchain.create_block(Transaction::get_coinbase(miner.clone(), MINING_REWARD / 2), vec![]);
+        block.mine();
+        blockchain.add_block(block).unwrap();
+        assert_eq!(blockchain.total_supply(), 2 * MINING_REWARD + MINING_REWARD / 2);
+    }
+}
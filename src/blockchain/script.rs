@@ -0,0 +1,991 @@
+use std::cell::RefCell;
+use crate::{
+    ecdsa::{self, point::AffinePoint, ECDSAPublicKey, RecoverableSignature}, math::big_int::BigInt,
+    schnorr::{self, SchnorrPublicKey, SchnorrSignature}, sha256::Sha256
+};
+use super::{
+    encoding::{Decode, Encode},
+    transaction::{
+        Transaction, LOCKTIME_THRESHOLD, SEQUENCE_FINAL, SEQUENCE_LOCKTIME_DISABLE_FLAG,
+        SEQUENCE_LOCKTIME_MASK, SEQUENCE_LOCKTIME_TYPE_FLAG
+    }
+};
+
+// Opcodes 0x01 to 0x4b push the next n bytes
+pub const OP_0: u8 = 0x00;
+pub const OP_PUSHDATA1: u8 = 0x4c;
+pub const OP_PUSHDATA2: u8 = 0x4d;
+pub const OP_1: u8 = 0x51;
+pub const OP_16: u8 = 0x60;
+pub const OP_IF: u8 = 0x63;
+pub const OP_NOTIF: u8 = 0x64;
+pub const OP_ELSE: u8 = 0x67;
+pub const OP_ENDIF: u8 = 0x68;
+pub const OP_VERIFY: u8 = 0x69;
+pub const OP_RETURN: u8 = 0x6a;
+pub const OP_DROP: u8 = 0x75;
+pub const OP_DUP: u8 = 0x76;
+pub const OP_SWAP: u8 = 0x7c;
+pub const OP_EQUAL: u8 = 0x87;
+pub const OP_EQUALVERIFY: u8 = 0x88;
+pub const OP_SHA256: u8 = 0xa8;
+pub const OP_CHECKSIG: u8 = 0xac;
+pub const OP_CHECKSIGVERIFY: u8 = 0xad;
+pub const OP_CHECKMULTISIG: u8 = 0xae;
+pub const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+pub const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+pub const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+pub const MAX_ELEMENT_SIZE: usize = 520;
+pub const MAX_STACK_SIZE: usize = 1000;
+pub const MAX_OPS_PER_SCRIPT: usize = 201;
+pub const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptError {
+    ScriptTooLarge,
+    ElementTooLarge,
+    StackOverflow,
+    TooManyOps,
+    MalformedPush,
+    InvalidOpcode(u8),
+    OpReturn,
+    PushOnly,
+    StackUnderflow,
+    UnbalancedConditional,
+    InvalidNumber,
+    InvalidPubkeyCount,
+    DuplicatePubkey,
+    InvalidSignatureCount,
+    InvalidSignature,
+    EqualVerify,
+    VerifyFailed,
+    UnsatisfiedLockTime,
+    EvalFalse,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Instruction<'a> {
+    Push(&'a [u8]),
+    Op(u8),
+}
+
+// Locking and unlocking conditions are small programs for a stack machine.
+// The unlocking script of an input only pushes data, the locking script of the
+// spent output then runs on that stack and has to leave a true value on top.
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct Script {
+    bytes: Vec<u8>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Script { bytes: Vec::new() }
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Script { bytes }
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn push_opcode(mut self, opcode: u8) -> Self {
+        self.bytes.push(opcode);
+        self
+    }
+
+    // Uses the shortest push for the data
+    pub fn push_data(mut self, data: &[u8]) -> Self {
+        if data.is_empty() {
+            self.bytes.push(OP_0);
+        } else if data.len() < OP_PUSHDATA1 as usize {
+            self.bytes.push(data.len() as u8);
+        } else if data.len() <= u8::MAX as usize {
+            self.bytes.push(OP_PUSHDATA1);
+            self.bytes.push(data.len() as u8);
+        } else {
+            self.bytes.push(OP_PUSHDATA2);
+            self.bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        }
+        self.bytes.extend_from_slice(data);
+        self
+    }
+
+    pub fn push_number(self, number: u64) -> Self {
+        match number {
+            0 => self.push_opcode(OP_0),
+            1..=16 => self.push_opcode(OP_1 + number as u8 - 1),
+            _ => self.push_data(&encode_number(number))
+        }
+    }
+
+    pub fn append(mut self, other: &Script) -> Self {
+        self.bytes.extend_from_slice(&other.bytes);
+        self
+    }
+
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions { bytes: &self.bytes, pos: 0 }
+    }
+
+    pub fn is_push_only(&self) -> bool {
+        self.instructions().all(|instruction| match instruction {
+            Ok(Instruction::Push(_)) => true,
+            Ok(Instruction::Op(op)) => (OP_1..=OP_16).contains(&op),
+            Err(_) => false
+        })
+    }
+
+    // Pay to public key hash: the spender reveals the key and signs with it
+    pub fn p2pkh(pubkey: &ECDSAPublicKey) -> Self {
+        Script::p2pkh_hash(&pubkey_hash(pubkey))
+    }
+
+    // The same script when only the hash of the key is known, see address::Address
+    pub fn p2pkh_hash(pubkey_hash: &Sha256) -> Self {
+        Script::new()
+            .push_opcode(OP_DUP)
+            .push_opcode(OP_SHA256)
+            .push_data(pubkey_hash.bytes())
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_CHECKSIG)
+    }
+
+    pub fn p2pkh_unlock(signature: &AffinePoint, pubkey: &ECDSAPublicKey) -> Self {
+        Script::new()
+            .push_data(&encode_signature(signature))
+            .push_data(&pubkey.to_bytes())
+    }
+
+    // With the recovery id the signature can be checked in a batch, see
+    // ecdsa::verify_batch
+    pub fn p2pkh_unlock_recoverable(signature: &RecoverableSignature, pubkey: &ECDSAPublicKey) -> Self {
+        Script::new()
+            .push_data(&signature.to_bytes())
+            .push_data(&pubkey.to_bytes())
+    }
+
+    // Unlocks a P2PKH output without the key, which is recovered from the
+    // signature, see verify_script
+    pub fn p2pkh_recover_unlock(signature: &RecoverableSignature) -> Self {
+        Script::new().push_data(&signature.to_bytes())
+    }
+
+    // The key hash of a P2PKH script, None for any other script
+    pub fn p2pkh_pubkey_hash(&self) -> Option<Sha256> {
+        if self.bytes.len() != 37 {
+            return None;
+        }
+        let hash = Sha256::from_bytes(self.bytes[3..35].try_into().unwrap());
+        (Script::p2pkh_hash(&hash) == *self).then_some(hash)
+    }
+
+    // Spendable with signatures of required of the keys, given in key order
+    pub fn multisig(required: usize, pubkeys: &[ECDSAPublicKey]) -> Self {
+        assert!(required > 0 && required <= pubkeys.len(), "Invalid number of required signatures");
+        assert!(pubkeys.len() <= MAX_PUBKEYS_PER_MULTISIG, "Too many keys for a multisig script");
+        let mut script = Script::new().push_number(required as u64);
+        for pubkey in pubkeys {
+            script = script.push_data(&pubkey.to_bytes());
+        }
+        script.push_number(pubkeys.len() as u64).push_opcode(OP_CHECKMULTISIG)
+    }
+
+    pub fn multisig_unlock(signatures: &[AffinePoint]) -> Self {
+        signatures.iter().fold(Script::new(), |script, signature| script.push_data(&encode_signature(signature)))
+    }
+
+    // Pay to a BIP 340 public key, OP_CHECKSIG tells the schemes apart by
+    // the 32 byte x-only key
+    pub fn schnorr(pubkey: &SchnorrPublicKey) -> Self {
+        Script::new()
+            .push_data(&pubkey.to_bytes())
+            .push_opcode(OP_CHECKSIG)
+    }
+
+    pub fn schnorr_unlock(signature: &SchnorrSignature) -> Self {
+        Script::new().push_data(&signature.to_bytes())
+    }
+
+    // Spendable by anyone who knows a preimage of hash
+    pub fn hash_lock(hash: &Sha256) -> Self {
+        Script::new()
+            .push_opcode(OP_SHA256)
+            .push_data(hash.bytes())
+            .push_opcode(OP_EQUAL)
+    }
+
+    // Wraps a script so it can not be spent before lock_time, which is a
+    // block height or a timestamp, see LOCKTIME_THRESHOLD. The spending
+    // transaction has to set at least this lock time.
+    pub fn time_locked(lock_time: u64, script: &Script) -> Self {
+        Script::new()
+            .push_number(lock_time)
+            .push_opcode(OP_CHECKLOCKTIMEVERIFY)
+            .push_opcode(OP_DROP)
+            .append(script)
+    }
+
+    // Wraps a script so it can only be spent once the output is old enough,
+    // sequence is a relative lock time the spending input has to carry
+    pub fn sequence_locked(sequence: u32, script: &Script) -> Self {
+        Script::new()
+            .push_number(sequence as u64)
+            .push_opcode(OP_CHECKSEQUENCEVERIFY)
+            .push_opcode(OP_DROP)
+            .append(script)
+    }
+}
+
+impl std::fmt::Display for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = Vec::new();
+        for instruction in self.instructions() {
+            match instruction {
+                Ok(Instruction::Push([])) => parts.push(String::from("OP_0")),
+                Ok(Instruction::Push(data)) => parts.push(data.iter().map(|b| format!("{:02x}", b)).collect()),
+                Ok(Instruction::Op(op)) => parts.push(opcode_name(op)),
+                Err(_) => {
+                    parts.push(String::from("[error]"));
+                    break;
+                }
+            }
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl std::fmt::Debug for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Script({})", self)
+    }
+}
+
+fn opcode_name(op: u8) -> String {
+    let name = match op {
+        OP_IF => "OP_IF",
+        OP_NOTIF => "OP_NOTIF",
+        OP_ELSE => "OP_ELSE",
+        OP_ENDIF => "OP_ENDIF",
+        OP_VERIFY => "OP_VERIFY",
+        OP_RETURN => "OP_RETURN",
+        OP_DROP => "OP_DROP",
+        OP_DUP => "OP_DUP",
+        OP_SWAP => "OP_SWAP",
+        OP_EQUAL => "OP_EQUAL",
+        OP_EQUALVERIFY => "OP_EQUALVERIFY",
+        OP_SHA256 => "OP_SHA256",
+        OP_CHECKSIG => "OP_CHECKSIG",
+        OP_CHECKSIGVERIFY => "OP_CHECKSIGVERIFY",
+        OP_CHECKMULTISIG => "OP_CHECKMULTISIG",
+        OP_CHECKMULTISIGVERIFY => "OP_CHECKMULTISIGVERIFY",
+        OP_CHECKLOCKTIMEVERIFY => "OP_CHECKLOCKTIMEVERIFY",
+        OP_CHECKSEQUENCEVERIFY => "OP_CHECKSEQUENCEVERIFY",
+        OP_1..=OP_16 => return format!("OP_{}", op - OP_1 + 1),
+        _ => return format!("OP_UNKNOWN_{:02x}", op)
+    };
+    String::from(name)
+}
+
+pub struct Instructions<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction<'a>, ScriptError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let op = *self.bytes.get(self.pos)?;
+        self.pos += 1;
+        let len = match op {
+            OP_0 => 0,
+            0x01..=0x4b => op as usize,
+            OP_PUSHDATA1 | OP_PUSHDATA2 => {
+                let size = if op == OP_PUSHDATA1 { 1 } else { 2 };
+                let Some(len) = self.bytes.get(self.pos..self.pos + size) else {
+                    self.pos = self.bytes.len();
+                    return Some(Err(ScriptError::MalformedPush));
+                };
+                self.pos += size;
+                len.iter().fold(0, |len, byte| (len << 8) | *byte as usize)
+            }
+            _ => return Some(Ok(Instruction::Op(op)))
+        };
+        match self.bytes.get(self.pos..self.pos + len) {
+            Some(data) => {
+                self.pos += len;
+                Some(Ok(Instruction::Push(data)))
+            }
+            None => {
+                self.pos = self.bytes.len();
+                Some(Err(ScriptError::MalformedPush))
+            }
+        }
+    }
+}
+
+// What the interpreter needs to know about the spending transaction
+pub trait SignatureChecker {
+    fn check_signature(&self, signature: &[u8], pubkey: &[u8]) -> bool;
+    // For OP_CHECKSIG, which fails the script on any signature that is
+    // present but wrong. The checker may only record such a signature to
+    // check it later together with others, see SignatureBatch.
+    fn check_signature_deferred(&self, signature: &[u8], pubkey: &[u8]) -> bool {
+        self.check_signature(signature, pubkey)
+    }
+    // The key a recoverable signature of the input was made with, if the
+    // checker knows what the input signs
+    fn recover_pubkey(&self, _signature: &RecoverableSignature) -> Option<ECDSAPublicKey> {
+        None
+    }
+    fn check_lock_time(&self, lock_time: u64) -> bool;
+    fn check_sequence(&self, sequence: u64) -> bool;
+}
+
+// Checks one input of a transaction. Lock times are compared with the lock
+// times the transaction commits to, whether those are reached is checked when
+// the transaction is validated against the chain.
+//
+// Strict checking also rejects high-S signatures, which anyone can create
+// from a valid one and which would change the txid, see ecdsa::is_low_s.
+// With a batch, the signatures of OP_CHECKSIG are recorded in it instead of
+// being checked.
+pub struct TransactionChecker<'a> {
+    pub transaction: &'a Transaction,
+    pub input: usize,
+    pub script_code: &'a Script,
+    pub strict: bool,
+    pub batch: Option<&'a RefCell<SignatureBatch>>,
+}
+
+impl TransactionChecker<'_> {
+    // A 32 byte key is a Schnorr key, anything else an ECDSA key. An ECDSA
+    // signature is r | s, or recovery id | r | s, which can be batched. Schnorr
+    // signatures can not be malleated, so strict checking does not change them.
+    // None if the signature can not be valid whatever the hash is.
+    fn signature_entry(&self, signature: &[u8], pubkey: &[u8]) -> Option<BatchEntry> {
+        if pubkey.len() == 32 {
+            let signature = SchnorrSignature::from_bytes(signature)?;
+            let pubkey = SchnorrPublicKey::from_bytes(pubkey)?;
+            let hash = self.transaction.get_input_hash(self.input, self.script_code);
+            return Some(BatchEntry::Schnorr(hash, signature, pubkey));
+        }
+        let recoverable = RecoverableSignature::from_bytes(signature);
+        let signature = match recoverable {
+            Some(recoverable) => recoverable.signature,
+            None => decode_signature(signature)?,
+        };
+        let pubkey = ECDSAPublicKey::from_bytes(pubkey).ok()?;
+        if pubkey.key.is_infinity() || (self.strict && !ecdsa::is_low_s(&signature)) {
+            return None;
+        }
+        let hash = self.transaction.get_input_hash(self.input, self.script_code);
+        Some(match recoverable {
+            Some(recoverable) => BatchEntry::RecoverableEcdsa(hash, recoverable, pubkey),
+            None => BatchEntry::Ecdsa(hash, signature, pubkey),
+        })
+    }
+}
+
+impl SignatureChecker for TransactionChecker<'_> {
+    fn check_signature(&self, signature: &[u8], pubkey: &[u8]) -> bool {
+        self.signature_entry(signature, pubkey).is_some_and(|entry| entry.verify())
+    }
+
+    fn recover_pubkey(&self, signature: &RecoverableSignature) -> Option<ECDSAPublicKey> {
+        if self.strict && !ecdsa::is_low_s(&signature.signature) {
+            return None;
+        }
+        let hash = self.transaction.get_input_hash(self.input, self.script_code);
+        ecdsa::recover(hash.bytes(), signature)
+    }
+
+    fn check_signature_deferred(&self, signature: &[u8], pubkey: &[u8]) -> bool {
+        let Some(batch) = self.batch else {
+            return self.check_signature(signature, pubkey);
+        };
+        let Some(entry) = self.signature_entry(signature, pubkey) else {
+            return false;
+        };
+        batch.borrow_mut().entries.push((self.transaction.hash(), self.input, entry));
+        true
+    }
+
+    // Heights and timestamps can not be compared with each other, and a
+    // final input would make the transaction ignore its lock time
+    fn check_lock_time(&self, lock_time: u64) -> bool {
+        let tx_lock_time = self.transaction.lock_time;
+        if (lock_time < LOCKTIME_THRESHOLD) != (tx_lock_time < LOCKTIME_THRESHOLD) || lock_time > tx_lock_time {
+            return false;
+        }
+        self.transaction.inputs[self.input].sequence != SEQUENCE_FINAL
+    }
+
+    // A required sequence with the disable flag set always passes, otherwise
+    // the input needs a relative lock time of the same kind that is at least as long
+    fn check_sequence(&self, sequence: u64) -> bool {
+        let Ok(sequence) = u32::try_from(sequence) else {
+            return false;
+        };
+        if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return true;
+        }
+        let tx_sequence = self.transaction.inputs[self.input].sequence;
+        if tx_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return false;
+        }
+        (sequence & SEQUENCE_LOCKTIME_TYPE_FLAG) == (tx_sequence & SEQUENCE_LOCKTIME_TYPE_FLAG)
+            && (sequence & SEQUENCE_LOCKTIME_MASK) <= (tx_sequence & SEQUENCE_LOCKTIME_MASK)
+    }
+}
+
+// A signature with the hash it signs, ready to be checked
+enum BatchEntry {
+    Ecdsa(Sha256, AffinePoint, ECDSAPublicKey),
+    RecoverableEcdsa(Sha256, RecoverableSignature, ECDSAPublicKey),
+    Schnorr(Sha256, SchnorrSignature, SchnorrPublicKey),
+}
+
+impl BatchEntry {
+    fn verify(&self) -> bool {
+        match self {
+            BatchEntry::Ecdsa(hash, signature, pubkey) => ecdsa::verify(*signature, hash.bytes(), pubkey),
+            BatchEntry::RecoverableEcdsa(hash, signature, pubkey) => ecdsa::verify_recoverable(signature, hash.bytes(), pubkey),
+            BatchEntry::Schnorr(hash, signature, pubkey) => schnorr::verify(signature, hash.bytes(), pubkey),
+        }
+    }
+}
+
+// The signatures recorded while the scripts of many transactions run, with
+// the transaction and input each belongs to. Checking them together is much
+// faster than checking them one by one, see schnorr::verify_batch and
+// ecdsa::verify_batch, but only tells whether all of them are valid. ECDSA
+// signatures without a recovery id can not be batched and are checked one by
+// one.
+#[derive(Default)]
+pub struct SignatureBatch {
+    entries: Vec<(Sha256, usize, BatchEntry)>,
+}
+
+impl SignatureBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Signatures without a recovery id, which verify checks one by one
+    pub fn unbatched_len(&self) -> usize {
+        self.entries.iter().filter(|(_, _, entry)| matches!(entry, BatchEntry::Ecdsa(..))).count()
+    }
+
+    pub fn verify(&self) -> bool {
+        let mut ecdsa_items = Vec::new();
+        let mut schnorr_items = Vec::new();
+        for (_, _, entry) in &self.entries {
+            match entry {
+                BatchEntry::Ecdsa(..) => if !entry.verify() {
+                    return false;
+                },
+                BatchEntry::RecoverableEcdsa(hash, signature, pubkey) => ecdsa_items.push((&hash.bytes()[..], *signature, pubkey)),
+                BatchEntry::Schnorr(hash, signature, pubkey) => schnorr_items.push((&hash.bytes()[..], *signature, pubkey)),
+            }
+        }
+        schnorr::verify_batch(&schnorr_items) && ecdsa::verify_batch(&ecdsa_items)
+    }
+
+    // The txid and input index of every invalid signature, found by checking
+    // the signatures one by one after the batch failed
+    pub fn invalid_inputs(&self) -> Vec<(Sha256, usize)> {
+        self.entries.iter()
+            .filter(|(_, _, entry)| !entry.verify())
+            .map(|(txid, input, _)| (txid.clone(), *input))
+            .collect()
+    }
+}
+
+pub fn pubkey_hash(pubkey: &ECDSAPublicKey) -> Sha256 {
+    Sha256::hash(&pubkey.to_bytes())
+}
+
+// Signatures are r and s as 32 byte big endian numbers
+pub fn encode_signature(signature: &AffinePoint) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64);
+    for value in [&signature.x, &signature.y] {
+        let bytes = value.to_bytes_be();
+        out.extend(std::iter::repeat_n(0u8, 32 - bytes.len()));
+        out.extend_from_slice(&bytes);
+    }
+    out
+}
+
+pub fn decode_signature(bytes: &[u8]) -> Option<AffinePoint> {
+    if bytes.len() != 64 {
+        return None;
+    }
+    Some(AffinePoint::new(BigInt::from_bytes_be(&bytes[..32]), BigInt::from_bytes_be(&bytes[32..])))
+}
+
+// Numbers on the stack are unsigned big endian without leading zeros, zero
+// is the empty element
+fn encode_number(number: u64) -> Vec<u8> {
+    let bytes = number.to_be_bytes();
+    let start = bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len());
+    bytes[start..].to_vec()
+}
+
+fn decode_number(bytes: &[u8]) -> Result<u64, ScriptError> {
+    if bytes.len() > 8 || bytes.first() == Some(&0) {
+        return Err(ScriptError::InvalidNumber);
+    }
+    Ok(bytes.iter().fold(0, |number, byte| (number << 8) | *byte as u64))
+}
+
+fn cast_to_bool(bytes: &[u8]) -> bool {
+    bytes.iter().any(|b| *b != 0)
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { vec![] }
+}
+
+fn pop(stack: &mut Vec<Vec<u8>>) -> Result<Vec<u8>, ScriptError> {
+    stack.pop().ok_or(ScriptError::StackUnderflow)
+}
+
+// Runs the unlocking script and then the locking script on the resulting stack.
+//
+// A P2PKH output can also be unlocked with only a recoverable signature. A
+// key recovered from it always verifies it, so the output is unlocked if the
+// hash of that key matches and the locking script is not run. A signature by
+// any other key recovers a key with a different hash. The recovery costs as
+// much as checking the signature, so it is never added to a batch.
+pub fn verify_script(script_sig: &Script, script_pubkey: &Script, checker: &dyn SignatureChecker) -> Result<(), ScriptError> {
+    if !script_sig.is_push_only() {
+        return Err(ScriptError::PushOnly);
+    }
+    let mut stack = Vec::new();
+    eval_script(script_sig, &mut stack, checker)?;
+    if let (Some(hash), [element]) = (script_pubkey.p2pkh_pubkey_hash(), stack.as_slice()) {
+        if let Some(signature) = RecoverableSignature::from_bytes(element) {
+            let pubkey = checker.recover_pubkey(&signature).ok_or(ScriptError::InvalidSignature)?;
+            return if pubkey_hash(&pubkey) == hash { Ok(()) } else { Err(ScriptError::EqualVerify) };
+        }
+    }
+    eval_script(script_pubkey, &mut stack, checker)?;
+    match stack.last() {
+        Some(top) if cast_to_bool(top) => Ok(()),
+        _ => Err(ScriptError::EvalFalse)
+    }
+}
+
+pub fn eval_script(script: &Script, stack: &mut Vec<Vec<u8>>, checker: &dyn SignatureChecker) -> Result<(), ScriptError> {
+    if script.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptTooLarge);
+    }
+    // One entry per open IF, only instructions where all of them are true run
+    let mut conditions: Vec<bool> = Vec::new();
+    let mut ops = 0;
+
+    for instruction in script.instructions() {
+        let executing = conditions.iter().all(|c| *c);
+        match instruction? {
+            Instruction::Push(data) => {
+                if data.len() > MAX_ELEMENT_SIZE {
+                    return Err(ScriptError::ElementTooLarge);
+                }
+                if executing {
+                    stack.push(data.to_vec());
+                }
+            }
+            Instruction::Op(op) => {
+                if op > OP_16 {
+                    ops += 1;
+                    if ops > MAX_OPS_PER_SCRIPT {
+                        return Err(ScriptError::TooManyOps);
+                    }
+                }
+                match op {
+                    OP_IF | OP_NOTIF => {
+                        let mut value = false;
+                        if executing {
+                            value = cast_to_bool(&pop(stack)?) == (op == OP_IF);
+                        }
+                        conditions.push(value);
+                    }
+                    OP_ELSE => {
+                        let last = conditions.last_mut().ok_or(ScriptError::UnbalancedConditional)?;
+                        *last = !*last;
+                    }
+                    OP_ENDIF => {
+                        conditions.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                    }
+                    _ if !executing => {}
+                    _ => ops += execute_opcode(op, stack, checker)?
+                }
+                if ops > MAX_OPS_PER_SCRIPT {
+                    return Err(ScriptError::TooManyOps);
+                }
+            }
+        }
+        if stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackOverflow);
+        }
+    }
+
+    if !conditions.is_empty() {
+        return Err(ScriptError::UnbalancedConditional);
+    }
+    Ok(())
+}
+
+// Returns the number of extra operations the opcode counts as
+fn execute_opcode(op: u8, stack: &mut Vec<Vec<u8>>, checker: &dyn SignatureChecker) -> Result<usize, ScriptError> {
+    match op {
+        OP_1..=OP_16 => stack.push(encode_number((op - OP_1 + 1) as u64)),
+        OP_VERIFY => {
+            if !cast_to_bool(&pop(stack)?) {
+                return Err(ScriptError::VerifyFailed);
+            }
+        }
+        OP_RETURN => return Err(ScriptError::OpReturn),
+        OP_DROP => {
+            pop(stack)?;
+        }
+        OP_DUP => {
+            let top = stack.last().ok_or(ScriptError::StackUnderflow)?.clone();
+            stack.push(top);
+        }
+        OP_SWAP => {
+            let a = pop(stack)?;
+            let b = pop(stack)?;
+            stack.push(a);
+            stack.push(b);
+        }
+        OP_EQUAL | OP_EQUALVERIFY => {
+            let equal = pop(stack)? == pop(stack)?;
+            if op == OP_EQUALVERIFY {
+                if !equal {
+                    return Err(ScriptError::EqualVerify);
+                }
+            } else {
+                stack.push(encode_bool(equal));
+            }
+        }
+        OP_SHA256 => {
+            let data = pop(stack)?;
+            stack.push(Sha256::hash(&data).bytes().to_vec());
+        }
+        OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+            let pubkey = pop(stack)?;
+            let signature = pop(stack)?;
+            let valid = checker.check_signature_deferred(&signature, &pubkey);
+            // A signature that is present but wrong always fails the script,
+            // only an empty signature may be used to make CHECKSIG false
+            if !valid && !signature.is_empty() {
+                return Err(ScriptError::InvalidSignature);
+            }
+            if op == OP_CHECKSIGVERIFY {
+                if !valid {
+                    return Err(ScriptError::VerifyFailed);
+                }
+            } else {
+                stack.push(encode_bool(valid));
+            }
+        }
+        OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+            let key_count = decode_number(&pop(stack)?)? as usize;
+            if key_count > MAX_PUBKEYS_PER_MULTISIG {
+                return Err(ScriptError::InvalidPubkeyCount);
+            }
+            let mut pubkeys = Vec::with_capacity(key_count);
+            for _ in 0..key_count {
+                pubkeys.push(pop(stack)?);
+            }
+            pubkeys.reverse();
+            // Distinct keys make every signature come from a different signer
+            for (i, pubkey) in pubkeys.iter().enumerate() {
+                if pubkeys[..i].contains(pubkey) {
+                    return Err(ScriptError::DuplicatePubkey);
+                }
+            }
+            let required = decode_number(&pop(stack)?)? as usize;
+            if required > key_count {
+                return Err(ScriptError::InvalidSignatureCount);
+            }
+            let mut signatures = Vec::with_capacity(required);
+            for _ in 0..required {
+                signatures.push(pop(stack)?);
+            }
+            signatures.reverse();
+
+            // Signatures have to follow the order of the keys, so every key
+            // signs at most once
+            let mut keys = pubkeys.iter();
+            let valid = signatures.iter().all(|signature| {
+                keys.any(|pubkey| checker.check_signature(signature, pubkey))
+            });
+            if !valid && signatures.iter().any(|signature| !signature.is_empty()) {
+                return Err(ScriptError::InvalidSignature);
+            }
+            if op == OP_CHECKMULTISIGVERIFY {
+                if !valid {
+                    return Err(ScriptError::VerifyFailed);
+                }
+            } else {
+                stack.push(encode_bool(valid));
+            }
+            return Ok(key_count);
+        }
+        OP_CHECKLOCKTIMEVERIFY => {
+            let lock_time = decode_number(stack.last().ok_or(ScriptError::StackUnderflow)?)?;
+            if !checker.check_lock_time(lock_time) {
+                return Err(ScriptError::UnsatisfiedLockTime);
+            }
+        }
+        OP_CHECKSEQUENCEVERIFY => {
+            let sequence = decode_number(stack.last().ok_or(ScriptError::StackUnderflow)?)?;
+            if !checker.check_sequence(sequence) {
+                return Err(ScriptError::UnsatisfiedLockTime);
+            }
+        }
+        _ => return Err(ScriptError::InvalidOpcode(op))
+    }
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{blockchain::transaction::{TxInput, TxOutput}, ecdsa::secp256k1};
+    use super::*;
+
+    // Accepts the signatures in valid and lock times up to max_lock_time
+    struct TestChecker {
+        valid: Vec<Vec<u8>>,
+        max_lock_time: u64,
+    }
+
+    impl SignatureChecker for TestChecker {
+        fn check_signature(&self, signature: &[u8], _pubkey: &[u8]) -> bool {
+            self.valid.iter().any(|s| s == signature)
+        }
+
+        fn check_lock_time(&self, lock_time: u64) -> bool {
+            lock_time <= self.max_lock_time
+        }
+
+        fn check_sequence(&self, sequence: u64) -> bool {
+            sequence <= self.max_lock_time
+        }
+    }
+
+    fn checker() -> TestChecker {
+        TestChecker { valid: vec![vec![1; 64], vec![2; 64]], max_lock_time: 100 }
+    }
+
+    #[test]
+    fn test_push_encoding() {
+        let data = vec![7u8; 300];
+        let script = Script::new().push_data(&[]).push_data(&[1, 2]).push_data(&data[..100]).push_data(&data).push_number(5).push_number(1000);
+        let instructions: Vec<Instruction> = script.instructions().map(|i| i.unwrap()).collect();
+        assert_eq!(instructions, vec![
+            Instruction::Push(&[]),
+            Instruction::Push(&[1, 2]),
+            Instruction::Push(&data[..100]),
+            Instruction::Push(&data),
+            Instruction::Op(OP_1 + 4),
+            Instruction::Push(&[0x03, 0xe8]),
+        ]);
+        assert!(script.is_push_only());
+
+        let truncated = Script::from_bytes(vec![OP_PUSHDATA1, 10, 1, 2]);
+        assert_eq!(truncated.instructions().next(), Some(Err(ScriptError::MalformedPush)));
+    }
+
+    #[test]
+    fn test_conditionals_and_hash_lock() {
+        let secret = b"secret";
+        let lock = Script::hash_lock(&Sha256::hash(secret));
+        assert_eq!(verify_script(&Script::new().push_data(secret), &lock, &checker()), Ok(()));
+        assert_eq!(verify_script(&Script::new().push_data(b"wrong"), &lock, &checker()), Err(ScriptError::EvalFalse));
+
+        let branch = Script::new().push_opcode(OP_IF).push_number(2).push_opcode(OP_ELSE).push_number(3).push_opcode(OP_ENDIF).push_number(3).push_opcode(OP_EQUAL);
+        assert_eq!(verify_script(&Script::new().push_number(0), &branch, &checker()), Ok(()));
+        assert_eq!(verify_script(&Script::new().push_number(1), &branch, &checker()), Err(ScriptError::EvalFalse));
+
+        let unbalanced = Script::new().push_number(1).push_opcode(OP_IF);
+        assert_eq!(verify_script(&Script::new(), &unbalanced, &checker()), Err(ScriptError::UnbalancedConditional));
+        let unlock = Script::new().push_number(1).push_opcode(OP_DUP);
+        assert_eq!(verify_script(&unlock, &Script::new(), &checker()), Err(ScriptError::PushOnly));
+        let unspendable = Script::new().push_opcode(OP_RETURN);
+        assert_eq!(verify_script(&Script::new().push_number(1), &unspendable, &checker()), Err(ScriptError::OpReturn));
+    }
+
+    #[test]
+    fn test_multisig_and_time_lock() {
+        let lock = Script::new().push_number(2)
+            .push_data(&[0xa; 65]).push_data(&[0xb; 65]).push_data(&[0xc; 65])
+            .push_number(3).push_opcode(OP_CHECKMULTISIG);
+        let unlock = |signatures: &[Vec<u8>]| signatures.iter().fold(Script::new(), |s, sig| s.push_data(sig));
+
+        assert_eq!(verify_script(&unlock(&[vec![1; 64], vec![2; 64]]), &lock, &checker()), Ok(()));
+        assert_eq!(verify_script(&unlock(&[vec![1; 64]]), &lock, &checker()), Err(ScriptError::StackUnderflow));
+        assert_eq!(verify_script(&unlock(&[vec![1; 64], vec![3; 64]]), &lock, &checker()), Err(ScriptError::InvalidSignature));
+        assert_eq!(verify_script(&unlock(&[vec![], vec![]]), &lock, &checker()), Err(ScriptError::EvalFalse));
+
+        let duplicate = Script::new().push_number(2)
+            .push_data(&[0xa; 65]).push_data(&[0xa; 65])
+            .push_number(2).push_opcode(OP_CHECKMULTISIG);
+        assert_eq!(verify_script(&unlock(&[vec![1; 64], vec![2; 64]]), &duplicate, &checker()), Err(ScriptError::DuplicatePubkey));
+
+        let locked = Script::time_locked(100, &Script::new().push_number(1));
+        assert_eq!(verify_script(&Script::new(), &locked, &checker()), Ok(()));
+        let locked = Script::time_locked(101, &Script::new().push_number(1));
+        assert_eq!(verify_script(&Script::new(), &locked, &checker()), Err(ScriptError::UnsatisfiedLockTime));
+    }
+
+    #[test]
+    fn test_transaction_lock_times() {
+        let mut tx = Transaction::new();
+        tx.add_input(TxInput { txid: Sha256::hash(b"previous"), vout: 0, script_sig: Script::new(), sequence: 10 });
+        tx.lock_time = 100;
+        let script_code = Script::new();
+        let checker = TransactionChecker { transaction: &tx, input: 0, script_code: &script_code, strict: true, batch: None };
+        assert!(checker.check_lock_time(100));
+        assert!(!checker.check_lock_time(101));
+        assert!(!checker.check_lock_time(LOCKTIME_THRESHOLD));
+        assert!(checker.check_sequence(10));
+        assert!(!checker.check_sequence(11));
+        assert!(!checker.check_sequence((SEQUENCE_LOCKTIME_TYPE_FLAG | 1) as u64));
+        assert!(checker.check_sequence(SEQUENCE_LOCKTIME_DISABLE_FLAG as u64));
+
+        // A final input turns both lock times off
+        let mut final_tx = tx.clone();
+        final_tx.inputs[0].sequence = SEQUENCE_FINAL;
+        let checker = TransactionChecker { transaction: &final_tx, input: 0, script_code: &script_code, strict: true, batch: None };
+        assert!(!checker.check_lock_time(100));
+        assert!(!checker.check_sequence(10));
+    }
+
+    #[test]
+    fn test_p2pkh() {
+        let (pubkey, privkey) = ecdsa::generate_keypair();
+        let lock = Script::p2pkh(&pubkey);
+        let mut tx = Transaction::new();
+        tx.add_input(TxInput { txid: Sha256::hash(b"previous"), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL });
+        tx.add_output(TxOutput { value: 10, script_pubkey: lock.clone(), spent: false });
+        let signature = ecdsa::sign(tx.get_input_hash(0, &lock).bytes(), &privkey);
+        let checker = TransactionChecker { transaction: &tx, input: 0, script_code: &lock, strict: true, batch: None };
+
+        assert_eq!(verify_script(&Script::p2pkh_unlock(&signature, &pubkey), &lock, &checker), Ok(()));
+        let other = ecdsa::generate_keypair().0;
+        assert_eq!(verify_script(&Script::p2pkh_unlock(&signature, &other), &lock, &checker), Err(ScriptError::EqualVerify));
+        let mut changed = tx.clone();
+        changed.outputs[0].value = 11;
+        let changed_checker = TransactionChecker { transaction: &changed, ..checker };
+        assert_eq!(verify_script(&Script::p2pkh_unlock(&signature, &pubkey), &lock, &changed_checker), Err(ScriptError::InvalidSignature));
+
+        // The high-S twin of the signature only passes lenient checking
+        let high = AffinePoint::new(signature.x, (secp256k1::N.resize::<12>() - signature.y.resize()).resize());
+        assert_eq!(verify_script(&Script::p2pkh_unlock(&high, &pubkey), &lock, &checker), Err(ScriptError::InvalidSignature));
+        let lenient = TransactionChecker { strict: false, ..checker };
+        assert_eq!(verify_script(&Script::p2pkh_unlock(&high, &pubkey), &lock, &lenient), Ok(()));
+    }
+
+    #[test]
+    fn test_keyless_p2pkh() {
+        let (pubkey, privkey) = ecdsa::generate_keypair();
+        let lock = Script::p2pkh(&pubkey);
+        assert_eq!(lock.p2pkh_pubkey_hash(), Some(pubkey_hash(&pubkey)));
+        let mut tx = Transaction::new();
+        tx.add_input(TxInput { txid: Sha256::hash(b"previous"), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL });
+        tx.add_output(TxOutput { value: 10, script_pubkey: lock.clone(), spent: false });
+        let hash = tx.get_input_hash(0, &lock);
+        let checker = TransactionChecker { transaction: &tx, input: 0, script_code: &lock, strict: true, batch: None };
+
+        let signature = ecdsa::sign_recoverable(hash.bytes(), &privkey);
+        assert_eq!(Script::p2pkh_recover_unlock(&signature).len(), 66);
+        assert_eq!(verify_script(&Script::p2pkh_recover_unlock(&signature), &lock, &checker), Ok(()));
+        // Another key recovers to a different hash
+        let other = ecdsa::sign_recoverable(hash.bytes(), &ecdsa::generate_keypair().1);
+        assert_eq!(verify_script(&Script::p2pkh_recover_unlock(&other), &lock, &checker), Err(ScriptError::EqualVerify));
+        // So does a signature of anything but this input
+        let unrelated = ecdsa::sign_recoverable(b"unrelated", &privkey);
+        assert_eq!(verify_script(&Script::p2pkh_recover_unlock(&unrelated), &lock, &checker), Err(ScriptError::EqualVerify));
+        // Only P2PKH outputs recover the key
+        let schnorr_lock = Script::schnorr(&SchnorrPublicKey::from_private_key(&privkey));
+        assert_eq!(schnorr_lock.p2pkh_pubkey_hash(), None);
+        assert!(verify_script(&Script::p2pkh_recover_unlock(&signature), &schnorr_lock, &checker).is_err());
+    }
+
+    #[test]
+    fn test_schnorr_lock() {
+        let (_, privkey) = ecdsa::generate_keypair();
+        let pubkey = SchnorrPublicKey::from_private_key(&privkey);
+        let lock = Script::schnorr(&pubkey);
+        let mut tx = Transaction::new();
+        tx.add_input(TxInput { txid: Sha256::hash(b"previous"), vout: 0, script_sig: Script::new(), sequence: SEQUENCE_FINAL });
+        tx.add_output(TxOutput { value: 10, script_pubkey: lock.clone(), spent: false });
+        let hash = tx.get_input_hash(0, &lock);
+        let checker = TransactionChecker { transaction: &tx, input: 0, script_code: &lock, strict: true, batch: None };
+
+        let signature = schnorr::sign(hash.bytes(), &privkey);
+        assert_eq!(verify_script(&Script::schnorr_unlock(&signature), &lock, &checker), Ok(()));
+        // An ECDSA signature by the same key does not unlock it
+        let ecdsa_signature = ecdsa::sign(hash.bytes(), &privkey);
+        assert_eq!(verify_script(&Script::new().push_data(&encode_signature(&ecdsa_signature)), &lock, &checker), Err(ScriptError::InvalidSignature));
+        let other = schnorr::sign(hash.bytes(), &ecdsa::generate_keypair().1);
+        assert_eq!(verify_script(&Script::schnorr_unlock(&other), &lock, &checker), Err(ScriptError::InvalidSignature));
+        assert_eq!(verify_script(&Script::new().push_data(&[]), &lock, &checker), Err(ScriptError::EvalFalse));
+    }
+
+    #[test]
+    fn test_signature_batch() {
+        let (ecdsa_pubkey, privkey) = ecdsa::generate_keypair();
+        let schnorr_pubkey = SchnorrPublicKey::from_private_key(&privkey);
+        let locks = [Script::p2pkh(&ecdsa_pubkey), Script::schnorr(&schnorr_pubkey), Script::schnorr(&schnorr_pubkey), Script::p2pkh(&ecdsa_pubkey)];
+        let mut tx = Transaction::new();
+        for i in 0..locks.len() {
+            tx.add_input(TxInput { txid: Sha256::hash(b"previous"), vout: i as u32, script_sig: Script::new(), sequence: SEQUENCE_FINAL });
+        }
+        tx.add_output(TxOutput { value: 10, script_pubkey: Script::new(), spent: false });
+        let hash = |i: usize| tx.get_input_hash(i, &locks[i]);
+        let unlocks = [
+            Script::p2pkh_unlock(&ecdsa::sign(hash(0).bytes(), &privkey), &ecdsa_pubkey),
+            Script::schnorr_unlock(&schnorr::sign(hash(1).bytes(), &privkey)),
+            Script::schnorr_unlock(&schnorr::sign(hash(1).bytes(), &privkey)),
+            Script::p2pkh_unlock_recoverable(&ecdsa::sign_recoverable(hash(3).bytes(), &privkey), &ecdsa_pubkey),
+        ];
+
+        // Every script passes with the signatures only recorded
+        let batch = RefCell::new(SignatureBatch::new());
+        for (i, (unlock, lock)) in unlocks.iter().zip(&locks).enumerate() {
+            let checker = TransactionChecker { transaction: &tx, input: i, script_code: lock, strict: false, batch: Some(&batch) };
+            assert_eq!(verify_script(unlock, lock, &checker), Ok(()));
+        }
+        let batch = batch.into_inner();
+        assert_eq!(batch.len(), 4);
+        assert_eq!(batch.unbatched_len(), 1);
+        assert!(!batch.verify());
+        assert_eq!(batch.invalid_inputs(), vec![(tx.hash(), 2)]);
+        assert!(SignatureBatch::new().verify());
+    }
+}
@@ -0,0 +1,477 @@
+use std::{collections::HashMap, fs::{self, File, OpenOptions}, io::{Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}};
+use crate::sha256::Sha256;
+use super::{block::Block, encoding::{Decode, DecodeError, Encode}, BlockError};
+
+const BLOCKS_FILE: &str = "blocks.dat";
+const INDEX_FILE: &str = "index.dat";
+const CHAIN_FILE: &str = "chain.dat";
+
+// Every block record is framed as: magic | payload length | payload | checksum
+const RECORD_MAGIC: [u8; 4] = *b"BLK1";
+const RECORD_HEADER_LEN: u64 = 8;
+const CHECKSUM_LEN: u64 = 4;
+
+// Every index record is: block hash | offset | payload length | checksum
+const INDEX_RECORD_LEN: usize = 32 + 8 + 4 + 4;
+
+// The record at position h of the chain file is the hash of the active block
+// at height h: block hash | checksum over height and hash
+const CHAIN_RECORD_LEN: usize = 32 + 4;
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    Decode(DecodeError),
+    InvalidChain(BlockError),
+    MissingBlock(Sha256),
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl From<DecodeError> for StorageError {
+    fn from(e: DecodeError) -> Self {
+        StorageError::Decode(e)
+    }
+}
+
+// offset points at the start of the record, len is the length of the encoded block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockLocation {
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl BlockLocation {
+    fn record_end(&self) -> u64 {
+        self.offset + RECORD_HEADER_LEN + self.len as u64 + CHECKSUM_LEN
+    }
+}
+
+// Append-only block storage. Blocks are written to the block file first and
+// only then to the index, both synced to disk. If the process dies halfway the
+// incomplete tail is cut off on the next open and the index is rebuilt from
+// the block file, so a crash can at most lose the block being written.
+//
+// The chain file maps every height of the active chain to its block hash. It
+// is written after the blocks it refers to, so after a crash it can only lag
+// behind the block file.
+pub struct ChainStore {
+    blocks_file: File,
+    index_file: File,
+    chain_file: File,
+    locations: HashMap<Sha256, BlockLocation>,
+    order: Vec<Sha256>,
+    chain: Vec<Sha256>,
+    end: u64,
+}
+
+impl ChainStore {
+    pub fn open(dir: &Path) -> Result<ChainStore, StorageError> {
+        fs::create_dir_all(dir)?;
+        let open = |name: &str| -> std::io::Result<File> {
+            let path: PathBuf = dir.join(name);
+            OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)
+        };
+
+        let mut store = ChainStore {
+            blocks_file: open(BLOCKS_FILE)?,
+            index_file: open(INDEX_FILE)?,
+            chain_file: open(CHAIN_FILE)?,
+            locations: HashMap::new(),
+            order: Vec::new(),
+            chain: Vec::new(),
+            end: 0,
+        };
+        store.load_index()?;
+        store.recover_blocks()?;
+        store.load_chain()?;
+        Ok(store)
+    }
+
+    // Reads the chain file up to the first entry that is torn, corrupt or
+    // names a block that is not stored, and cuts the chain file off there
+    fn load_chain(&mut self) -> Result<(), StorageError> {
+        let mut bytes = Vec::new();
+        self.chain_file.seek(SeekFrom::Start(0))?;
+        self.chain_file.read_to_end(&mut bytes)?;
+        self.chain = parse_chain(&bytes);
+        self.chain.truncate(self.chain.iter().take_while(|hash| self.locations.contains_key(hash)).count());
+        self.chain_file.set_len((self.chain.len() * CHAIN_RECORD_LEN) as u64)?;
+        self.chain_file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    // Replaces the active chain with chain. Only the heights above the last
+    // block both chains share are rewritten.
+    pub fn set_chain(&mut self, chain: &[Sha256]) -> Result<(), StorageError> {
+        let mut shared = self.chain.len().min(chain.len());
+        while shared > 0 && self.chain[shared - 1] != chain[shared - 1] {
+            shared -= 1;
+        }
+        if shared == self.chain.len() && shared == chain.len() {
+            return Ok(());
+        }
+
+        let mut records = Vec::with_capacity((chain.len() - shared) * CHAIN_RECORD_LEN);
+        for (height, hash) in chain.iter().enumerate().skip(shared) {
+            records.extend_from_slice(&chain_record(height, hash));
+        }
+        self.chain_file.set_len((shared * CHAIN_RECORD_LEN) as u64)?;
+        self.chain_file.seek(SeekFrom::End(0))?;
+        self.chain_file.write_all(&records)?;
+        self.chain_file.sync_data()?;
+
+        self.chain.truncate(shared);
+        self.chain.extend_from_slice(&chain[shared..]);
+        Ok(())
+    }
+
+    // The hashes of the active chain by height
+    pub fn chain(&self) -> &[Sha256] {
+        &self.chain
+    }
+
+    // Reads the index up to the first entry that is torn, corrupt or does not
+    // line up with the block file, and cuts the index file off there
+    fn load_index(&mut self) -> Result<(), StorageError> {
+        let blocks_len = self.blocks_file.metadata()?.len();
+        let mut bytes = Vec::new();
+        self.index_file.seek(SeekFrom::Start(0))?;
+        self.index_file.read_to_end(&mut bytes)?;
+
+        let mut valid = 0;
+        for record in bytes.chunks_exact(INDEX_RECORD_LEN) {
+            let (body, checksum) = record.split_at(INDEX_RECORD_LEN - 4);
+            if Sha256::hash(body).bytes()[..4] != *checksum {
+                break;
+            }
+            let hash = Sha256::from_bytes(body[..32].try_into().unwrap());
+            let location = BlockLocation {
+                offset: u64::from_be_bytes(body[32..40].try_into().unwrap()),
+                len: u32::from_be_bytes(body[40..44].try_into().unwrap()),
+            };
+            if location.offset != self.end || location.record_end() > blocks_len {
+                break;
+            }
+            self.end = location.record_end();
+            self.locations.insert(hash.clone(), location);
+            self.order.push(hash);
+            valid += 1;
+        }
+
+        self.index_file.set_len((valid * INDEX_RECORD_LEN) as u64)?;
+        self.index_file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    // Indexes any complete blocks written after the last index entry and
+    // truncates the block file at the first incomplete or corrupt record
+    fn recover_blocks(&mut self) -> Result<(), StorageError> {
+        let blocks_len = self.blocks_file.metadata()?.len();
+        while self.end < blocks_len {
+            let (block, len) = match read_record(&mut self.blocks_file, self.end, blocks_len) {
+                Some(record) => record,
+                None => break
+            };
+            let location = BlockLocation { offset: self.end, len };
+            self.write_index(&block.hash, location)?;
+        }
+        self.blocks_file.set_len(self.end)?;
+        self.blocks_file.sync_data()?;
+        self.blocks_file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    fn write_index(&mut self, hash: &Sha256, location: BlockLocation) -> Result<(), StorageError> {
+        let mut record = Vec::with_capacity(INDEX_RECORD_LEN);
+        record.extend_from_slice(hash.bytes());
+        record.extend_from_slice(&location.offset.to_be_bytes());
+        record.extend_from_slice(&location.len.to_be_bytes());
+        let checksum = Sha256::hash(&record);
+        record.extend_from_slice(&checksum.bytes()[..4]);
+
+        self.index_file.write_all(&record)?;
+        self.index_file.sync_data()?;
+
+        self.end = location.record_end();
+        self.locations.insert(hash.clone(), location);
+        self.order.push(hash.clone());
+        Ok(())
+    }
+
+    pub fn append_block(&mut self, block: &Block) -> Result<(), StorageError> {
+        let payload = block.to_bytes();
+        let mut record = Vec::with_capacity(payload.len() + (RECORD_HEADER_LEN + CHECKSUM_LEN) as usize);
+        record.extend_from_slice(&RECORD_MAGIC);
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        record.extend_from_slice(&payload);
+        record.extend_from_slice(&Sha256::hash(&payload).bytes()[..4]);
+
+        self.blocks_file.seek(SeekFrom::Start(self.end))?;
+        self.blocks_file.write_all(&record)?;
+        self.blocks_file.sync_data()?;
+
+        let location = BlockLocation { offset: self.end, len: payload.len() as u32 };
+        self.write_index(&block.hash, location)
+    }
+
+    pub fn read_block(&mut self, hash: &Sha256) -> Result<Option<Block>, StorageError> {
+        let location = match self.locations.get(hash) {
+            Some(location) => *location,
+            None => return Ok(None)
+        };
+        let mut payload = vec![0u8; location.len as usize];
+        self.blocks_file.seek(SeekFrom::Start(location.offset + RECORD_HEADER_LEN))?;
+        self.blocks_file.read_exact(&mut payload)?;
+        Ok(Some(Block::from_bytes(&payload)?))
+    }
+
+    // Every stored block, including side branches, in the order they were
+    // written. A block is only stored once its parent is known, so parents
+    // always come before their children.
+    pub fn read_blocks(&mut self) -> Result<Vec<Block>, StorageError> {
+        let mut blocks = Vec::with_capacity(self.order.len());
+        for hash in self.order.clone() {
+            blocks.push(self.read_block(&hash)?.unwrap());
+        }
+        Ok(blocks)
+    }
+
+    // The complete blocks in the block file of dir, read without opening the
+    // store, so nothing is written or truncated. This is how the chain of a
+    // node that is still running can be read, a block it is writing at the
+    // same time is left out.
+    pub fn read_only_blocks(dir: &Path) -> Result<Vec<Block>, StorageError> {
+        let mut blocks_file = File::open(dir.join(BLOCKS_FILE))?;
+        let blocks_len = blocks_file.metadata()?.len();
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while let Some((block, len)) = read_record(&mut blocks_file, offset, blocks_len) {
+            offset = BlockLocation { offset, len }.record_end();
+            blocks.push(block);
+        }
+        Ok(blocks)
+    }
+
+    // The active chain in the chain file of dir, read without opening the
+    // store, see read_only_blocks
+    pub fn read_only_chain(dir: &Path) -> Result<Vec<Sha256>, StorageError> {
+        match fs::read(dir.join(CHAIN_FILE)) {
+            Ok(bytes) => Ok(parse_chain(&bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub fn location(&self, hash: &Sha256) -> Option<BlockLocation> {
+        self.locations.get(hash).copied()
+    }
+
+    pub fn hashes(&self) -> &Vec<Sha256> {
+        &self.order
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+fn chain_record(height: usize, hash: &Sha256) -> [u8; CHAIN_RECORD_LEN] {
+    let mut checked = (height as u64).to_be_bytes().to_vec();
+    checked.extend_from_slice(hash.bytes());
+    let mut record = [0u8; CHAIN_RECORD_LEN];
+    record[..32].copy_from_slice(hash.bytes());
+    record[32..].copy_from_slice(&Sha256::hash(&checked).bytes()[..4]);
+    record
+}
+
+// The hashes up to the first torn or corrupt record
+fn parse_chain(bytes: &[u8]) -> Vec<Sha256> {
+    bytes.chunks_exact(CHAIN_RECORD_LEN)
+        .enumerate()
+        .map(|(height, record)| (height, Sha256::from_bytes(record[..32].try_into().unwrap()), record))
+        .take_while(|(height, hash, record)| chain_record(*height, hash) == **record)
+        .map(|(_, hash, _)| hash)
+        .collect()
+}
+
+fn read_record(file: &mut File, offset: u64, blocks_len: u64) -> Option<(Block, u32)> {
+    if offset + RECORD_HEADER_LEN > blocks_len {
+        return None;
+    }
+    let mut header = [0u8; RECORD_HEADER_LEN as usize];
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    file.read_exact(&mut header).ok()?;
+    if header[..4] != RECORD_MAGIC {
+        return None;
+    }
+    let len = u32::from_be_bytes(header[4..].try_into().unwrap()) as u64;
+    if offset + RECORD_HEADER_LEN + len + CHECKSUM_LEN > blocks_len {
+        return None;
+    }
+    let mut payload = vec![0u8; (len + CHECKSUM_LEN) as usize];
+    file.read_exact(&mut payload).ok()?;
+    let (payload, checksum) = payload.split_at(len as usize);
+    if Sha256::hash(payload).bytes()[..4] != *checksum {
+        return None;
+    }
+    Some((Block::from_bytes(payload).ok()?, len as u32))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::{blockchain::{consensus::ConsensusParams, transaction::Transaction, Blockchain, MINING_REWARD}, ecdsa, math::random};
+    use super::*;
+
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("blockchain-{}-{:016x}", name, random::get_nrandom_u64(1)[0]));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn mined_blocks(n: usize) -> Vec<Block> {
+        let (pubkey, _) = ecdsa::generate_keypair();
+        let mut blocks: Vec<Block> = Vec::new();
+        for _ in 0..n {
+            let previous = blocks.last().map(|b| b.hash.clone()).unwrap_or_else(|| Sha256::hash(&[]));
+            let mut block = Block::new(previous, vec![Transaction::get_coinbase(pubkey.clone(), 50)]);
+            block.mine();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_append_and_reopen() {
+        let dir = temp_dir("store-reopen");
+        let blocks = mined_blocks(3);
+        {
+            let mut store = ChainStore::open(&dir).unwrap();
+            for block in &blocks {
+                store.append_block(block).unwrap();
+            }
+        }
+
+        let mut store = ChainStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 3);
+        for (i, block) in blocks.iter().enumerate() {
+            assert_eq!(store.hashes()[i], block.hash);
+            assert_eq!(store.read_block(&block.hash).unwrap().unwrap().hash, block.hash);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_from_torn_write() {
+        let dir = temp_dir("store-torn");
+        let blocks = mined_blocks(2);
+        {
+            let mut store = ChainStore::open(&dir).unwrap();
+            store.append_block(&blocks[0]).unwrap();
+            store.append_block(&blocks[1]).unwrap();
+        }
+
+        // Simulate a crash in the middle of writing the second block and
+        // before its index entry made it to disk
+        let first_end = ChainStore::open(&dir).unwrap().location(&blocks[0].hash).unwrap().record_end();
+        let blocks_file = OpenOptions::new().write(true).open(dir.join(BLOCKS_FILE)).unwrap();
+        blocks_file.set_len(first_end + 20).unwrap();
+        let index_file = OpenOptions::new().write(true).open(dir.join(INDEX_FILE)).unwrap();
+        index_file.set_len(INDEX_RECORD_LEN as u64 + 10).unwrap();
+
+        // Reading without opening the store leaves the torn record in place
+        assert_eq!(ChainStore::read_only_blocks(&dir).unwrap().len(), 1);
+        assert_eq!(fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len(), first_end + 20);
+
+        let mut store = ChainStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(fs::metadata(dir.join(BLOCKS_FILE)).unwrap().len(), first_end);
+
+        // The store is usable again after recovery
+        store.append_block(&blocks[1]).unwrap();
+        let mut store = ChainStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.read_block(&blocks[1].hash).unwrap().unwrap().hash, blocks[1].hash);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rebuild_missing_index() {
+        let dir = temp_dir("store-index");
+        let blocks = mined_blocks(3);
+        {
+            let mut store = ChainStore::open(&dir).unwrap();
+            for block in &blocks {
+                store.append_block(block).unwrap();
+            }
+        }
+        fs::remove_file(dir.join(INDEX_FILE)).unwrap();
+
+        let store = ChainStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.hashes()[2], blocks[2].hash);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_blockchain_survives_restart() {
+        let dir = temp_dir("chain-restart");
+        let (pubkey, _) = ecdsa::generate_keypair();
+        {
+            let mut blockchain = Blockchain::open(&dir).unwrap();
+            assert!(blockchain.blocks.is_empty());
+            for _ in 0..2 {
+                let mut block = blockchain.create_block(Transaction::get_coinbase(pubkey.clone(), MINING_REWARD), vec![]);
+                block.mine();
+                blockchain.add_block(block).unwrap();
+            }
+        }
+
+        let blockchain = Blockchain::open(&dir).unwrap();
+        assert_eq!(blockchain.blocks.len(), 2);
+        assert_eq!(blockchain.get_user_funds(&pubkey).len(), 2);
+        assert_eq!(blockchain.verify_chain(), Ok(()));
+        drop(blockchain);
+        assert_eq!(Blockchain::open_read_only(&dir).unwrap().get_user_funds(&pubkey).len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_chain_index_and_invalid_chain() {
+        let dir = temp_dir("chain-index");
+        let (pubkey, _) = ecdsa::generate_keypair();
+        let mut hashes = Vec::new();
+        {
+            let mut blockchain = Blockchain::open(&dir).unwrap();
+            for _ in 0..2 {
+                let mut block = blockchain.create_block(Transaction::get_coinbase(pubkey.clone(), MINING_REWARD), vec![]);
+                block.mine();
+                hashes.push(block.hash.clone());
+                blockchain.add_block(block).unwrap();
+            }
+        }
+        assert_eq!(ChainStore::open(&dir).unwrap().chain(), hashes.as_slice());
+        assert_eq!(ChainStore::read_only_chain(&dir).unwrap(), hashes);
+
+        // A chain file behind the block file is caught up on the next open
+        let chain_file = OpenOptions::new().write(true).open(dir.join(CHAIN_FILE)).unwrap();
+        chain_file.set_len(CHAIN_RECORD_LEN as u64 + 5).unwrap();
+        assert_eq!(Blockchain::open(&dir).unwrap().blocks.len(), 2);
+        assert_eq!(ChainStore::read_only_chain(&dir).unwrap(), hashes);
+
+        // Blocks that break the rules they are opened with are an error
+        // instead of a shorter chain
+        let params = ConsensusParams { initial_reward: MINING_REWARD / 2, ..Default::default() };
+        assert!(matches!(Blockchain::open_with_params(&dir, params.clone()), Err(StorageError::InvalidChain(BlockError::InvalidCoinbase))));
+        assert!(matches!(Blockchain::open_read_only_with_params(&dir, params), Err(StorageError::InvalidChain(BlockError::InvalidCoinbase))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
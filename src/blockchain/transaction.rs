@@ -1,21 +1,39 @@
-use crate::{ecdsa::{point::AffinePoint, ECDSAPublicKey}, math::random, sha256::Sha256};
+use crate::{ecdsa::ECDSAPublicKey, math::random, sha256::Sha256};
+use super::{encoding::{write_list, write_varint, Encode, TRANSACTION_VERSION}, script::Script};
+
+// Lock times below this are block heights, anything above is a unix timestamp
+pub const LOCKTIME_THRESHOLD: u64 = 500_000_000;
+
+// An input with this sequence has no relative lock time, and a transaction
+// where every input has it ignores its lock time
+pub const SEQUENCE_FINAL: u32 = 0xFFFF_FFFF;
+
+// Unless the disable flag is set, the low 16 bits of a sequence are a relative
+// lock time, counted in blocks or, with the type flag, in units of
+// 2^SEQUENCE_LOCKTIME_GRANULARITY seconds since the spent output was confirmed
+pub const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+pub const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+pub const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_FFFF;
+pub const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
 
 // txid is the hash of the transaction that created this input
 // vout is the index of the output in that transaction
-// script_sig is the signature and public key used to unlock this input
+// script_sig is the script that unlocks the referenced output
+// sequence holds the relative lock time of the input
 #[derive(Clone, Debug, PartialEq)]
 pub struct TxInput {
     pub txid: Sha256,
     pub vout: u32,
-    pub script_sig: (AffinePoint, ECDSAPublicKey),
+    pub script_sig: Script,
+    pub sequence: u32,
 }
 
 // value is the amount of coins being sent
-// script_pubkey is the public key that can unlock this output
+// script_pubkey is the script that has to be satisfied to spend this output
 #[derive(Clone, Debug, PartialEq)]
 pub struct TxOutput {
     pub value: u64,
-    pub script_pubkey: ECDSAPublicKey,
+    pub script_pubkey: Script,
     pub spent: bool
 }
 
@@ -24,6 +42,7 @@ pub struct Transaction {
     pub coinbase_padding: Option<[u64; 4]>, // Used to distinguish coinbase transactions in different blocks
     pub inputs: Vec<TxInput>,
     pub outputs: Vec<TxOutput>,
+    pub lock_time: u64, // Height or timestamp before which the transaction can not be mined, 0 for none
 }
 
 impl Transaction {
@@ -32,6 +51,7 @@ impl Transaction {
             coinbase_padding: None,
             inputs: Vec::new(),
             outputs: Vec::new(),
+            lock_time: 0,
         }
     }
 
@@ -39,7 +59,7 @@ impl Transaction {
         let mut tx = Transaction::new();
         tx.outputs.push(TxOutput {
             value,
-            script_pubkey: miner,
+            script_pubkey: Script::p2pkh(&miner),
             spent: false,
         });
 
@@ -59,54 +79,54 @@ impl Transaction {
         self.inputs.is_empty()
     }
 
-    pub fn serialize_for_input(&self, idx: usize, utxo_key: &ECDSAPublicKey) -> Vec<u8> {
-        let mut serialized = Vec::new();
-        
-        serialized.push(self.inputs.len() as u8);
+    // Whether the transaction may be in the block at height, where time is the
+    // median time past of the previous block
+    pub fn is_final(&self, height: u64, time: u64) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+        let reached = if self.lock_time < LOCKTIME_THRESHOLD {
+            self.lock_time < height
+        } else {
+            self.lock_time < time
+        };
+        reached || self.inputs.iter().all(|input| input.sequence == SEQUENCE_FINAL)
+    }
+
+    // Sum of all outputs, None if it does not fit in a u64
+    pub fn output_value(&self) -> Option<u64> {
+        self.outputs.iter().try_fold(0u64, |total, output| total.checked_add(output.value))
+    }
+
+    // Signature hash preimage: the transaction without any unlocking scripts,
+    // with the locking script of the referenced output placed at the input
+    // being signed
+    pub fn serialize_for_input(&self, idx: usize, script_code: &Script) -> Vec<u8> {
+        let mut serialized = vec![TRANSACTION_VERSION];
+
+        write_varint(&mut serialized, self.inputs.len() as u64);
         for (i, input) in self.inputs.iter().enumerate() {
-            serialized.extend_from_slice(input.txid.bytes());
+            input.txid.encode(&mut serialized);
             serialized.extend_from_slice(&input.vout.to_be_bytes());
             if i == idx {
-                serialized.extend_from_slice(&utxo_key.get_der_encoding());
+                script_code.encode(&mut serialized);
             }
+            serialized.extend_from_slice(&input.sequence.to_be_bytes());
         }
-        serialized.push(self.outputs.len() as u8);
-        for output in &self.outputs {
-            serialized.extend_from_slice(&output.value.to_be_bytes());
-            serialized.extend_from_slice(&output.script_pubkey.get_der_encoding());
-        }
+        write_list(&mut serialized, &self.outputs);
+        serialized.extend_from_slice(&self.lock_time.to_be_bytes());
         serialized
     }
 
-    pub fn get_input_hash(&self, idx: usize, utxo_key: &ECDSAPublicKey) -> Sha256 {
-        let serialized = self.serialize_for_input(idx, utxo_key);
+    pub fn get_input_hash(&self, idx: usize, script_code: &Script) -> Sha256 {
+        let serialized = self.serialize_for_input(idx, script_code);
         Sha256::hash(&serialized)
     }
 
+    // The txid is the hash of the canonical encoding. The coinbase padding is
+    // part of it, which distinguishes the same coinbase in different blocks
     pub fn hash(&self) -> Sha256 {
-        let mut serialized = Vec::new();
-        serialized.push(self.inputs.len() as u8);
-        for input in &self.inputs {
-            serialized.extend_from_slice(input.txid.bytes());
-            serialized.extend_from_slice(&input.vout.to_be_bytes());
-            serialized.extend_from_slice(&input.script_sig.0.get_bytes());
-            serialized.extend_from_slice(&input.script_sig.1.get_der_encoding());
-        }
-
-        // If this is a coinbase, we add random bytes to distinguish it 
-        // from the same transaction in different blocks
-        if self.is_coinbase() && self.coinbase_padding.is_some() {
-            for b in &self.coinbase_padding.unwrap() {
-                serialized.extend_from_slice(&b.to_be_bytes());
-            }
-        }
-
-        serialized.push(self.outputs.len() as u8);
-        for output in &self.outputs {
-            serialized.extend_from_slice(&output.value.to_be_bytes());
-            serialized.extend_from_slice(&output.script_pubkey.get_der_encoding());
-        }
-        Sha256::hash(&serialized)
+        Sha256::hash(&self.to_bytes())
     }
 }
 
@@ -115,8 +135,8 @@ impl std::fmt::Debug for Transaction {
         let mut res = String::from("Inputs: ");
         for input in &self.inputs {
             res.push_str(&format!(
-                "txid: {}, vout: {}, script_sig: (AffinePoint: {}, PubKey: {})",
-                input.txid, input.vout, input.script_sig.0, input.script_sig.1
+                "txid: {}, vout: {}, script_sig: {}, sequence: {}",
+                input.txid, input.vout, input.script_sig, input.sequence
             ));
         }
         for output in &self.outputs {
@@ -125,6 +145,6 @@ impl std::fmt::Debug for Transaction {
                 output.value, output.script_pubkey
             ));
         }
-        write!(f, "Transaction: {}", res)
+        write!(f, "Transaction: {}Lock time: {}", res, self.lock_time)
     }
 }
\ No newline at end of file
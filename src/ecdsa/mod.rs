@@ -1,7 +1,9 @@
 pub mod point;
+pub mod rfc6979;
 pub mod secp256k1;
 
-use point::AffinePoint;
+use point::{AffinePoint, JacobianPoint};
+use rfc6979::NonceGenerator;
 use secp256k1::BARRET_MU_N;
 use crate::{math::{big_int::{BigInt, BigIntMod}, algorithms}, sha256::Sha256, util};
 
@@ -34,6 +36,10 @@ impl ECDSAPrivateKey {
     pub fn get_der_encoding(&self) -> Vec<u8> {
         util::der_encode(&[&self.key])
     }
+
+    pub fn public_key(&self) -> ECDSAPublicKey {
+        ECDSAPublicKey { key: secp256k1::G.scalar_multiply(self.key).to_affine() }
+    }
 }
 
 impl ECDSAPublicKey {
@@ -43,7 +49,9 @@ impl ECDSAPublicKey {
         let mut bytes = der_encoding.as_slice();
         let fields = util::der_decode::<4>(&mut bytes);
         assert_eq!(fields.len(), 2, "Invalid DER encoding for ECDSA public key");
-        ECDSAPublicKey { key: AffinePoint::new(fields[0].clone(), fields[1].clone()) }
+        let key = AffinePoint::new(fields[0].clone(), fields[1].clone());
+        assert!(key.is_on_curve(), "ECDSA public key is not on the curve");
+        ECDSAPublicKey { key }
     }
 
     pub fn save(&self, file: &str) {
@@ -55,6 +63,18 @@ impl ECDSAPublicKey {
     pub fn get_der_encoding(&self) -> Vec<u8> {
         util::der_encode(&[&self.key.x, &self.key.y])
     }
+
+    // 33 byte SEC1 encoding, the y coordinate is reduced to its parity
+    pub fn get_compressed_bytes(&self) -> Vec<u8> {
+        self.key.get_compressed_bytes()
+    }
+
+    // Compressed or uncompressed SEC1 encoding of a point on the curve
+    pub fn from_sec1_bytes(bytes: &[u8]) -> Option<Self> {
+        AffinePoint::from_bytes(bytes)
+            .filter(|key| !key.is_infinity())
+            .map(|key| ECDSAPublicKey { key })
+    }
 }
 
 impl std::fmt::Display for ECDSAPublicKey {
@@ -74,37 +94,139 @@ pub fn generate_keypair() -> (ECDSAPublicKey, ECDSAPrivateKey) {
     while private_key >= secp256k1::N {
         private_key = BigInt::rand(4, 4);
     }
-    (
-        ECDSAPublicKey { key: secp256k1::G.scalar_multiply(private_key).to_affine() }, 
-        ECDSAPrivateKey { key: private_key }
-    )
+    let private_key = ECDSAPrivateKey { key: private_key };
+    (private_key.public_key(), private_key)
 }
 
-pub fn sign(message: &[u8], private_key: &ECDSAPrivateKey) -> AffinePoint {
-    let z: BigInt<4> = Sha256::hash(message).to_bigint().resize();
-    let z = BigIntMod::<12>::new_with_mu(z.resize(), secp256k1::N.resize(), BARRET_MU_N);
+// A signature together with the recovery id, which tells which of the up to
+// four public keys that verify (r, s) for a message signed it:
+//
+//     bit 0   parity of the y coordinate of R = k * G
+//     bit 1   set if the x coordinate of R was N or more, so r = x - N
+//
+// With it the public key does not have to be sent along, see recover.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RecoverableSignature {
+    pub signature: AffinePoint,
+    pub recovery_id: u8,
+}
 
-    loop {
-        let mut k = BigInt::rand(4, 4);
-        while k >= secp256k1::N {
-            k = BigInt::rand(4, 4);
-        }
-        let p = secp256k1::G.scalar_multiply(k).to_affine();
-        let x1 = p.x;
-        let r = BigIntMod::<12>::new_reduce(x1.resize(), secp256k1::N.resize(), BARRET_MU_N);
-        if r.integer == BigInt::from_num(0) {
-            continue;
+impl RecoverableSignature {
+    // recovery id | r | s
+    pub fn to_bytes(&self) -> [u8; 65] {
+        let mut bytes = [0; 65];
+        bytes[0] = self.recovery_id;
+        for (i, value) in [&self.signature.x, &self.signature.y].into_iter().enumerate() {
+            let value = value.to_bytes_be();
+            bytes[1 + 32 * (i + 1) - value.len()..1 + 32 * (i + 1)].copy_from_slice(&value);
         }
+        bytes
+    }
 
-        let k_inv = algorithms::mod_inverse(k.resize::<12>(), secp256k1::N.resize::<12>());
-        let k_inv = BigIntMod::<12>::new_with_mu(k_inv, secp256k1::N.resize(), BARRET_MU_N);
-        let da = BigIntMod::<12>::new_with_mu(private_key.key.resize(), secp256k1::N.resize(), BARRET_MU_N);
-        let s = k_inv * (z + r * da);
-        if s.integer == BigInt::from_num(0) {
-            continue;
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 65 || bytes[0] > 3 {
+            return None;
         }
-        return AffinePoint::new(r.integer.resize(), s.integer.resize());
+        let signature = AffinePoint::new(BigInt::from_bytes_be(&bytes[1..33]), BigInt::from_bytes_be(&bytes[33..]));
+        Some(RecoverableSignature { signature, recovery_id: bytes[0] })
+    }
+}
+
+// Deterministic, the nonce is derived from the key and the message hash, see
+// NonceGenerator. The signature is always low-S.
+pub fn sign(message: &[u8], private_key: &ECDSAPrivateKey) -> AffinePoint {
+    sign_recoverable(message, private_key).signature
+}
+
+pub fn sign_recoverable(message: &[u8], private_key: &ECDSAPrivateKey) -> RecoverableSignature {
+    let hash = Sha256::hash(message);
+    let z: BigInt<4> = hash.to_bigint().resize();
+    let z = BigIntMod::<12>::new_with_mu(z.resize(), secp256k1::N.resize(), BARRET_MU_N);
+    NonceGenerator::new(&private_key.key, hash.bytes())
+        .find_map(|k| sign_with_nonce(z, k, private_key))
+        .unwrap()
+}
+
+// None if the nonce gives r = 0 or s = 0, the next nonce has to be tried
+fn sign_with_nonce(z: BigIntMod<12>, k: BigInt<4>, private_key: &ECDSAPrivateKey) -> Option<RecoverableSignature> {
+    let p = secp256k1::G.scalar_multiply(k).to_affine();
+    let x1 = p.x;
+    let r = BigIntMod::<12>::new_reduce(x1.resize(), secp256k1::N.resize(), BARRET_MU_N);
+    if r.integer == BigInt::from_num(0) {
+        return None;
+    }
+
+    let k_inv = algorithms::mod_inverse(k.resize::<12>(), secp256k1::N.resize::<12>());
+    let k_inv = BigIntMod::<12>::new_with_mu(k_inv, secp256k1::N.resize(), BARRET_MU_N);
+    let da = BigIntMod::<12>::new_with_mu(private_key.key.resize(), secp256k1::N.resize(), BARRET_MU_N);
+    let s = k_inv * (z + r * da);
+    if s.integer == BigInt::from_num(0) {
+        return None;
+    }
+
+    // Negating s for a low-S signature is the same as signing with -k, whose
+    // R has the other y
+    let signature = AffinePoint::new(r.integer.resize(), s.integer.resize());
+    let mut recovery_id = p.y.is_odd() as u8 | ((x1 >= secp256k1::N) as u8) << 1;
+    if !is_low_s(&signature) {
+        recovery_id ^= 1;
+    }
+    Some(RecoverableSignature { signature: normalize_s(signature), recovery_id })
+}
+
+// The public key that signed the message, Q = r^-1 * (s * R - z * G) where R
+// is the point given by r and the recovery id. None if there is no such key.
+pub fn recover(message: &[u8], signature: &RecoverableSignature) -> Option<ECDSAPublicKey> {
+    let big_r = nonce_point(signature)?;
+    let r = signature.signature.x;
+    let s = signature.signature.y;
+
+    let z: BigInt<4> = Sha256::hash(message).to_bigint().resize();
+    let z = BigIntMod::<12>::new_reduce(z.resize(), secp256k1::N.resize(), BARRET_MU_N);
+    let r_inv = algorithms::mod_inverse(r.resize::<12>(), secp256k1::N.resize::<12>());
+    let r_inv = BigIntMod::<12>::new_with_mu(r_inv, secp256k1::N.resize(), BARRET_MU_N);
+    let s = BigIntMod::<12>::new_with_mu(s.resize(), secp256k1::N.resize(), BARRET_MU_N);
+    let u1 = BigIntMod::from_num(0, secp256k1::N.resize()) - z * r_inv;
+    let u2 = s * r_inv;
+
+    let q = point::shamir_multiply(u1.integer.resize(), &big_r, u2.integer.resize());
+    if q.is_infinity() {
+        return None;
+    }
+    Some(ECDSAPublicKey { key: q.to_affine() })
+}
+
+// R = k * G as told by r and the recovery id, None if r or s is out of range
+// or there is no such point
+fn nonce_point(signature: &RecoverableSignature) -> Option<AffinePoint> {
+    let r = signature.signature.x;
+    let s = signature.signature.y;
+    if r == BigInt::from_num(0) || s == BigInt::from_num(0) || r >= secp256k1::N || s >= secp256k1::N || signature.recovery_id > 3 {
+        return None;
+    }
+    let mut x = r.resize::<12>();
+    if signature.recovery_id & 2 != 0 {
+        x = x + secp256k1::N.resize();
+    }
+    if x >= secp256k1::P.resize() {
+        return None;
     }
+    AffinePoint::decompress(x.resize(), signature.recovery_id & 1 == 1)
+}
+
+// (r, s) and (r, N - s) are both valid for the same message and key, so
+// anyone could change a signature without the key. Only the one with s at
+// most N / 2 is accepted by verify_strict.
+pub fn is_low_s(signature: &AffinePoint) -> bool {
+    signature.y <= secp256k1::HALF_N
+}
+
+pub fn normalize_s(signature: AffinePoint) -> AffinePoint {
+    if is_low_s(&signature) {
+        return signature;
+    }
+    let s = (secp256k1::N.resize::<12>() - signature.y.resize()).resize();
+    AffinePoint::new(signature.x, s)
 }
 
 pub fn verify(signature: AffinePoint, message: &[u8], public_key: &ECDSAPublicKey) -> bool {
@@ -122,13 +244,76 @@ pub fn verify(signature: AffinePoint, message: &[u8], public_key: &ECDSAPublicKe
     let u1 = z * s_inv;
     let u2 = BigIntMod::new_with_mu(r.resize(), secp256k1::N.resize(), BARRET_MU_N) * s_inv;
 
-    let p1 = secp256k1::G.scalar_multiply(u1.integer.resize());
-    let p2 = public_key.key.scalar_multiply(u2.integer.resize());
-    let p = (p1 + p2).to_affine();
+    let p = point::shamir_multiply(u1.integer.resize(), &public_key.key, u2.integer.resize()).to_affine();
     let x1 = BigIntMod::<12>::new_reduce(p.x.resize(), secp256k1::N.resize(), BARRET_MU_N);
     x1.integer == r.resize()
 }
 
+// Like verify, but high-S signatures are rejected, see is_low_s
+pub fn verify_strict(signature: AffinePoint, message: &[u8], public_key: &ECDSAPublicKey) -> bool {
+    is_low_s(&signature) && verify(signature, message, public_key)
+}
+
+// Like verify, but the recovery id has to be right as well
+pub fn verify_recoverable(signature: &RecoverableSignature, message: &[u8], public_key: &ECDSAPublicKey) -> bool {
+    recover(message, signature).is_some_and(|key| key.key == public_key.key)
+}
+
+// Checks many recoverable signatures at once. r alone only gives the x
+// coordinate of R, with the recovery id each signature is the equation
+//
+//     u1 * G + u2 * Q - R = infinity, u1 = z / s, u2 = r / s
+//
+// and with random weights a_i, with a_0 = 1,
+//
+//     (sum a_i * u1_i) * G + sum (a_i * u2_i) * Q_i - sum a_i * R_i = infinity
+//
+// is one multi scalar multiplication, the same as schnorr::verify_batch. The
+// weights come from a hash of the whole batch. True if verify_recoverable is
+// true for every signature, false if any is invalid or has the wrong
+// recovery id.
+pub fn verify_batch(items: &[(&[u8], RecoverableSignature, &ECDSAPublicKey)]) -> bool {
+    let mut seed = Vec::new();
+    for (message, signature, public_key) in items {
+        seed.extend_from_slice(&public_key.key.get_compressed_bytes());
+        seed.extend_from_slice(&signature.to_bytes());
+        seed.extend_from_slice(&(message.len() as u64).to_be_bytes());
+        seed.extend_from_slice(message);
+    }
+    let seed = Sha256::hash(&seed);
+
+    let mut g_scalar = scalar(BigInt::from_num(0));
+    let mut terms = Vec::with_capacity(2 * items.len() + 1);
+    for (i, (message, signature, public_key)) in items.iter().enumerate() {
+        let Some(big_r) = nonce_point(signature) else {
+            return false;
+        };
+        if public_key.key.is_infinity() {
+            return false;
+        }
+        let z: BigInt<4> = Sha256::hash(message).to_bigint().resize();
+        let s_inv = algorithms::mod_inverse(signature.signature.y.resize::<12>(), secp256k1::N.resize::<12>());
+        let s_inv = BigIntMod::<12>::new_with_mu(s_inv, secp256k1::N.resize(), BARRET_MU_N);
+        let a = if i == 0 { scalar(BigInt::from_num(1)) } else { batch_weight(&seed, i) };
+        g_scalar = g_scalar + a * scalar(z) * s_inv;
+        terms.push((public_key.key, (a * scalar(signature.signature.x) * s_inv).integer.resize()));
+        terms.push((big_r.negate(), a.integer.resize()));
+    }
+    terms.push((secp256k1::G, g_scalar.integer.resize()));
+    JacobianPoint::multi_scalar_multiply(&terms).is_infinity()
+}
+
+fn scalar(value: BigInt<4>) -> BigIntMod<12> {
+    BigIntMod::<12>::new_reduce(value.resize(), secp256k1::N.resize(), BARRET_MU_N)
+}
+
+// 128 bits are enough for the chance of a forgery passing to be negligible
+fn batch_weight(seed: &Sha256, i: usize) -> BigIntMod<12> {
+    let mut data = seed.bytes().to_vec();
+    data.extend_from_slice(&(i as u64).to_be_bytes());
+    scalar(BigInt::from_bytes_be(&Sha256::hash(&data).bytes()[..16]))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -173,4 +358,142 @@ mod tests {
         let (other_pubkey, _) = generate_keypair();
         assert!(!verify(signature, message, &other_pubkey));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_rfc6979_vectors() {
+        let n_minus_1 = (secp256k1::N.resize::<12>() - BigInt::from_num(1)).resize();
+        let alan = BigInt::from_hex_string("f8b8af8ce3c7cca5e300d33939540c10d45ce001b8f252bfbc57ba0342904181");
+        let vectors: [(BigInt<4>, &str, &str, &str, &str); 4] = [
+            (BigInt::from_num(1), "Satoshi Nakamoto",
+                "8f8a276c19f4149656b280621e358cce24f5f52542772691ee69063b74f15d15",
+                "934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d8",
+                "2442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e5"),
+            (n_minus_1, "Satoshi Nakamoto",
+                "33a19b60e25fb6f4435af53a3d42d493644827367e6453928554f43e49aa6f90",
+                "fd567d121db66e382991534ada77a6bd3106f0a1098c231e47993447cd6af2d0",
+                "6b39cd0eb1bc8603e159ef5c20a5c8ad685a45b06ce9bebed3f153d10d93bed5"),
+            (alan, "Alan Turing",
+                "525a82b70e67874398067543fd84c83d30c175fdc45fdeee082fe13b1d7cfdf1",
+                "7063ae83e7f62bbb171798131b4a0564b956930092b33b07b395615d9ec7e15c",
+                "58dfcc1e00a35e1572f366ffe34ba0fc47db1e7189759b9fb233c5b05ab388ea"),
+            (BigInt::from_num(1), "Everything should be made as simple as possible, but not simpler.",
+                "ec633bd56a5774a0940cb97e27a9e4e51dc94af737596a0c5cbb3d30332d92a5",
+                "33a69cd2065432a30f3d1ce4eb0d59b8ab58c74f27c41a7fdb5696ad4e6108c9",
+                "6f807982866f785d3f6418d24163ddae117b7db4d5fdf0071de069fa54342262"),
+        ];
+        for (key, message, k, r, s) in vectors {
+            let private_key = ECDSAPrivateKey { key };
+            let mut nonces = NonceGenerator::new(&key, Sha256::hash(message.as_bytes()).bytes());
+            assert_eq!(nonces.next(), Some(BigInt::from_hex_string(k)));
+
+            let signature = sign(message.as_bytes(), &private_key);
+            assert_eq!(signature, AffinePoint::new(BigInt::from_hex_string(r), BigInt::from_hex_string(s)));
+            assert_eq!(sign(message.as_bytes(), &private_key), signature);
+            assert!(verify_strict(signature, message.as_bytes(), &private_key.public_key()));
+        }
+    }
+
+    #[test]
+    fn test_recover_public_key() {
+        for i in 0..4u8 {
+            let (pubkey, privkey) = generate_keypair();
+            let message = [b'm', i];
+            let signature = sign_recoverable(&message, &privkey);
+            assert_eq!(signature.signature, sign(&message, &privkey));
+            assert_eq!(recover(&message, &signature), Some(pubkey.clone()));
+            assert_eq!(RecoverableSignature::from_bytes(&signature.to_bytes()), Some(signature));
+
+            // Another message or recovery id gives some other key
+            assert_ne!(recover(b"other", &signature), Some(pubkey.clone()));
+            let flipped = RecoverableSignature { recovery_id: signature.recovery_id ^ 1, ..signature };
+            assert_ne!(recover(&message, &flipped), Some(pubkey.clone()));
+        }
+
+        // The published vector for key 1 recovers G
+        let signature = sign_recoverable(b"Satoshi Nakamoto", &ECDSAPrivateKey { key: BigInt::from_num(1) });
+        assert_eq!(recover(b"Satoshi Nakamoto", &signature).map(|pubkey| pubkey.key), Some(secp256k1::G));
+
+        let signature = RecoverableSignature { signature: AffinePoint::new(BigInt::from_num(0), BigInt::from_num(1)), recovery_id: 0 };
+        assert_eq!(recover(b"message", &signature), None);
+        assert_eq!(RecoverableSignature::from_bytes(&[4; 65]), None);
+        assert_eq!(RecoverableSignature::from_bytes(&[0; 64]), None);
+    }
+
+    #[test]
+    fn test_high_s_malleability() {
+        let (pubkey, privkey) = generate_keypair();
+        let message = b"Byte array to sign";
+        let signature = sign(message, &privkey);
+        assert!(is_low_s(&signature));
+
+        // The negated s verifies too, but only in the lenient mode
+        let high = AffinePoint::new(signature.x, (secp256k1::N.resize::<12>() - signature.y.resize()).resize());
+        assert!(!is_low_s(&high));
+        assert!(verify(high, message, &pubkey));
+        assert!(!verify_strict(high, message, &pubkey));
+        assert!(verify_strict(signature, message, &pubkey));
+        assert_eq!(normalize_s(high), signature);
+
+        assert!(is_low_s(&AffinePoint::new(signature.x, secp256k1::HALF_N)));
+        assert!(!is_low_s(&AffinePoint::new(signature.x, (secp256k1::HALF_N.resize::<12>() + BigInt::from_num(1)).resize())));
+    }
+
+    #[test]
+    fn test_public_key_compression() {
+        let g = ECDSAPublicKey { key: secp256k1::G };
+        assert_eq!(util::hex_encode(&g.get_compressed_bytes()), "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798");
+        assert_eq!(ECDSAPublicKey::from_sec1_bytes(&g.get_compressed_bytes()), Some(g.clone()));
+
+        for _ in 0..4 {
+            let (pubkey, _) = generate_keypair();
+            let compressed = pubkey.get_compressed_bytes();
+            assert_eq!(compressed.len(), 33);
+            assert_eq!(compressed[0], if pubkey.key.y.is_odd() { 0x03 } else { 0x02 });
+            assert_eq!(ECDSAPublicKey::from_sec1_bytes(&compressed), Some(pubkey.clone()));
+            assert_eq!(ECDSAPublicKey::from_sec1_bytes(&pubkey.key.get_bytes()), Some(pubkey.clone()));
+
+            // The other parity is the negated point
+            let mut negated = compressed.clone();
+            negated[0] ^= 1;
+            let negated = ECDSAPublicKey::from_sec1_bytes(&negated).unwrap();
+            assert_eq!(negated.key.x, pubkey.key.x);
+            assert_eq!((negated.key.y.resize::<12>() + pubkey.key.y.resize()).resize::<4>(), secp256k1::P);
+        }
+
+        // Points off the curve are rejected in both encodings
+        let mut moved = secp256k1::G.get_bytes();
+        moved[64] ^= 1;
+        assert_eq!(AffinePoint::from_bytes(&moved), None);
+        let x = (1..=255u8).find(|x| secp256k1::sqrt_mod_p(secp256k1::curve_y_squared(BigInt::from_num(*x as u128))).is_none()).unwrap();
+        let mut no_point = [0; 33];
+        no_point[0] = 0x02;
+        no_point[32] = x;
+        assert_eq!(AffinePoint::from_bytes(&no_point), None);
+        assert_eq!(ECDSAPublicKey::from_sec1_bytes(&[0x00]), None);
+        assert_eq!(ECDSAPublicKey::from_sec1_bytes(&g.get_compressed_bytes()[..32]), None);
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        let keys: Vec<(ECDSAPublicKey, ECDSAPrivateKey)> = (0..4).map(|_| generate_keypair()).collect();
+        let messages: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 10 * i as usize]).collect();
+        let mut items: Vec<(&[u8], RecoverableSignature, &ECDSAPublicKey)> = (0..4)
+            .map(|i| (messages[i].as_slice(), sign_recoverable(&messages[i], &keys[i].1), &keys[i].0))
+            .collect();
+        assert!(verify_batch(&items));
+        assert!(verify_batch(&[]));
+
+        // One signature for the wrong message fails the whole batch
+        items[2].0 = &messages[1];
+        assert!(!verify_batch(&items));
+        let invalid: Vec<usize> = (0..4).filter(|&i| !verify_recoverable(&items[i].1, items[i].0, items[i].2)).collect();
+        assert_eq!(invalid, vec![2]);
+
+        // A wrong recovery id fails it too, although verify accepts (r, s)
+        items[2].0 = &messages[2];
+        items[3].1.recovery_id ^= 1;
+        assert!(verify(items[3].1.signature, items[3].0, items[3].2));
+        assert!(!verify_recoverable(&items[3].1, items[3].0, items[3].2));
+        assert!(!verify_batch(&items));
+    }
+}
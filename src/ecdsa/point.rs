@@ -1,7 +1,27 @@
+use std::sync::OnceLock;
 use crate::math::{algorithms::mod_inverse, big_int::{BigInt, BigIntMod}};
 use core::ops::Add;
 use crate::ecdsa::secp256k1::*;
 
+pub const SEC1_INFINITY: u8 = 0x00;
+pub const SEC1_EVEN: u8 = 0x02;
+pub const SEC1_ODD: u8 = 0x03;
+pub const SEC1_UNCOMPRESSED: u8 = 0x04;
+
+// Window of the wNAF digits of a variable point, its table of odd multiples
+// has 2^(w-2) points and is built for every multiplication
+const WNAF_WINDOW: u32 = 5;
+// G gets a wider window, its table is only built once
+const G_WNAF_WINDOW: u32 = 8;
+// The comb table of G holds d * 2^(COMB_BITS * j) * G for every digit d and
+// window j of a 256 bit scalar
+const COMB_BITS: usize = 4;
+const COMB_WINDOWS: usize = 256 / COMB_BITS;
+const COMB_ENTRIES: usize = (1 << COMB_BITS) - 1;
+
+static GENERATOR_COMB: OnceLock<Vec<AffinePoint>> = OnceLock::new();
+static GENERATOR_WNAF: OnceLock<Vec<AffinePoint>> = OnceLock::new();
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct AffinePoint {
     pub x: BigInt<4>,
@@ -31,14 +51,25 @@ impl AffinePoint {
         self.infinity
     }
 
+    // G is multiplied with its precomputed comb table, any other point with
+    // the wNAF digits of the scalar, see JacobianPoint::multi_scalar_multiply
     pub fn scalar_multiply(&self, scalar: BigInt<4>) -> JacobianPoint {
+        if *self == G {
+            return generator_multiply(scalar);
+        }
+        JacobianPoint::multi_scalar_multiply(&[(*self, scalar)])
+    }
+
+    // Plain double-and-add over the bits of the scalar, kept as the reference
+    // the faster multiplications are tested and benchmarked against
+    pub fn scalar_multiply_double_and_add(&self, scalar: BigInt<4>) -> JacobianPoint {
         if self.is_infinity() || scalar == BigInt::from_num(0) {
             return JacobianPoint::from_affine(&AffinePoint::infinity());
         }
 
         let mut result = JacobianPoint::from_affine(self);
         let bits = scalar.to_bits();
-        let mut i = (scalar.log2() - 2) as i32;
+        let mut i = scalar.log2() as i32 - 2;
 
         while i >= 0 {
             result = result.double();
@@ -50,18 +81,218 @@ impl AffinePoint {
         result
     }
 
+    pub fn negate(&self) -> Self {
+        if self.is_infinity() || self.y == BigInt::from_num(0) {
+            return *self;
+        }
+        AffinePoint::new(self.x, (P.resize::<12>() - self.y.resize()).resize())
+    }
+
+    // y^2 = x^3 + 7 with both coordinates reduced mod P
+    pub fn is_on_curve(&self) -> bool {
+        !self.is_infinity() && self.x < P && self.y < P
+            && BigIntMod::<12>::new_with_mu(self.y.resize(), P.resize(), BARRET_MU_P).square().integer == curve_y_squared(self.x).resize()
+    }
+
+    // The point with this x whose y has the given parity, None if x is not
+    // the x of any point
+    pub fn decompress(x: BigInt<4>, odd: bool) -> Option<Self> {
+        if x >= P {
+            return None;
+        }
+        let y = sqrt_mod_p(curve_y_squared(x))?;
+        let y = if y.is_odd() == odd { y } else { (P.resize::<12>() - y.resize()).resize() };
+        Some(AffinePoint::new(x, y))
+    }
+
+    // SEC1 encoding: 0x04 | x | y uncompressed, 0x00 for infinity
     pub fn get_bytes(&self) -> Vec<u8> {
         if self.is_infinity() {
-            return vec![0x00];
+            return vec![SEC1_INFINITY];
+        }
+        let mut bytes = vec![SEC1_UNCOMPRESSED];
+        bytes.extend_from_slice(&coordinate_bytes(&self.x));
+        bytes.extend_from_slice(&coordinate_bytes(&self.y));
+        bytes
+    }
+
+    // SEC1 compressed encoding: 0x02 or 0x03 for an even or odd y, then x.
+    // y is recovered from the curve equation on decoding
+    pub fn get_compressed_bytes(&self) -> Vec<u8> {
+        if self.is_infinity() {
+            return vec![SEC1_INFINITY];
         }
-        let mut bytes = vec![0x04]; // Uncompressed point prefix
-        bytes.extend_from_slice(&self.x.to_bytes_be());
-        bytes.extend_from_slice(&self.y.to_bytes_be());
+        let mut bytes = vec![if self.y.is_odd() { SEC1_ODD } else { SEC1_EVEN }];
+        bytes.extend_from_slice(&coordinate_bytes(&self.x));
         bytes
     }
+
+    // Either SEC1 encoding, points that are not on the curve are rejected
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let (&prefix, rest) = bytes.split_first()?;
+        match (prefix, rest.len()) {
+            (SEC1_INFINITY, 0) => Some(AffinePoint::infinity()),
+            (SEC1_EVEN | SEC1_ODD, 32) => AffinePoint::decompress(BigInt::from_bytes_be(rest), prefix == SEC1_ODD),
+            (SEC1_UNCOMPRESSED, 64) => {
+                let point = AffinePoint::new(BigInt::from_bytes_be(&rest[..32]), BigInt::from_bytes_be(&rest[32..]));
+                point.is_on_curve().then_some(point)
+            }
+            _ => None
+        }
+    }
+}
+
+// BigInt::to_bytes_be strips leading zeros, encodings always use 32 bytes
+pub fn coordinate_bytes(value: &BigInt<4>) -> [u8; 32] {
+    let bytes = value.to_bytes_be();
+    let mut out = [0; 32];
+    out[32 - bytes.len()..].copy_from_slice(&bytes);
+    out
+}
+
+// g_scalar * G + scalar * point, the sum ECDSA and Schnorr verification need
+pub fn shamir_multiply(g_scalar: BigInt<4>, point: &AffinePoint, scalar: BigInt<4>) -> JacobianPoint {
+    JacobianPoint::multi_scalar_multiply(&[(G, g_scalar), (*point, scalar)])
+}
+
+// The scalar in base 2^COMB_BITS, k = sum d_j * 2^(COMB_BITS * j), so k * G
+// is the sum of the table entries d_j * 2^(COMB_BITS * j) * G without any
+// doubling
+fn generator_multiply(scalar: BigInt<4>) -> JacobianPoint {
+    let table = GENERATOR_COMB.get_or_init(|| {
+        let mut points = Vec::with_capacity(COMB_WINDOWS * COMB_ENTRIES);
+        let mut base = JacobianPoint::from_affine(&G);
+        for _ in 0..COMB_WINDOWS {
+            let mut multiple = base;
+            for _ in 0..COMB_ENTRIES {
+                points.push(multiple);
+                multiple = multiple + base;
+            }
+            base = multiple;
+        }
+        JacobianPoint::to_affine_batch(&points)
+    });
+
+    let bits = scalar.to_bits();
+    let mut result = JacobianPoint::from_affine(&AffinePoint::infinity());
+    for (window, bits) in bits.chunks(COMB_BITS).enumerate() {
+        let digit = bits.iter().rev().fold(0, |digit, bit| (digit << 1) | *bit as usize);
+        if digit != 0 {
+            result = result + table[window * COMB_ENTRIES + digit - 1];
+        }
+    }
+    result
+}
+
+fn generator_wnaf_table() -> &'static [AffinePoint] {
+    GENERATOR_WNAF.get_or_init(|| odd_multiples(&[G], G_WNAF_WINDOW).pop().unwrap())
+}
+
+// P, 3P, 5P, ... up to the largest wNAF digit of the window, for each point
+fn odd_multiples(points: &[AffinePoint], window: u32) -> Vec<Vec<AffinePoint>> {
+    let count = 1 << (window - 2);
+    let mut multiples = Vec::with_capacity(points.len() * count);
+    for point in points {
+        let mut multiple = JacobianPoint::from_affine(point);
+        let double = multiple.double();
+        for _ in 0..count {
+            multiples.push(multiple);
+            multiple = multiple + double;
+        }
+    }
+    JacobianPoint::to_affine_batch(&multiples).chunks(count).map(|chunk| chunk.to_vec()).collect()
+}
+
+// Width-w non-adjacent form of the scalar, least significant digit first.
+// Every non-zero digit is odd with an absolute value below 2^(w-1) and is
+// followed by at least w-1 zeros, so a multiplication needs one doubling per
+// bit but only about one addition per w+1 bits. Has 257 digits, a carry
+// can go past the top bit.
+fn wnaf(scalar: &BigInt<4>, window: u32) -> Vec<i32> {
+    let bits = scalar.to_bits();
+    let bit = |i: usize| bits.get(i).map_or(0, |bit| *bit as i32);
+    let mut digits = vec![0; bits.len() + 1];
+    let mut carry = 0;
+    let mut i = 0;
+    while i < bits.len() {
+        if bit(i) == carry {
+            i += 1;
+            continue;
+        }
+        let mut word = (0..window as usize).rev().fold(0, |word, j| (word << 1) | bit(i + j)) + carry;
+        carry = (word >> (window - 1)) & 1;
+        word -= carry << window;
+        digits[i] = word;
+        i += window as usize;
+    }
+    digits[bits.len()] = carry;
+    digits
 }
 
 impl JacobianPoint {
+    // The sum of scalar * point over all terms. The terms share one chain of
+    // doublings, which is what makes summing many products cheaper than
+    // multiplying them one by one (Straus' method, Shamir's trick for two
+    // terms). Each scalar is added in with its wNAF digits, G uses a wider
+    // window with its table built once.
+    pub fn multi_scalar_multiply(terms: &[(AffinePoint, BigInt<4>)]) -> Self {
+        let terms: Vec<&(AffinePoint, BigInt<4>)> = terms.iter()
+            .filter(|(point, scalar)| !point.is_infinity() && *scalar != BigInt::from_num(0))
+            .collect();
+        let points: Vec<AffinePoint> = terms.iter().map(|(point, _)| *point).filter(|point| *point != G).collect();
+        let tables = odd_multiples(&points, WNAF_WINDOW);
+        let mut tables = tables.iter();
+        let digits: Vec<(&[AffinePoint], Vec<i32>)> = terms.iter()
+            .map(|(point, scalar)| if *point == G {
+                (generator_wnaf_table(), wnaf(scalar, G_WNAF_WINDOW))
+            } else {
+                (tables.next().unwrap().as_slice(), wnaf(scalar, WNAF_WINDOW))
+            })
+            .collect();
+
+        let mut result = Self::from_affine(&AffinePoint::infinity());
+        for i in (0..=256).rev() {
+            result = result.double();
+            for (table, digits) in &digits {
+                let digit = digits[i];
+                if digit > 0 {
+                    result = result + table[digit as usize / 2];
+                } else if digit < 0 {
+                    result = result + table[-digit as usize / 2].negate();
+                }
+            }
+        }
+        result
+    }
+
+    // Converts all points with a single inversion (Montgomery's trick): the
+    // inverse of the product of all z gives each inverse with a few
+    // multiplications. None of the points may be infinite.
+    pub fn to_affine_batch(points: &[JacobianPoint]) -> Vec<AffinePoint> {
+        let z: Vec<BigIntMod<12>> = points.iter()
+            .map(|point| BigIntMod::new_with_mu(point.z.resize(), P.resize(), BARRET_MU_P))
+            .collect();
+        let mut products = Vec::with_capacity(z.len());
+        for (i, z) in z.iter().enumerate() {
+            products.push(if i == 0 { *z } else { products[i - 1] * *z });
+        }
+        let Some(product) = products.last() else {
+            return Vec::new();
+        };
+        let mut inverse = BigIntMod::new_with_mu(mod_inverse(product.integer, P.resize()), P.resize(), BARRET_MU_P);
+
+        let mut affine = vec![AffinePoint::infinity(); points.len()];
+        for i in (0..points.len()).rev() {
+            let z_inv = if i == 0 { inverse } else { inverse * products[i - 1] };
+            inverse = inverse * z[i];
+            let z_inv_2 = z_inv.square();
+            let x = BigIntMod::new_with_mu(points[i].x.resize(), P.resize(), BARRET_MU_P) * z_inv_2;
+            let y = BigIntMod::new_with_mu(points[i].y.resize(), P.resize(), BARRET_MU_P) * z_inv_2 * z_inv;
+            affine[i] = AffinePoint::new(x.integer.resize(), y.integer.resize());
+        }
+        affine
+    }
+
     pub fn new(x: BigInt<4>, y: BigInt<4>, z: BigInt<4>) -> Self {
         Self { x, y, z }
     }
@@ -218,4 +449,41 @@ impl std::fmt::Display for JacobianPoint {
             write!(f, "JacobianPoint({}, {}, {})", self.x.get_hex(), self.y.get_hex(), self.z.get_hex())
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::math::random;
+    use super::*;
+
+    #[test]
+    fn test_scalar_multiply_matches_double_and_add() {
+        let random_scalar = || {
+            let mut bytes = [0u8; 32];
+            random::get_random_bytes(&mut bytes).unwrap();
+            BigInt::<4>::from_bytes_be(&bytes)
+        };
+        let point = G.scalar_multiply_double_and_add(random_scalar()).to_affine();
+        let mut scalars = vec![BigInt::from_num(1), BigInt::from_num(2), BigInt::from_num(0xffff), (N.resize::<12>() - BigInt::from_num(1)).resize()];
+        scalars.extend((0..4).map(|_| random_scalar()));
+        for scalar in scalars {
+            for base in [G, point] {
+                assert_eq!(base.scalar_multiply(scalar).to_affine(), base.scalar_multiply_double_and_add(scalar).to_affine());
+            }
+            // Every digit is odd or zero and together they give the scalar back
+            let digits = wnaf(&scalar, WNAF_WINDOW);
+            assert!(digits.iter().all(|digit| *digit == 0 || (digit % 2 != 0 && digit.abs() < 16)));
+            let sum = digits.iter().rev().fold(JacobianPoint::from_affine(&AffinePoint::infinity()), |sum, digit| {
+                let sum = sum.double();
+                let multiple = G.scalar_multiply_double_and_add(BigInt::from_num(digit.unsigned_abs() as u128));
+                if *digit < 0 { sum + multiple.to_affine().negate() } else { sum + multiple }
+            });
+            assert_eq!(sum.to_affine(), G.scalar_multiply_double_and_add(scalar).to_affine());
+        }
+        assert!(G.scalar_multiply(N).is_infinity());
+        assert!(G.scalar_multiply(BigInt::from_num(0)).is_infinity());
+
+        let (a, b) = (random_scalar(), random_scalar());
+        let expected = G.scalar_multiply_double_and_add(a) + point.scalar_multiply_double_and_add(b);
+        assert_eq!(shamir_multiply(a, &point, b).to_affine(), expected.to_affine());
+    }
+}
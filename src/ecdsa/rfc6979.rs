@@ -0,0 +1,67 @@
+use crate::{math::big_int::BigInt, sha256::Sha256};
+use super::secp256k1::N;
+
+// Deterministic nonces as in RFC 6979 section 3.2 with HMAC-SHA256. k only
+// depends on the private key and the message hash, so signing the same
+// message twice gives the same signature and a bad random number generator
+// can not leak the key through a reused k.
+//
+// The generator yields the candidates in 1..N in order, the next one is only
+// needed if a candidate gives r = 0 or s = 0.
+pub struct NonceGenerator {
+    k: [u8; 32],
+    v: [u8; 32],
+}
+
+impl NonceGenerator {
+    pub fn new(private_key: &BigInt<4>, hash: &[u8; 32]) -> Self {
+        let mut seed = int_to_octets(private_key).to_vec();
+        seed.extend_from_slice(&bits_to_octets(hash));
+
+        let mut generator = NonceGenerator { k: [0x00; 32], v: [0x01; 32] };
+        generator.reseed(0x00, &seed);
+        generator.reseed(0x01, &seed);
+        generator
+    }
+
+    // K = HMAC_K(V || separator || data), V = HMAC_K(V)
+    fn reseed(&mut self, separator: u8, data: &[u8]) {
+        let mut message = self.v.to_vec();
+        message.push(separator);
+        message.extend_from_slice(data);
+        self.k = *Sha256::hmac(&self.k, &message).bytes();
+        self.v = *Sha256::hmac(&self.k, &self.v).bytes();
+    }
+}
+
+impl Iterator for NonceGenerator {
+    type Item = BigInt<4>;
+
+    fn next(&mut self) -> Option<BigInt<4>> {
+        loop {
+            // The order has as many bits as the hash, one block is enough
+            self.v = *Sha256::hmac(&self.k, &self.v).bytes();
+            let candidate = BigInt::from_bytes_be(&self.v);
+            self.reseed(0x00, &[]);
+            if candidate != BigInt::from_num(0) && candidate < N {
+                return Some(candidate);
+            }
+        }
+    }
+}
+
+fn int_to_octets(value: &BigInt<4>) -> [u8; 32] {
+    let bytes = value.to_bytes_be();
+    let mut out = [0; 32];
+    out[32 - bytes.len()..].copy_from_slice(&bytes);
+    out
+}
+
+// The hash as a number reduced mod N, it is below 2N so one subtraction does
+fn bits_to_octets(hash: &[u8; 32]) -> [u8; 32] {
+    let mut z = BigInt::<4>::from_bytes_be(hash);
+    if z >= N {
+        z = (z.resize::<12>() - N.resize()).resize();
+    }
+    int_to_octets(&z)
+}
@@ -1,4 +1,4 @@
-use crate::math::big_int::BigInt;
+use crate::math::big_int::{BigInt, BigIntMod};
 use crate::ecdsa::point::AffinePoint;
 
 pub const P: BigInt<4> = BigInt::from_parts([
@@ -14,6 +14,10 @@ pub const G: AffinePoint = AffinePoint::new(GX, GY);
 pub const N: BigInt<4> = BigInt::from_parts([
     0xBFD25E8CD0364141, 0xBAAEDCE6AF48A03B, 0xFFFFFFFFFFFFFFFE, 0xFFFFFFFFFFFFFFFF
 ]);
+// N / 2 rounded down, the largest s of a low-S signature
+pub const HALF_N: BigInt<4> = BigInt::from_parts([
+    0xDFE92F46681B20A0, 0x5D576E7357A4501D, 0xFFFFFFFFFFFFFFFF, 0x7FFFFFFFFFFFFFFF
+]);
 
 pub const BARRET_MU_P: BigInt<12> = BigInt::from_parts([
     0x0, 0x0, 0x00000001000003d1, 0x0, 0x0, 0x0, 0x1, 0x0, 0x0, 0x0, 0x0, 0x0
@@ -22,4 +26,17 @@ pub const BARRET_MU_P: BigInt<12> = BigInt::from_parts([
 pub const BARRET_MU_N: BigInt<12> = BigInt::from_parts([
     0xe697f5e45bcd07c7, 0x9d671cd581c69bc5, 0x402da1732fc9bec0, 
     0x4551231950b75fc4, 0x1, 0x0, 0x1, 0x0, 0x0, 0x0, 0x0, 0x0
-]);
\ No newline at end of file
+]);
+// Square root mod P, None if a is not a square. P is 3 mod 4, so for a square
+// a the root is a^((P+1)/4): its square is a^((P-1)/2) * a = a
+pub fn sqrt_mod_p(a: BigInt<4>) -> Option<BigInt<4>> {
+    let a = BigIntMod::<12>::new_reduce(a.resize(), P.resize(), BARRET_MU_P);
+    let root = a.pow((P.resize::<12>() + BigInt::from_num(1)) >> 2);
+    (root.square().integer == a.integer).then(|| root.integer.resize())
+}
+
+// x^3 + 7 mod P, the y^2 of the points with this x
+pub fn curve_y_squared(x: BigInt<4>) -> BigInt<4> {
+    let x = BigIntMod::<12>::new_with_mu(x.resize(), P.resize(), BARRET_MU_P);
+    (x.square() * x + BigIntMod::from_num(7, P.resize())).integer.resize()
+}
@@ -2,7 +2,10 @@ pub mod rsa;
 pub mod math;
 pub mod sha256;
 pub mod ecdsa;
+pub mod schnorr;
 pub mod util;
 pub mod blockchain;
 pub mod node;
+pub mod net;
+pub mod rpc;
 pub mod user;
\ No newline at end of file
@@ -0,0 +1,273 @@
+use std::{io::{Read, Write}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr}};
+use crate::{
+    blockchain::{block::{Block, BlockHeader}, encoding::{read_list, write_list, Decode, DecodeError, Encode, Reader}, transaction::Transaction},
+    sha256::Sha256
+};
+use super::{sync::MAX_HEADERS_PER_MESSAGE, NetError};
+
+// Every message starts with the magic, a command byte, the payload length and
+// the first four bytes of the sha256 of the payload
+pub const NETWORK_MAGIC: [u8; 4] = *b"NET1";
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+const HEADER_SIZE: usize = 13;
+
+const CMD_VERSION: u8 = 0;
+const CMD_VERACK: u8 = 1;
+const CMD_PING: u8 = 2;
+const CMD_PONG: u8 = 3;
+const CMD_INV: u8 = 4;
+const CMD_GETDATA: u8 = 5;
+const CMD_NOTFOUND: u8 = 6;
+const CMD_BLOCK: u8 = 7;
+const CMD_TX: u8 = 8;
+const CMD_GETADDR: u8 = 9;
+const CMD_ADDR: u8 = 10;
+const CMD_GETHEADERS: u8 = 11;
+const CMD_HEADERS: u8 = 12;
+
+const INV_TRANSACTION: u8 = 1;
+const INV_BLOCK: u8 = 2;
+
+const ADDR_IPV4: u8 = 4;
+const ADDR_IPV6: u8 = 6;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Inventory {
+    Transaction(Sha256),
+    Block(Sha256),
+}
+
+// nonce is random per node and lets a node detect connections to itself.
+// listen_port is where the sender accepts connections, 0 if it does not.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Version {
+    pub version: u32,
+    pub nonce: u64,
+    pub listen_port: u16,
+    pub best_height: u64,
+}
+
+#[derive(Clone, Debug)]
+pub enum Message {
+    Version(Version),
+    Verack,
+    Ping(u64),
+    Pong(u64),
+    Inv(Vec<Inventory>),
+    GetData(Vec<Inventory>),
+    NotFound(Vec<Inventory>),
+    Block(Block),
+    Tx(Transaction),
+    GetAddr,
+    Addr(Vec<SocketAddr>),
+    GetHeaders(Vec<Sha256>), // Block locator, see BlockSync::locator
+    Headers(Vec<BlockHeader>),
+}
+
+impl Message {
+    fn command(&self) -> u8 {
+        match self {
+            Message::Version(_) => CMD_VERSION,
+            Message::Verack => CMD_VERACK,
+            Message::Ping(_) => CMD_PING,
+            Message::Pong(_) => CMD_PONG,
+            Message::Inv(_) => CMD_INV,
+            Message::GetData(_) => CMD_GETDATA,
+            Message::NotFound(_) => CMD_NOTFOUND,
+            Message::Block(_) => CMD_BLOCK,
+            Message::Tx(_) => CMD_TX,
+            Message::GetAddr => CMD_GETADDR,
+            Message::Addr(_) => CMD_ADDR,
+            Message::GetHeaders(_) => CMD_GETHEADERS,
+            Message::Headers(_) => CMD_HEADERS,
+        }
+    }
+
+    fn encode_payload(&self, out: &mut Vec<u8>) {
+        match self {
+            Message::Version(version) => {
+                out.extend_from_slice(&version.version.to_be_bytes());
+                out.extend_from_slice(&version.nonce.to_be_bytes());
+                out.extend_from_slice(&version.listen_port.to_be_bytes());
+                out.extend_from_slice(&version.best_height.to_be_bytes());
+            }
+            Message::Verack | Message::GetAddr => {}
+            Message::Ping(nonce) | Message::Pong(nonce) => out.extend_from_slice(&nonce.to_be_bytes()),
+            Message::Inv(items) | Message::GetData(items) | Message::NotFound(items) => write_list(out, items),
+            Message::Block(block) => block.encode(out),
+            Message::Tx(tx) => tx.encode(out),
+            Message::Addr(addrs) => write_list(out, addrs),
+            Message::GetHeaders(locator) => write_list(out, locator),
+            Message::Headers(headers) => write_list(out, headers),
+        }
+    }
+
+    fn decode_payload(command: u8, payload: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader::new(payload);
+        let message = match command {
+            CMD_VERSION => Message::Version(Version {
+                version: reader.read_u32()?,
+                nonce: reader.read_u64()?,
+                listen_port: u16::from_be_bytes(reader.read_array()?),
+                best_height: reader.read_u64()?,
+            }),
+            CMD_VERACK => Message::Verack,
+            CMD_PING => Message::Ping(reader.read_u64()?),
+            CMD_PONG => Message::Pong(reader.read_u64()?),
+            CMD_INV => Message::Inv(read_list(&mut reader)?),
+            CMD_GETDATA => Message::GetData(read_list(&mut reader)?),
+            CMD_NOTFOUND => Message::NotFound(read_list(&mut reader)?),
+            CMD_BLOCK => Message::Block(Block::decode(&mut reader)?),
+            CMD_TX => Message::Tx(Transaction::decode(&mut reader)?),
+            CMD_GETADDR => Message::GetAddr,
+            CMD_ADDR => Message::Addr(read_list(&mut reader)?),
+            CMD_GETHEADERS => Message::GetHeaders(read_list(&mut reader)?),
+            CMD_HEADERS => {
+                let headers: Vec<BlockHeader> = read_list(&mut reader)?;
+                if headers.len() > MAX_HEADERS_PER_MESSAGE {
+                    return Err(DecodeError::LengthTooLarge);
+                }
+                Message::Headers(headers)
+            }
+            command => return Err(DecodeError::InvalidTag(command))
+        };
+        reader.finish()?;
+        Ok(message)
+    }
+}
+
+impl Encode for Inventory {
+    fn encode(&self, out: &mut Vec<u8>) {
+        let (tag, hash) = match self {
+            Inventory::Transaction(hash) => (INV_TRANSACTION, hash),
+            Inventory::Block(hash) => (INV_BLOCK, hash),
+        };
+        out.push(tag);
+        hash.encode(out);
+    }
+}
+
+impl Decode for Inventory {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        match reader.read_u8()? {
+            INV_TRANSACTION => Ok(Inventory::Transaction(Sha256::decode(reader)?)),
+            INV_BLOCK => Ok(Inventory::Block(Sha256::decode(reader)?)),
+            tag => Err(DecodeError::InvalidTag(tag))
+        }
+    }
+}
+
+impl Encode for SocketAddr {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self.ip() {
+            IpAddr::V4(ip) => {
+                out.push(ADDR_IPV4);
+                out.extend_from_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                out.push(ADDR_IPV6);
+                out.extend_from_slice(&ip.octets());
+            }
+        }
+        out.extend_from_slice(&self.port().to_be_bytes());
+    }
+}
+
+impl Decode for SocketAddr {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let ip = match reader.read_u8()? {
+            ADDR_IPV4 => IpAddr::V4(Ipv4Addr::from(reader.read_array::<4>()?)),
+            ADDR_IPV6 => IpAddr::V6(Ipv6Addr::from(reader.read_array::<16>()?)),
+            tag => return Err(DecodeError::InvalidTag(tag))
+        };
+        Ok(SocketAddr::new(ip, u16::from_be_bytes(reader.read_array()?)))
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    Sha256::hash(payload).bytes()[..4].try_into().unwrap()
+}
+
+pub fn write_message<W: Write>(writer: &mut W, message: &Message) -> Result<(), NetError> {
+    let mut payload = Vec::new();
+    message.encode_payload(&mut payload);
+
+    let mut frame = Vec::with_capacity(HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&NETWORK_MAGIC);
+    frame.push(message.command());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Blocks until a whole message has been read
+pub fn read_message<R: Read>(reader: &mut R) -> Result<Message, NetError> {
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header)?;
+    if header[..4] != NETWORK_MAGIC {
+        return Err(NetError::InvalidMagic);
+    }
+    let command = header[4];
+    let len = u32::from_be_bytes(header[5..9].try_into().unwrap()) as usize;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(NetError::MessageTooLarge);
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    if header[9..] != checksum(&payload) {
+        return Err(NetError::InvalidChecksum);
+    }
+    Ok(Message::decode_payload(command, &payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ecdsa;
+    use super::*;
+
+    fn roundtrip(message: &Message) -> Message {
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, message).unwrap();
+        read_message(&mut bytes.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn test_message_roundtrip() {
+        let version = Version { version: PROTOCOL_VERSION, nonce: 7, listen_port: 8333, best_height: 12 };
+        assert!(matches!(roundtrip(&Message::Version(version.clone())), Message::Version(v) if v == version));
+        assert!(matches!(roundtrip(&Message::Ping(5)), Message::Ping(5)));
+
+        let items = vec![Inventory::Block(Sha256::hash(b"block")), Inventory::Transaction(Sha256::hash(b"tx"))];
+        assert!(matches!(roundtrip(&Message::GetData(items.clone())), Message::GetData(decoded) if decoded == items));
+
+        let addrs: Vec<SocketAddr> = vec!["127.0.0.1:8333".parse().unwrap(), "[::1]:18333".parse().unwrap()];
+        assert!(matches!(roundtrip(&Message::Addr(addrs.clone())), Message::Addr(decoded) if decoded == addrs));
+
+        let tx = Transaction::get_coinbase(ecdsa::generate_keypair().0, 50);
+        assert!(matches!(roundtrip(&Message::Tx(tx.clone())), Message::Tx(decoded) if decoded == tx));
+    }
+
+    #[test]
+    fn test_corrupted_message() {
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &Message::Ping(5)).unwrap();
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(read_message(&mut corrupted.as_slice()), Err(NetError::InvalidChecksum)));
+
+        let mut magic = bytes.clone();
+        magic[0] = 0;
+        assert!(matches!(read_message(&mut magic.as_slice()), Err(NetError::InvalidMagic)));
+
+        let mut oversized = bytes;
+        oversized[5..9].copy_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes());
+        assert!(matches!(read_message(&mut oversized.as_slice()), Err(NetError::MessageTooLarge)));
+    }
+}
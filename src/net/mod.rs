@@ -0,0 +1,714 @@
+use std::{
+    collections::HashMap, io,
+    net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex},
+    thread::{self, JoinHandle}, time::{Duration, Instant}
+};
+use message::{read_message, write_message, Inventory, Message, Version, PROTOCOL_VERSION};
+use sync::{BlockSync, SyncProgress, MAX_HEADERS_PER_MESSAGE};
+use crate::{
+    blockchain::{block::{Block, BlockHeader}, encoding::DecodeError, transaction::Transaction, BlockError, BlockStatus},
+    math::random,
+    node::{mempool::MempoolError, Node}, sha256::Sha256
+};
+
+pub mod message;
+pub mod sync;
+
+pub const MAX_PEERS: usize = 16;
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+// A peer that sends nothing for this long, not even a pong, is dropped
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(90);
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+const SYNC_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_ADDR_COUNT: usize = 100;
+// Nonces tried between checks for a new tip or a stop while mining
+const MINE_ATTEMPTS: u64 = 10_000;
+
+#[derive(Debug)]
+pub enum NetError {
+    Io(io::Error),
+    Decode(DecodeError),
+    InvalidMagic,
+    InvalidChecksum,
+    MessageTooLarge,
+    Handshake,
+    SelfConnection,
+    DuplicateConnection,
+    TooManyPeers,
+    InvalidHeader(BlockError),
+}
+
+impl From<io::Error> for NetError {
+    fn from(e: io::Error) -> Self {
+        NetError::Io(e)
+    }
+}
+
+impl From<DecodeError> for NetError {
+    fn from(e: DecodeError) -> Self {
+        NetError::Decode(e)
+    }
+}
+
+// The handshake is done once the peer has sent both its version and a verack
+#[derive(Default)]
+struct PeerState {
+    version: Option<Version>,
+    verack: bool,
+}
+
+struct Peer {
+    id: u64,
+    addr: SocketAddr,
+    outbound: bool,
+    writer: Mutex<TcpStream>,
+    state: Mutex<PeerState>,
+}
+
+impl Peer {
+    fn send(&self, message: &Message) -> Result<(), NetError> {
+        write_message(&mut *self.writer.lock().unwrap(), message)
+    }
+
+    fn is_established(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.version.is_some() && state.verack
+    }
+
+    fn nonce(&self) -> Option<u64> {
+        self.state.lock().unwrap().version.as_ref().map(|version| version.nonce)
+    }
+
+    // Where the peer accepts connections, which is only the address it
+    // connected from if the connection is outbound
+    fn listen_addr(&self) -> Option<SocketAddr> {
+        if self.outbound {
+            return Some(self.addr);
+        }
+        let state = self.state.lock().unwrap();
+        state.version.as_ref()
+            .filter(|version| version.listen_port != 0)
+            .map(|version| SocketAddr::new(self.addr.ip(), version.listen_port))
+    }
+
+    fn disconnect(&self) {
+        let _ = self.writer.lock().unwrap().shutdown(Shutdown::Both);
+    }
+}
+
+struct Shared {
+    node: Mutex<Node>,
+    sync: Mutex<BlockSync>,
+    peers: Mutex<HashMap<u64, Arc<Peer>>>,
+    next_peer_id: AtomicU64,
+    local_addr: SocketAddr,
+    nonce: u64,
+    running: AtomicBool,
+}
+
+impl Shared {
+    fn version(&self) -> Version {
+        Version {
+            version: PROTOCOL_VERSION,
+            nonce: self.nonce,
+            listen_port: self.local_addr.port(),
+            best_height: self.node.lock().unwrap().blockchain().blocks.len() as u64,
+        }
+    }
+
+    fn established_peers(&self) -> Vec<Arc<Peer>> {
+        self.peers.lock().unwrap().values().filter(|peer| peer.is_established()).cloned().collect()
+    }
+
+    fn is_connected(&self, addr: &SocketAddr) -> bool {
+        self.peers.lock().unwrap().values().any(|peer| peer.addr == *addr || peer.listen_addr() == Some(*addr))
+    }
+
+    fn broadcast(&self, message: &Message, except: Option<&Arc<Peer>>) {
+        for peer in self.established_peers() {
+            if except.is_some_and(|except| Arc::ptr_eq(except, &peer)) {
+                continue;
+            }
+            if peer.send(message).is_err() {
+                peer.disconnect();
+            }
+        }
+    }
+
+    fn is_known(&self, item: &Inventory) -> bool {
+        let node = self.node.lock().unwrap();
+        match item {
+            Inventory::Block(hash) => node.blockchain().contains_block(hash),
+            Inventory::Transaction(txid) => node.mempool().contains(txid) || node.blockchain().transaction_height(txid).is_some()
+        }
+    }
+
+    // Asks every peer for the next blocks of the best header chain it has
+    fn request_blocks(&self) {
+        let peers = self.established_peers();
+        let requests: Vec<(Arc<Peer>, Vec<Inventory>)> = {
+            let node = self.node.lock().unwrap();
+            let mut sync = self.sync.lock().unwrap();
+            peers.into_iter()
+                .map(|peer| {
+                    let hashes = sync.request_blocks(peer.id, node.blockchain());
+                    (peer, hashes.into_iter().map(Inventory::Block).collect())
+                })
+                .collect()
+        };
+        for (peer, items) in requests {
+            if !items.is_empty() && peer.send(&Message::GetData(items)).is_err() {
+                peer.disconnect();
+            }
+        }
+    }
+
+    // Connects the downloaded blocks that are next in line and announces the new tip
+    fn connect_blocks(&self) {
+        let tip = {
+            let mut node = self.node.lock().unwrap();
+            let mut sync = self.sync.lock().unwrap();
+            let mut tip = None;
+            for block in sync.take_ready(node.blockchain()) {
+                let hash = block.hash.clone();
+                if node.accept_block(block).is_err() {
+                    sync.invalid_block(&hash);
+                    break;
+                }
+                tip = Some(hash);
+            }
+            tip
+        };
+        if let Some(tip) = tip {
+            self.broadcast(&Message::Inv(vec![Inventory::Block(tip)]), None);
+        }
+        self.request_blocks();
+    }
+
+    // Starts downloading headers from a peer that just finished the handshake
+    // if it claims a longer chain than the best known header chain
+    fn start_sync(&self, peer: &Arc<Peer>) -> Result<(), NetError> {
+        let best_height = peer.state.lock().unwrap().version.as_ref().map_or(0, |version| version.best_height as usize);
+        let (behind, locator) = {
+            let mut sync = self.sync.lock().unwrap();
+            sync.set_peer_height(peer.id, best_height);
+            (best_height > sync.headers().best_len(), sync.headers().locator())
+        };
+        if behind {
+            peer.send(&Message::GetHeaders(locator))?;
+        }
+        self.request_blocks();
+        Ok(())
+    }
+
+    fn accept_headers(&self, peer: &Arc<Peer>, headers: Vec<BlockHeader>) -> Result<(), NetError> {
+        let more = headers.len() == MAX_HEADERS_PER_MESSAGE;
+        let locator = {
+            let mut sync = self.sync.lock().unwrap();
+            let mut last = None;
+            for header in headers {
+                let hash = header.hash();
+                sync.accept_header(header).map_err(NetError::InvalidHeader)?;
+                last = Some(hash);
+            }
+            if let Some(height) = last.and_then(|hash| sync.headers().height(&hash)) {
+                sync.set_peer_height(peer.id, height + 1);
+            }
+            sync.headers().locator()
+        };
+        if more {
+            peer.send(&Message::GetHeaders(locator))?;
+        }
+        self.request_blocks();
+        Ok(())
+    }
+}
+
+// Runs a Node on the network. Every connection gets its own thread reading
+// messages from the peer, one more thread accepts connections and pings peers.
+// New blocks and transactions are announced with inv messages, peers that do
+// not know them yet fetch them and announce them in turn. Blocks are always
+// fetched headers first, see sync::BlockSync.
+pub struct Network {
+    shared: Arc<Shared>,
+    listener_thread: Option<JoinHandle<()>>,
+}
+
+impl Network {
+    pub fn start<A: ToSocketAddrs>(node: Node, addr: A) -> Result<Self, NetError> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let shared = Arc::new(Shared {
+            sync: Mutex::new(BlockSync::new(node.blockchain())),
+            node: Mutex::new(node),
+            peers: Mutex::new(HashMap::new()),
+            next_peer_id: AtomicU64::new(0),
+            local_addr: listener.local_addr()?,
+            nonce: random::get_nrandom_u64(1)[0],
+            running: AtomicBool::new(true),
+        });
+
+        let thread_shared = shared.clone();
+        let listener_thread = thread::spawn(move || run_listener(thread_shared, listener));
+        Ok(Network { shared, listener_thread: Some(listener_thread) })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.shared.local_addr
+    }
+
+    pub fn connect(&self, addr: SocketAddr) -> Result<(), NetError> {
+        connect_to(&self.shared, addr)
+    }
+
+    pub fn with_node<T, F: FnOnce(&mut Node) -> T>(&self, f: F) -> T {
+        f(&mut self.shared.node.lock().unwrap())
+    }
+
+    // Addresses of the peers that finished the handshake
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.shared.established_peers().iter().map(|peer| peer.addr).collect()
+    }
+
+    pub fn peer_count(&self) -> usize {
+        self.shared.established_peers().len()
+    }
+
+    pub fn submit_transaction(&self, transaction: Transaction) -> Result<(), MempoolError> {
+        let txid = transaction.hash();
+        self.shared.node.lock().unwrap().add_transaction(transaction)?;
+        self.shared.broadcast(&Message::Inv(vec![Inventory::Transaction(txid)]), None);
+        Ok(())
+    }
+
+    // Mines a block on top of the active chain and announces it. The node is
+    // only locked to build the block and to add it, so the peers are served
+    // while the nonces are searched. The search starts over on a new tip.
+    // None if stop returns true or the network shuts down first.
+    pub fn mine<F: Fn() -> bool>(&self, stop: F) -> Result<Option<Block>, BlockError> {
+        loop {
+            let mut block = self.with_node(|node| node.block_template())?;
+            let previous = block.header.previous_block_hash.clone();
+            let mut stale = false;
+            while !stale && !block.try_mine(MINE_ATTEMPTS) {
+                if stop() || !self.shared.running.load(Ordering::SeqCst) {
+                    return Ok(None);
+                }
+                let tip = self.with_node(|node| node.blockchain().blocks.last().map_or(Sha256::hash(&[]), |tip| tip.hash.clone()));
+                stale = tip != previous;
+            }
+            if !stale {
+                self.add_block(block.clone(), Node::accept_mined_block)?;
+                return Ok(Some(block));
+            }
+        }
+    }
+
+    // A block mined elsewhere, announced to the peers if it is valid
+    pub fn submit_block(&self, block: Block) -> Result<BlockStatus, BlockError> {
+        self.add_block(block, Node::accept_block)
+    }
+
+    fn add_block<F: FnOnce(&mut Node, Block) -> Result<BlockStatus, BlockError>>(&self, block: Block, accept: F) -> Result<BlockStatus, BlockError> {
+        let hash = block.hash.clone();
+        let mut node = self.shared.node.lock().unwrap();
+        let status = accept(&mut node, block.clone())?;
+        self.shared.sync.lock().unwrap().add_block_header(&block);
+        drop(node);
+        self.shared.broadcast(&Message::Inv(vec![Inventory::Block(hash)]), None);
+        Ok(status)
+    }
+
+    pub fn sync_progress(&self) -> SyncProgress {
+        let node = self.shared.node.lock().unwrap();
+        let progress = self.shared.sync.lock().unwrap().progress(node.blockchain());
+        progress
+    }
+
+    // Blocks until the node has caught up with its peers, calling report
+    // whenever the progress changes. Returns false on timeout.
+    pub fn wait_for_sync<F: FnMut(&SyncProgress)>(&self, timeout: Duration, mut report: F) -> bool {
+        let start = Instant::now();
+        let mut last = None;
+        while start.elapsed() < timeout {
+            let progress = self.sync_progress();
+            if last.as_ref() != Some(&progress) {
+                report(&progress);
+            }
+            if progress.is_synced() && self.peer_count() > 0 {
+                return true;
+            }
+            last = Some(progress);
+            thread::sleep(SYNC_POLL_INTERVAL);
+        }
+        false
+    }
+
+    pub fn shutdown(&mut self) {
+        self.shared.running.store(false, Ordering::SeqCst);
+        for peer in self.shared.peers.lock().unwrap().values() {
+            peer.disconnect();
+        }
+        if let Some(thread) = self.listener_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Network {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn run_listener(shared: Arc<Shared>, listener: TcpListener) {
+    let mut last_ping = Instant::now();
+    let mut last_sync = Instant::now();
+    while shared.running.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                let shared = shared.clone();
+                thread::spawn(move || run_peer(shared, stream, addr, false));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(_) => thread::sleep(ACCEPT_POLL_INTERVAL)
+        }
+
+        if last_ping.elapsed() >= PING_INTERVAL {
+            shared.broadcast(&Message::Ping(random::get_nrandom_u64(1)[0]), None);
+            last_ping = Instant::now();
+        }
+
+        // Stalled downloads are handed to other peers
+        if last_sync.elapsed() >= SYNC_INTERVAL {
+            let stalled = shared.sync.lock().unwrap().expire_requests();
+            for peer in shared.peers.lock().unwrap().values().filter(|peer| stalled.contains(&peer.id)) {
+                peer.disconnect();
+            }
+            shared.request_blocks();
+            last_sync = Instant::now();
+        }
+    }
+}
+
+fn connect_to(shared: &Arc<Shared>, addr: SocketAddr) -> Result<(), NetError> {
+    if addr == shared.local_addr {
+        return Err(NetError::SelfConnection);
+    }
+    if shared.is_connected(&addr) {
+        return Err(NetError::DuplicateConnection);
+    }
+    let stream = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+    let shared = shared.clone();
+    thread::spawn(move || run_peer(shared, stream, addr, true));
+    Ok(())
+}
+
+fn run_peer(shared: Arc<Shared>, stream: TcpStream, addr: SocketAddr, outbound: bool) {
+    let id = shared.next_peer_id.fetch_add(1, Ordering::SeqCst);
+    let setup = || -> Result<(TcpStream, Arc<Peer>), NetError> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(PEER_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let peer = Arc::new(Peer { id, addr, outbound, writer: Mutex::new(stream), state: Mutex::new(PeerState::default()) });
+        Ok((reader, peer))
+    };
+    let Ok((mut reader, peer)) = setup() else {
+        return;
+    };
+
+    {
+        let mut peers = shared.peers.lock().unwrap();
+        if peers.len() >= MAX_PEERS {
+            peer.disconnect();
+            return;
+        }
+        peers.insert(id, peer.clone());
+    }
+
+    let mut result = Ok(());
+    if outbound {
+        result = peer.send(&Message::Version(shared.version()));
+    }
+    while result.is_ok() && shared.running.load(Ordering::SeqCst) {
+        result = read_message(&mut reader).and_then(|message| handle_message(&shared, &peer, message));
+    }
+
+    shared.peers.lock().unwrap().remove(&id);
+    peer.disconnect();
+    shared.sync.lock().unwrap().remove_peer(id);
+    shared.request_blocks();
+}
+
+fn handle_message(shared: &Arc<Shared>, peer: &Arc<Peer>, message: Message) -> Result<(), NetError> {
+    match message {
+        Message::Version(version) => handle_version(shared, peer, version),
+        Message::Verack => {
+            let mut state = peer.state.lock().unwrap();
+            if state.version.is_none() || state.verack {
+                return Err(NetError::Handshake);
+            }
+            state.verack = true;
+            drop(state);
+            peer.send(&Message::GetAddr)?;
+            shared.start_sync(peer)
+        }
+        _ if !peer.is_established() => Err(NetError::Handshake),
+        Message::Ping(nonce) => peer.send(&Message::Pong(nonce)),
+        Message::Pong(_) => Ok(()),
+        Message::NotFound(items) => {
+            let mut sync = shared.sync.lock().unwrap();
+            for item in items {
+                if let Inventory::Block(hash) = item {
+                    sync.cancel_request(&hash);
+                }
+            }
+            Ok(())
+        }
+        Message::GetHeaders(locator) => {
+            let headers = shared.node.lock().unwrap().get_headers(&locator, MAX_HEADERS_PER_MESSAGE);
+            peer.send(&Message::Headers(headers))
+        }
+        Message::Headers(headers) => shared.accept_headers(peer, headers),
+        // New blocks are fetched through their headers, so they are checked
+        // and downloaded the same way as during the initial sync
+        Message::Inv(items) => {
+            let (blocks, transactions): (Vec<Inventory>, Vec<Inventory>) = items.into_iter()
+                .filter(|item| !shared.is_known(item))
+                .partition(|item| matches!(item, Inventory::Block(_)));
+            if !blocks.is_empty() {
+                let locator = shared.sync.lock().unwrap().headers().locator();
+                peer.send(&Message::GetHeaders(locator))?;
+            }
+            if !transactions.is_empty() {
+                peer.send(&Message::GetData(transactions))?;
+            }
+            Ok(())
+        }
+        Message::GetData(items) => {
+            let mut not_found = Vec::new();
+            for item in items {
+                let reply = {
+                    let node = shared.node.lock().unwrap();
+                    match &item {
+                        Inventory::Block(hash) => node.blockchain().get_block(hash).cloned().map(Message::Block),
+                        Inventory::Transaction(txid) => node.mempool().get(txid).map(|entry| Message::Tx(entry.transaction.clone()))
+                    }
+                };
+                match reply {
+                    Some(reply) => peer.send(&reply)?,
+                    None => not_found.push(item)
+                }
+            }
+            if !not_found.is_empty() {
+                peer.send(&Message::NotFound(not_found))?;
+            }
+            Ok(())
+        }
+        Message::Block(block) => {
+            let mut sync = shared.sync.lock().unwrap();
+            if sync.is_requested(&block.hash) {
+                sync.block_received(block);
+                drop(sync);
+                shared.connect_blocks();
+                return Ok(());
+            }
+            drop(sync);
+
+            // A block that was not requested through the sync
+            let hash = block.hash.clone();
+            let mut node = shared.node.lock().unwrap();
+            if node.accept_block(block).is_ok() {
+                shared.sync.lock().unwrap().add_block_header(node.blockchain().get_block(&hash).unwrap());
+                drop(node);
+                shared.broadcast(&Message::Inv(vec![Inventory::Block(hash)]), Some(peer));
+            }
+            Ok(())
+        }
+        Message::Tx(transaction) => {
+            let txid = transaction.hash();
+            let accepted = shared.node.lock().unwrap().add_transaction(transaction).is_ok();
+            if accepted {
+                shared.broadcast(&Message::Inv(vec![Inventory::Transaction(txid)]), Some(peer));
+            }
+            Ok(())
+        }
+        Message::GetAddr => {
+            let addrs = shared.established_peers().iter()
+                .filter(|other| !Arc::ptr_eq(other, peer))
+                .filter_map(|other| other.listen_addr())
+                .take(MAX_ADDR_COUNT)
+                .collect();
+            peer.send(&Message::Addr(addrs))
+        }
+        Message::Addr(addrs) => {
+            for addr in addrs.into_iter().take(MAX_ADDR_COUNT) {
+                if shared.peers.lock().unwrap().len() >= MAX_PEERS {
+                    break;
+                }
+                if addr != shared.local_addr && !shared.is_connected(&addr) {
+                    let shared = shared.clone();
+                    thread::spawn(move || connect_to(&shared, addr));
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+fn handle_version(shared: &Arc<Shared>, peer: &Arc<Peer>, version: Version) -> Result<(), NetError> {
+    if version.version != PROTOCOL_VERSION || peer.state.lock().unwrap().version.is_some() {
+        return Err(NetError::Handshake);
+    }
+    if version.nonce == shared.nonce {
+        return Err(NetError::SelfConnection);
+    }
+
+    // Two nodes that connect to each other at the same time end up with two
+    // connections. Both sides keep the one opened by the node with the lower nonce.
+    let initiator = |outbound: bool| if outbound { shared.nonce } else { version.nonce };
+    let duplicate = shared.peers.lock().unwrap().values()
+        .find(|other| !Arc::ptr_eq(other, peer) && other.nonce() == Some(version.nonce))
+        .cloned();
+    if let Some(other) = duplicate {
+        if other.outbound == peer.outbound || initiator(other.outbound) < initiator(peer.outbound) {
+            return Err(NetError::DuplicateConnection);
+        }
+        other.disconnect();
+    }
+
+    peer.state.lock().unwrap().version = Some(version);
+    if !peer.outbound {
+        peer.send(&Message::Version(shared.version()))?;
+    }
+    peer.send(&Message::Verack)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{blockchain::{consensus::ConsensusParams, storage::tests::temp_dir, Blockchain, MINING_REWARD}, ecdsa};
+    use super::*;
+
+    fn wait_for<F: Fn() -> bool>(condition: F) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(20) {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn test_relay_between_nodes() {
+        let keys = ecdsa::generate_keypair();
+        let blockchain = Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD));
+        let genesis = blockchain.blocks[0].clone();
+        let start = |name: &str, blockchain: Option<Blockchain>| {
+            let blockchain = blockchain.unwrap_or_else(|| {
+                let mut history = Blockchain::empty();
+                history.add_block(genesis.clone()).unwrap();
+                history
+            });
+            Network::start(Node::new(name, blockchain, ecdsa::generate_keypair()), "127.0.0.1:0").unwrap()
+        };
+        let a = start("A", Some(blockchain));
+        let b = start("B", None);
+        let c = start("C", None);
+
+        // C only knows B, but learns about A from it
+        b.connect(a.local_addr()).unwrap();
+        assert!(wait_for(|| a.peer_count() == 1 && b.peer_count() == 1));
+        c.connect(b.local_addr()).unwrap();
+        assert!(wait_for(|| a.peer_count() == 2 && c.peer_count() == 2));
+        assert!(c.peers().contains(&a.local_addr()));
+
+        let block = a.mine(|| false).unwrap().unwrap();
+        assert!(wait_for(|| c.with_node(|node| node.blockchain().blocks.last().unwrap().hash == block.hash)));
+
+        let mut user = crate::user::User::new("User", keys);
+        user.update_funds_from_chain(&a.with_node(|node| node.get_funds_from_chain(&user.public_key)));
+        let tx = user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 10)], 1).unwrap();
+        c.submit_transaction(tx.clone()).unwrap();
+        assert!(wait_for(|| a.with_node(|node| node.mempool().contains(&tx.hash()))));
+        assert!(b.with_node(|node| node.mempool().contains(&tx.hash())));
+    }
+
+    #[test]
+    fn test_initial_sync_and_resume() {
+        let keys = ecdsa::generate_keypair();
+        let blockchain = Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD));
+        let a = Network::start(Node::new("A", blockchain, keys), "127.0.0.1:0").unwrap();
+        for _ in 0..24 {
+            a.mine(|| false).unwrap().unwrap();
+        }
+        let tip = |network: &Network| network.with_node(|node| node.blockchain().blocks.last().map(|block| block.hash.clone()));
+
+        // A node starting from nothing downloads the whole chain, headers first
+        let dir = temp_dir("net-sync");
+        let start = || {
+            let blockchain = Blockchain::open(&dir).unwrap();
+            Network::start(Node::new("B", blockchain, ecdsa::generate_keypair()), "127.0.0.1:0").unwrap()
+        };
+        let b = start();
+        b.connect(a.local_addr()).unwrap();
+        let mut reports = Vec::new();
+        assert!(b.wait_for_sync(Duration::from_secs(60), |progress| reports.push(progress.clone())));
+        assert_eq!(tip(&b), tip(&a));
+        assert_eq!(reports.last().unwrap().blocks, 25);
+        assert!(reports.last().unwrap().is_synced());
+        drop(b);
+
+        // After a restart it continues from the stored chain
+        for _ in 0..3 {
+            a.mine(|| false).unwrap().unwrap();
+        }
+        let b = start();
+        assert_eq!(b.sync_progress().blocks, 25);
+        b.connect(a.local_addr()).unwrap();
+        assert!(b.wait_for_sync(Duration::from_secs(60), |_| {}));
+        assert_eq!(tip(&b), tip(&a));
+        assert_eq!(b.with_node(|node| node.blockchain().verify_chain()), Ok(()));
+        drop(b);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_mining_outside_the_node_lock() {
+        // Practically no nonce is good enough for this difficulty
+        let params = ConsensusParams { initial_difficulty: u64::MAX, ..ConsensusParams::default() };
+        let node = Node::new("Miner", Blockchain::empty_with_params(params), ecdsa::generate_keypair());
+        let network = Network::start(node, "127.0.0.1:0").unwrap();
+
+        // The node can be used during the search, which ends once asked to
+        let checks = std::cell::Cell::new(0);
+        let stop = || {
+            checks.set(checks.get() + 1);
+            network.with_node(|node| node.blockchain().blocks.is_empty()) && checks.get() == 3
+        };
+        assert!(matches!(network.mine(stop), Ok(None)));
+        assert_eq!(checks.get(), 3);
+    }
+
+    #[test]
+    fn test_self_connection() {
+        let keys = ecdsa::generate_keypair();
+        let blockchain = Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD));
+        let network = Network::start(Node::new("Node", blockchain, keys), "127.0.0.1:0").unwrap();
+        assert!(matches!(network.connect(network.local_addr()), Err(NetError::SelfConnection)));
+
+        // Through another address the nonce gives it away
+        let other = SocketAddr::new("127.0.0.2".parse().unwrap(), network.local_addr().port());
+        if network.connect(other).is_ok() {
+            thread::sleep(Duration::from_millis(200));
+            assert_eq!(network.peer_count(), 0);
+        }
+    }
+}
@@ -0,0 +1,248 @@
+use std::{collections::{HashMap, HashSet}, time::{Duration, Instant}};
+use crate::{
+    blockchain::{block::{Block, BlockHeader}, headers::HeaderChain, BlockError, Blockchain},
+    sha256::Sha256
+};
+
+pub const MAX_HEADERS_PER_MESSAGE: usize = 2000;
+pub const MAX_BLOCKS_IN_FLIGHT_PER_PEER: usize = 16;
+
+// A peer that does not deliver a requested block in time is dropped and the
+// block is requested from someone else
+pub const BLOCK_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Blocks are only requested this far past the first missing one, which bounds
+// how many downloaded blocks wait for their parent
+const DOWNLOAD_WINDOW: usize = 1024;
+
+// headers is the length of the best header chain, blocks the length of the
+// active chain and peer_height the longest chain a peer claims to have
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncProgress {
+    pub headers: usize,
+    pub blocks: usize,
+    pub peer_height: usize,
+    pub in_flight: usize,
+}
+
+impl SyncProgress {
+    pub fn is_synced(&self) -> bool {
+        self.blocks >= self.headers && self.blocks >= self.peer_height
+    }
+}
+
+impl std::fmt::Display for SyncProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let target = self.headers.max(self.peer_height);
+        let percent = (self.blocks.min(target) * 100).checked_div(target).unwrap_or(100);
+        write!(f, "blocks {}/{} ({}%), headers {}, {} blocks in flight", self.blocks, target, percent, self.headers, self.in_flight)
+    }
+}
+
+// Headers first download. The header chain is downloaded and checked first,
+// which only needs the proof of work and the difficulty rules. The blocks
+// along the best header chain are then requested from every peer that has
+// them, a few at a time each, and connected in order as they come in.
+//
+// The state starts from the headers of the block tree, so a sync that was
+// interrupted continues from the last connected block of a persisted chain.
+pub struct BlockSync {
+    headers: HeaderChain,
+    peer_heights: HashMap<u64, usize>,
+    in_flight: HashMap<Sha256, (u64, Instant)>,
+    received: HashMap<Sha256, Block>,
+}
+
+impl BlockSync {
+    pub fn new(blockchain: &Blockchain) -> Self {
+        BlockSync {
+            headers: blockchain.headers().clone(),
+            peer_heights: HashMap::new(),
+            in_flight: HashMap::new(),
+            received: HashMap::new(),
+        }
+    }
+
+    // Records the header of a block the blockchain accepted by other means
+    pub fn add_block_header(&mut self, block: &Block) {
+        let _ = self.headers.accept(block.header.clone());
+    }
+
+    // Returns false if the header was already known
+    pub fn accept_header(&mut self, header: BlockHeader) -> Result<bool, BlockError> {
+        self.headers.accept(header)
+    }
+
+    pub fn headers(&self) -> &HeaderChain {
+        &self.headers
+    }
+
+    // The height a peer claims to have, raised by the headers it sends
+    pub fn set_peer_height(&mut self, peer: u64, height: usize) {
+        let known = self.peer_heights.entry(peer).or_insert(0);
+        *known = (*known).max(height);
+    }
+
+    pub fn remove_peer(&mut self, peer: u64) {
+        self.peer_heights.remove(&peer);
+        self.in_flight.retain(|_, (owner, _)| *owner != peer);
+    }
+
+    // Blocks of the best header chain that are not part of the block tree yet,
+    // in the order they have to be connected
+    fn missing_blocks(&self, blockchain: &Blockchain) -> Vec<Sha256> {
+        let Some(best) = self.headers.best_hash() else {
+            return vec![];
+        };
+        let mut missing: Vec<Sha256> = self.headers.ancestors(best)
+            .map(|(hash, _)| hash)
+            .take_while(|hash| !blockchain.contains_block(hash))
+            .cloned()
+            .collect();
+        missing.reverse();
+        missing
+    }
+
+    // Picks the next blocks to request from a peer and marks them as in flight
+    pub fn request_blocks(&mut self, peer: u64, blockchain: &Blockchain) -> Vec<Sha256> {
+        let Some(&peer_height) = self.peer_heights.get(&peer) else {
+            return vec![];
+        };
+        let busy = self.in_flight.values().filter(|(owner, _)| *owner == peer).count();
+        let requests: Vec<Sha256> = self.missing_blocks(blockchain).into_iter()
+            .take(DOWNLOAD_WINDOW)
+            .filter(|hash| self.headers.height(hash).unwrap() < peer_height)
+            .filter(|hash| !self.in_flight.contains_key(hash) && !self.received.contains_key(hash))
+            .take(MAX_BLOCKS_IN_FLIGHT_PER_PEER.saturating_sub(busy))
+            .collect();
+        let now = Instant::now();
+        for hash in &requests {
+            self.in_flight.insert(hash.clone(), (peer, now));
+        }
+        requests
+    }
+
+    // Lets the block be requested again, for example after the peer did not have it
+    pub fn cancel_request(&mut self, hash: &Sha256) {
+        self.in_flight.remove(hash);
+    }
+
+    // Drops requests that took too long and returns the peers that stalled
+    pub fn expire_requests(&mut self) -> HashSet<u64> {
+        let mut stalled = HashSet::new();
+        self.in_flight.retain(|_, (peer, requested)| {
+            let expired = requested.elapsed() >= BLOCK_DOWNLOAD_TIMEOUT;
+            if expired {
+                stalled.insert(*peer);
+            }
+            !expired
+        });
+        stalled
+    }
+
+    pub fn is_requested(&self, hash: &Sha256) -> bool {
+        self.in_flight.contains_key(hash)
+    }
+
+    // Keeps a requested block until it can be connected
+    pub fn block_received(&mut self, block: Block) {
+        self.in_flight.remove(&block.hash);
+        self.received.insert(block.hash.clone(), block);
+    }
+
+    // Downloaded blocks that directly continue the block tree, in order
+    pub fn take_ready(&mut self, blockchain: &Blockchain) -> Vec<Block> {
+        let mut ready = Vec::new();
+        for hash in self.missing_blocks(blockchain) {
+            match self.received.remove(&hash) {
+                Some(block) => ready.push(block),
+                None => break
+            }
+        }
+        ready
+    }
+
+    // Forgets a header whose block turned out to be invalid, together with
+    // every header building on it
+    pub fn invalid_block(&mut self, hash: &Sha256) {
+        for removed in self.headers.remove_branch(hash) {
+            self.in_flight.remove(&removed);
+            self.received.remove(&removed);
+        }
+    }
+
+    pub fn progress(&self, blockchain: &Blockchain) -> SyncProgress {
+        SyncProgress {
+            headers: self.headers.best_len(),
+            blocks: blockchain.blocks.len(),
+            peer_height: self.peer_heights.values().copied().max().unwrap_or(0),
+            in_flight: self.in_flight.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{blockchain::{transaction::Transaction, MINING_REWARD}, ecdsa};
+    use super::*;
+
+    fn mine_chain(blockchain: &mut Blockchain, n: usize) {
+        let (pubkey, _) = ecdsa::generate_keypair();
+        for _ in 0..n {
+            let mut block = blockchain.create_block(Transaction::get_coinbase(pubkey.clone(), MINING_REWARD), vec![]);
+            block.mine();
+            blockchain.add_block(block).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_parallel_download_and_resume() {
+        let mut source = Blockchain::empty();
+        mine_chain(&mut source, 40);
+        let mut blockchain = Blockchain::empty();
+        let mut sync = BlockSync::new(&blockchain);
+        for block in &source.blocks {
+            sync.accept_header(block.header.clone()).unwrap();
+        }
+
+        // Peer 2 only has half of the chain
+        sync.set_peer_height(1, 40);
+        sync.set_peer_height(2, 20);
+        let first = sync.request_blocks(1, &blockchain);
+        let second = sync.request_blocks(2, &blockchain);
+        assert_eq!(first.len(), MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+        assert_eq!(second.len(), 20 - MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+        assert_eq!(first[0], source.blocks[0].hash);
+        assert_eq!(second[0], source.blocks[MAX_BLOCKS_IN_FLIGHT_PER_PEER].hash);
+        assert!(sync.request_blocks(1, &blockchain).is_empty());
+
+        // Blocks arriving out of order wait for their parent
+        let block = |hash: &Sha256| source.get_block(hash).unwrap().clone();
+        sync.block_received(block(&first[1]));
+        assert!(sync.take_ready(&blockchain).is_empty());
+        sync.block_received(block(&first[0]));
+        for block in sync.take_ready(&blockchain) {
+            blockchain.add_block(block).unwrap();
+        }
+        assert_eq!(blockchain.blocks.len(), 2);
+
+        // Peer 1 disconnects, its remaining blocks go to peer 2
+        sync.remove_peer(1);
+        let reassigned = sync.request_blocks(2, &blockchain);
+        assert_eq!(reassigned.len(), MAX_BLOCKS_IN_FLIGHT_PER_PEER - second.len());
+        assert_eq!(reassigned[0], source.blocks[2].hash);
+        assert_eq!(sync.progress(&blockchain).in_flight, MAX_BLOCKS_IN_FLIGHT_PER_PEER);
+
+        // A new sync state continues after the blocks that were connected
+        let mut resumed = BlockSync::new(&blockchain);
+        assert_eq!(resumed.headers().best_len(), 2);
+        for block in &source.blocks {
+            resumed.accept_header(block.header.clone()).unwrap();
+        }
+        resumed.set_peer_height(3, 40);
+        assert_eq!(resumed.request_blocks(3, &blockchain)[0], source.blocks[2].hash);
+        let progress = resumed.progress(&blockchain);
+        assert_eq!((progress.headers, progress.blocks), (40, 2));
+        assert!(!progress.is_synced());
+    }
+}
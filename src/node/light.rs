@@ -0,0 +1,208 @@
+use std::collections::{HashMap, HashSet};
+use crate::{
+    blockchain::{consensus::ConsensusParams, headers::HeaderChain, script::Script, transaction::Transaction, BlockError},
+    ecdsa::{ECDSAPrivateKey, ECDSAPublicKey}, sha256::Sha256, user::User
+};
+use super::Node;
+
+const HEADERS_PER_REQUEST: usize = 2000;
+
+#[derive(Debug, PartialEq)]
+pub enum LightClientError {
+    InvalidHeader(BlockError),
+    UnknownBlock,
+    InvalidProof,
+}
+
+// A transaction and the merkle branch connecting it to the merkle root in the
+// header of the block that contains it
+#[derive(Clone, Debug)]
+pub struct MerkleProof {
+    pub block_hash: Sha256,
+    pub transaction: Transaction,
+    pub branch: Vec<(Sha256, usize)>,
+}
+
+// Selects the transactions that pay to one of the scripts or spend an output
+// that matched earlier. Outputs are added as they match, so a payment that is
+// spent further on in the same scan is followed.
+#[derive(Clone, Debug, Default)]
+pub struct TransactionFilter {
+    scripts: HashSet<Script>,
+    outpoints: HashSet<(Sha256, u32)>,
+}
+
+impl TransactionFilter {
+    pub fn new(scripts: Vec<Script>) -> Self {
+        TransactionFilter { scripts: scripts.into_iter().collect(), outpoints: HashSet::new() }
+    }
+
+    pub fn add_outpoint(&mut self, txid: Sha256, vout: u32) {
+        self.outpoints.insert((txid, vout));
+    }
+
+    pub fn matches(&mut self, tx: &Transaction) -> bool {
+        let spends = tx.inputs.iter().any(|input| self.outpoints.contains(&(input.txid.clone(), input.vout)));
+        let txid = tx.hash();
+        let mut pays = false;
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            if self.scripts.contains(&output.script_pubkey) {
+                self.outpoints.insert((txid.clone(), vout as u32));
+                pays = true;
+            }
+        }
+        spends || pays
+    }
+}
+
+// Simplified payment verification. The client only follows the header chain
+// and lets a full node find the transactions of its wallet. Each of them comes
+// with a merkle proof against a header of the best chain, so the node can leave
+// transactions out but can not make any up without redoing the proof of work.
+pub struct LightClient {
+    pub user: User,
+    headers: HeaderChain,
+    filter: TransactionFilter,
+    utxos: HashMap<(Sha256, u32), u64>,
+    scanned: Option<Sha256>, // Last block the wallet was updated to
+}
+
+impl LightClient {
+    pub fn new(name: &str, keys: (ECDSAPublicKey, ECDSAPrivateKey), params: ConsensusParams) -> Self {
+        let user = User::new(name, keys);
+        let filter = TransactionFilter::new(vec![Script::p2pkh(&user.public_key)]);
+        LightClient {
+            user,
+            headers: HeaderChain::new(params),
+            filter,
+            utxos: HashMap::new(),
+            scanned: None,
+        }
+    }
+
+    pub fn headers(&self) -> &HeaderChain {
+        &self.headers
+    }
+
+    pub fn balance(&self) -> u64 {
+        self.utxos.values().sum()
+    }
+
+    // Downloads the new headers and the transactions in their blocks. Returns
+    // the number of new headers.
+    pub fn sync(&mut self, node: &Node) -> Result<usize, LightClientError> {
+        let new_headers = self.sync_headers(node)?;
+
+        // The wallet is rebuilt if the blocks it was built from left the best chain
+        let best_chain: HashSet<&Sha256> = match self.headers.best_hash() {
+            Some(best) => self.headers.ancestors(best).map(|(hash, _)| hash).collect(),
+            None => HashSet::new()
+        };
+        if self.scanned.as_ref().is_some_and(|scanned| !best_chain.contains(scanned)) {
+            self.utxos.clear();
+            self.filter = TransactionFilter::new(vec![Script::p2pkh(&self.user.public_key)]);
+            self.scanned = None;
+        }
+
+        let from = self.scanned.as_ref().map_or(0, |scanned| self.headers.height(scanned).unwrap() + 1);
+        let proofs = node.get_merkle_proofs(&self.filter, from..self.headers.best_len());
+        for proof in &proofs {
+            if !best_chain.contains(&proof.block_hash) {
+                return Err(LightClientError::UnknownBlock);
+            }
+            self.verify_proof(proof)?;
+        }
+        for proof in proofs {
+            self.apply_transaction(&proof.transaction);
+        }
+        self.scanned = self.headers.best_hash().cloned();
+
+        let funds: Vec<(Sha256, u32, u64)> = self.utxos.iter().map(|((txid, vout), value)| (txid.clone(), *vout, *value)).collect();
+        self.user.update_funds_from_chain(&funds);
+        Ok(new_headers)
+    }
+
+    fn sync_headers(&mut self, node: &Node) -> Result<usize, LightClientError> {
+        let mut new_headers = 0;
+        loop {
+            let headers = node.get_headers(&self.headers.locator(), HEADERS_PER_REQUEST);
+            let received = headers.len();
+            for header in headers {
+                if self.headers.accept(header).map_err(LightClientError::InvalidHeader)? {
+                    new_headers += 1;
+                }
+            }
+            if received < HEADERS_PER_REQUEST {
+                return Ok(new_headers);
+            }
+        }
+    }
+
+    // Checks that the transaction is committed to by a known header. The proof
+    // of work of the header was checked when it entered the header chain.
+    pub fn verify_proof(&self, proof: &MerkleProof) -> Result<(), LightClientError> {
+        let header = self.headers.get(&proof.block_hash).ok_or(LightClientError::UnknownBlock)?;
+        if !self.user.verify_transaction_prescence(proof.transaction.clone(), proof.branch.clone(), header.merkle_root.clone()) {
+            return Err(LightClientError::InvalidProof);
+        }
+        Ok(())
+    }
+
+    fn apply_transaction(&mut self, tx: &Transaction) {
+        if !self.filter.matches(tx) {
+            return;
+        }
+        for input in &tx.inputs {
+            self.utxos.remove(&(input.txid.clone(), input.vout));
+        }
+        let script = Script::p2pkh(&self.user.public_key);
+        let txid = tx.hash();
+        for (vout, output) in tx.outputs.iter().enumerate() {
+            if output.script_pubkey == script {
+                self.utxos.insert((txid.clone(), vout as u32), output.value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{blockchain::{Blockchain, MINING_REWARD}, ecdsa};
+    use super::*;
+
+    #[test]
+    fn test_light_client_wallet() {
+        let keys = ecdsa::generate_keypair();
+        let blockchain = Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD));
+        let mut node = Node::new("Full", blockchain, keys);
+        node.mine().unwrap();
+        node.user.update_funds_from_chain(&node.get_funds_from_chain(&node.user.public_key));
+
+        let mut light = LightClient::new("Light", ecdsa::generate_keypair(), node.blockchain().params().clone());
+        let payment = node.user.try_transaction_with_fee(&[(light.user.public_key.clone(), 30)], 1).unwrap();
+        node.add_transaction(payment.clone()).unwrap();
+        node.mine().unwrap();
+        assert_eq!(light.sync(&node), Ok(3));
+        assert_eq!(light.headers().best_hash(), Some(&node.blockchain().blocks[2].hash));
+        assert_eq!(light.balance(), 30);
+
+        // A proof has to match the merkle root of the header
+        let mut proofs = node.get_merkle_proofs(&TransactionFilter::new(vec![Script::p2pkh(&light.user.public_key)]), 0..3);
+        assert_eq!(proofs.len(), 1);
+        assert_eq!(light.verify_proof(&proofs[0]), Ok(()));
+        proofs[0].transaction.outputs[0].value += 1;
+        assert_eq!(light.verify_proof(&proofs[0]), Err(LightClientError::InvalidProof));
+        proofs[0].block_hash = node.blockchain().blocks[1].hash.clone();
+        proofs[0].transaction = payment;
+        assert_eq!(light.verify_proof(&proofs[0]), Err(LightClientError::InvalidProof));
+
+        // Spending is picked up as well, only the change is left
+        let spend = light.user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 10)], 2).unwrap();
+        node.add_transaction(spend).unwrap();
+        node.mine().unwrap();
+        assert_eq!(light.sync(&node), Ok(1));
+        assert_eq!(light.balance(), 18);
+        assert_eq!(light.user.get_funds(), 18);
+        assert_eq!(light.sync(&node), Ok(0));
+    }
+}
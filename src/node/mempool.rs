@@ -0,0 +1,423 @@
+use std::collections::{HashMap, HashSet};
+use crate::{
+    blockchain::{block::Block, encoding::Encode, transaction::{Transaction, TxOutput}, Blockchain, TransactionError},
+    sha256::Sha256
+};
+
+// Total encoded size of all pooled transactions, in bytes
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 5_000_000;
+
+#[derive(Debug, PartialEq)]
+pub enum MempoolError {
+    AlreadyKnown,
+    FeeTooLow,
+    Invalid(TransactionError),
+}
+
+impl From<TransactionError> for MempoolError {
+    fn from(e: TransactionError) -> Self {
+        MempoolError::Invalid(e)
+    }
+}
+
+// order is increased for every accepted transaction, so sorting by it gives
+// an order in which every parent comes before its children
+#[derive(Clone, Debug)]
+pub struct MempoolEntry {
+    pub transaction: Transaction,
+    pub fee: u64,
+    pub size: usize,
+    order: u64,
+}
+
+impl MempoolEntry {
+    // Compares fee per byte without losing precision
+    fn pays_more_than(&self, other: &MempoolEntry) -> bool {
+        self.fee as u128 * other.size as u128 > other.fee as u128 * self.size as u128
+    }
+}
+
+// Unconfirmed transactions waiting to be mined. The pool keeps its own record
+// of which outputs its transactions spend, the chain state is never touched.
+// Transactions may spend outputs of other pooled transactions.
+pub struct Mempool {
+    entries: HashMap<Sha256, MempoolEntry>,
+    spent: HashMap<(Sha256, u32), Sha256>,
+    total_size: usize,
+    max_size: usize,
+    next_order: u64,
+}
+
+impl Mempool {
+    pub fn new(max_size: usize) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            spent: HashMap::new(),
+            total_size: 0,
+            max_size,
+            next_order: 0,
+        }
+    }
+
+    // Unspent outputs as seen by the pool: the chain's UTXO set plus the
+    // outputs of pooled transactions, minus everything pooled transactions spend
+    fn get_output(&self, blockchain: &Blockchain, txid: &Sha256, vout: u32) -> Option<TxOutput> {
+        if self.spent.contains_key(&(txid.clone(), vout)) {
+            return None;
+        }
+        match self.entries.get(txid) {
+            Some(entry) => entry.transaction.outputs.get(vout as usize).cloned(),
+            None => blockchain.get_unspent_output(txid, vout).cloned()
+        }
+    }
+
+    // Verifies and adds a transaction, returning its fee. When the pool grows
+    // beyond its maximum size the transactions paying the lowest fee per byte
+    // are evicted together with their descendants.
+    pub fn add(&mut self, transaction: Transaction, blockchain: &Blockchain) -> Result<u64, MempoolError> {
+        let txid = transaction.hash();
+        if self.entries.contains_key(&txid) {
+            return Err(MempoolError::AlreadyKnown);
+        }
+        if transaction.is_coinbase() {
+            return Err(MempoolError::Invalid(TransactionError::UnallowedTransaction));
+        }
+        let fee = blockchain.verify_transaction_with(&transaction, true, |id, vout| self.get_output(blockchain, id, vout))?;
+
+        for input in &transaction.inputs {
+            self.spent.insert((input.txid.clone(), input.vout), txid.clone());
+        }
+        let size = transaction.to_bytes().len();
+        self.total_size += size;
+        self.entries.insert(txid.clone(), MempoolEntry { transaction, fee, size, order: self.next_order });
+        self.next_order += 1;
+
+        while self.total_size > self.max_size {
+            let lowest = self.entries.iter()
+                .min_by(|(_, a), (_, b)| {
+                    if b.pays_more_than(a) { std::cmp::Ordering::Less }
+                    else if a.pays_more_than(b) { std::cmp::Ordering::Greater }
+                    else { b.order.cmp(&a.order) }
+                })
+                .map(|(txid, _)| txid.clone())
+                .unwrap();
+            self.remove(&lowest);
+        }
+        if !self.entries.contains_key(&txid) {
+            return Err(MempoolError::FeeTooLow);
+        }
+        Ok(fee)
+    }
+
+    // Removes a transaction and every pooled transaction depending on it
+    pub fn remove(&mut self, txid: &Sha256) -> Vec<Transaction> {
+        let mut removed = Vec::new();
+        let mut pending = vec![txid.clone()];
+        while let Some(txid) = pending.pop() {
+            let entry = match self.entries.remove(&txid) {
+                Some(entry) => entry,
+                None => continue
+            };
+            for input in &entry.transaction.inputs {
+                self.spent.remove(&(input.txid.clone(), input.vout));
+            }
+            for vout in 0..entry.transaction.outputs.len() {
+                if let Some(child) = self.spent.get(&(txid.clone(), vout as u32)) {
+                    pending.push(child.clone());
+                }
+            }
+            self.total_size -= entry.size;
+            removed.push(entry.transaction);
+        }
+        removed
+    }
+
+    // Drops the transactions confirmed by a newly connected block, and every
+    // transaction that conflicts with it by spending the same outputs
+    pub fn remove_for_block(&mut self, block: &Block) {
+        for tx in block.merkle_tree.transactions() {
+            let txid = tx.hash();
+            if let Some(entry) = self.entries.remove(&txid) {
+                // Children of a confirmed transaction stay valid
+                for input in &entry.transaction.inputs {
+                    self.spent.remove(&(input.txid.clone(), input.vout));
+                }
+                self.total_size -= entry.size;
+                continue;
+            }
+            for input in &tx.inputs {
+                if let Some(conflict) = self.spent.get(&(input.txid.clone(), input.vout)).cloned() {
+                    self.remove(&conflict);
+                }
+            }
+        }
+    }
+
+    // After a reorg the pool is rebuilt on top of the new chain. Transactions
+    // from the disconnected blocks are added back first, as pooled
+    // transactions may spend their outputs. Anything no longer valid is dropped.
+    pub fn reorganize(&mut self, disconnected: &[Block], blockchain: &Blockchain) {
+        let mut entries: Vec<MempoolEntry> = self.entries.drain().map(|(_, entry)| entry).collect();
+        entries.sort_by_key(|entry| entry.order);
+        self.spent.clear();
+        self.total_size = 0;
+
+        for block in disconnected.iter().rev() {
+            for tx in block.merkle_tree.transactions() {
+                if !tx.is_coinbase() {
+                    let _ = self.add(tx.clone(), blockchain);
+                }
+            }
+        }
+        for entry in entries {
+            let _ = self.add(entry.transaction, blockchain);
+        }
+    }
+
+    // Transactions for a new block of at most max_size bytes of transactions,
+    // highest fee per byte first. A transaction is only picked once all of its
+    // pooled parents have been picked, one that does not fit is left out
+    // together with its descendants.
+    pub fn select_for_block(&self, max_size: usize) -> Vec<&MempoolEntry> {
+        let mut candidates: Vec<(&Sha256, &MempoolEntry)> = self.entries.iter().collect();
+        candidates.sort_by(|(_, a), (_, b)| {
+            if a.pays_more_than(b) { std::cmp::Ordering::Less }
+            else if b.pays_more_than(a) { std::cmp::Ordering::Greater }
+            else { a.order.cmp(&b.order) }
+        });
+
+        let mut selected = Vec::with_capacity(candidates.len());
+        let mut included: HashSet<&Sha256> = HashSet::new();
+        let mut excluded: HashSet<&Sha256> = HashSet::new();
+        let mut size = 0;
+        while !candidates.is_empty() {
+            let next = candidates.iter().position(|(_, entry)| {
+                entry.transaction.inputs.iter().all(|input| {
+                    !self.entries.contains_key(&input.txid) || included.contains(&input.txid) || excluded.contains(&input.txid)
+                })
+            }).unwrap();
+            let (txid, entry) = candidates.remove(next);
+            let has_excluded_parent = entry.transaction.inputs.iter().any(|input| excluded.contains(&input.txid));
+            if has_excluded_parent || size + entry.size > max_size {
+                excluded.insert(txid);
+                continue;
+            }
+            size += entry.size;
+            included.insert(txid);
+            selected.push(entry);
+        }
+        selected
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.spent.clear();
+        self.total_size = 0;
+    }
+
+    pub fn contains(&self, txid: &Sha256) -> bool {
+        self.entries.contains_key(txid)
+    }
+
+    pub fn get(&self, txid: &Sha256) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn size(&self) -> usize {
+        self.total_size
+    }
+
+    pub fn total_fees(&self) -> u64 {
+        self.entries.values().map(|entry| entry.fee).sum()
+    }
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(DEFAULT_MAX_MEMPOOL_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        blockchain::{script::Script, transaction::{TxInput, SEQUENCE_FINAL}, BlockStatus, MINING_REWARD},
+        ecdsa, user::User
+    };
+    use super::*;
+
+    fn funded_user() -> (User, Blockchain) {
+        let keys = ecdsa::generate_keypair();
+        let blockchain = Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD));
+        let mut user = User::new("User", keys);
+        user.update_funds_from_chain(&blockchain.get_user_funds(&user.public_key));
+        (user, blockchain)
+    }
+
+    // Mines a block paying a new user
+    fn fund_new_user(blockchain: &mut Blockchain, name: &str) -> User {
+        let keys = ecdsa::generate_keypair();
+        let coinbase = Transaction::get_coinbase(keys.0.clone(), blockchain.next_block_subsidy());
+        let mut block = blockchain.create_block(coinbase, vec![]);
+        block.mine();
+        blockchain.add_block(block).unwrap();
+        let mut user = User::new(name, keys);
+        user.update_funds_from_chain(&blockchain.get_user_funds(&user.public_key));
+        user
+    }
+
+    #[test]
+    fn test_unconfirmed_chain() {
+        let (mut user, mut blockchain) = funded_user();
+        let mut mempool = Mempool::default();
+
+        let tx1 = user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 20)], 2).unwrap();
+        user.update_funds(&tx1);
+        let tx2 = user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 10)], 3).unwrap();
+        assert_eq!(tx2.inputs[0].txid, tx1.hash());
+
+        // The child can not enter before its parent
+        assert_eq!(mempool.add(tx2.clone(), &blockchain), Err(MempoolError::Invalid(TransactionError::InsufficientFunds)));
+        assert_eq!(mempool.add(tx1.clone(), &blockchain), Ok(2));
+        assert_eq!(mempool.add(tx2.clone(), &blockchain), Ok(3));
+        assert_eq!(mempool.add(tx2.clone(), &blockchain), Err(MempoolError::AlreadyKnown));
+        assert!(blockchain.get_unspent_output(&tx1.inputs[0].txid, 0).is_some());
+
+        // The child pays more per byte but still has to follow its parent
+        let selected: Vec<Sha256> = mempool.select_for_block(usize::MAX).iter().map(|e| e.transaction.hash()).collect();
+        assert_eq!(selected, vec![tx1.hash(), tx2.hash()]);
+        let size = |tx: &Transaction| mempool.get(&tx.hash()).unwrap().size;
+        let selected: Vec<Sha256> = mempool.select_for_block(size(&tx1) + size(&tx2) - 1).iter().map(|e| e.transaction.hash()).collect();
+        assert_eq!(selected, vec![tx1.hash()]);
+        // Without room for the parent the child is left out too
+        assert!(mempool.select_for_block(size(&tx1) - 1).is_empty());
+
+        let coinbase = Transaction::get_coinbase(user.public_key.clone(), MINING_REWARD + 5);
+        let mut block = blockchain.create_block(coinbase, vec![tx1, tx2]);
+        block.mine();
+        blockchain.add_block(block.clone()).unwrap();
+        mempool.remove_for_block(&block);
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.size(), 0);
+    }
+
+    #[test]
+    fn test_fee_rate_order_and_conflicts() {
+        let (user, mut blockchain) = funded_user();
+        let other = fund_new_user(&mut blockchain, "Other");
+
+        let mut mempool = Mempool::default();
+        let cheap = user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 10)], 1).unwrap();
+        let expensive = other.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 10)], 9).unwrap();
+        mempool.add(cheap.clone(), &blockchain).unwrap();
+        mempool.add(expensive.clone(), &blockchain).unwrap();
+        let selected: Vec<Sha256> = mempool.select_for_block(usize::MAX).iter().map(|e| e.transaction.hash()).collect();
+        assert_eq!(selected, vec![expensive.hash(), cheap.hash()]);
+        let limit = mempool.get(&expensive.hash()).unwrap().size;
+        let selected: Vec<Sha256> = mempool.select_for_block(limit).iter().map(|e| e.transaction.hash()).collect();
+        assert_eq!(selected, vec![expensive.hash()]);
+
+        // A block spending the same output as a pooled transaction evicts it
+        let conflict = user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 40)], 1).unwrap();
+        assert_eq!(mempool.add(conflict.clone(), &blockchain), Err(MempoolError::Invalid(TransactionError::InsufficientFunds)));
+        let coinbase = Transaction::get_coinbase(user.public_key.clone(), MINING_REWARD + 1);
+        let mut block = blockchain.create_block(coinbase, vec![conflict]);
+        block.mine();
+        blockchain.add_block(block.clone()).unwrap();
+        mempool.remove_for_block(&block);
+        assert!(!mempool.contains(&cheap.hash()));
+        assert!(mempool.contains(&expensive.hash()));
+    }
+
+    #[test]
+    fn test_eviction() {
+        let (mut user, mut blockchain) = funded_user();
+        let rich = fund_new_user(&mut blockchain, "Rich");
+        let other = fund_new_user(&mut blockchain, "Other");
+
+        let tx1 = user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 20)], 1).unwrap();
+        user.update_funds(&tx1);
+        let tx2 = user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 10)], 5).unwrap();
+
+        // Room for exactly two transactions
+        let mut mempool = Mempool::new(tx1.to_bytes().len() + tx2.to_bytes().len());
+        mempool.add(tx1.clone(), &blockchain).unwrap();
+        mempool.add(tx2.clone(), &blockchain).unwrap();
+
+        // Evicting the parent takes the child with it
+        let expensive = rich.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 10)], 20).unwrap();
+        assert_eq!(mempool.add(expensive.clone(), &blockchain), Ok(20));
+        assert!(mempool.contains(&expensive.hash()));
+        assert!(!mempool.contains(&tx1.hash()));
+        assert!(!mempool.contains(&tx2.hash()));
+
+        // A transaction paying too little to make room is refused
+        let medium = other.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 10)], 10).unwrap();
+        mempool.add(medium, &blockchain).unwrap();
+        assert_eq!(mempool.add(tx1.clone(), &blockchain), Err(MempoolError::FeeTooLow));
+        assert_eq!(mempool.len(), 2);
+        assert_eq!(mempool.total_fees(), 30);
+    }
+
+    #[test]
+    fn test_lock_time_acceptance() {
+        let (miner, _) = ecdsa::generate_keypair();
+        let mut coinbase = Transaction::get_coinbase(miner.clone(), MINING_REWARD);
+        coinbase.outputs[0].script_pubkey = Script::new().push_number(1);
+        let blockchain = Blockchain::new(coinbase);
+        let mut mempool = Mempool::default();
+
+        let mut tx = Transaction::new();
+        let txid = blockchain.blocks[0].merkle_tree.transactions()[0].hash();
+        tx.add_input(TxInput { txid, vout: 0, script_sig: Script::new(), sequence: 0 });
+        tx.add_output(TxOutput { value: MINING_REWARD, script_pubkey: Script::p2pkh(&miner), spent: false });
+        tx.lock_time = 5;
+        assert_eq!(mempool.add(tx.clone(), &blockchain), Err(MempoolError::Invalid(TransactionError::LockTimeNotReached)));
+
+        tx.inputs[0].sequence = 2;
+        tx.lock_time = 0;
+        assert_eq!(mempool.add(tx.clone(), &blockchain), Err(MempoolError::Invalid(TransactionError::SequenceLockNotReached)));
+        tx.inputs[0].sequence = SEQUENCE_FINAL;
+        assert_eq!(mempool.add(tx, &blockchain), Ok(0));
+    }
+
+    #[test]
+    fn test_reorganize_readds_transactions() {
+        let (user, mut blockchain) = funded_user();
+        let genesis = blockchain.blocks[0].hash.clone();
+        let mut mempool = Mempool::default();
+
+        let tx = user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 10)], 4).unwrap();
+        mempool.add(tx.clone(), &blockchain).unwrap();
+        let coinbase = Transaction::get_coinbase(user.public_key.clone(), MINING_REWARD + 4);
+        let mut block = blockchain.create_block(coinbase, vec![tx.clone()]);
+        block.mine();
+        blockchain.add_block(block.clone()).unwrap();
+        mempool.remove_for_block(&block);
+        assert!(mempool.is_empty());
+
+        // A longer branch without the transaction takes over
+        let miner = ecdsa::generate_keypair().0;
+        let mut fork = Block::new(genesis, vec![Transaction::get_coinbase(miner.clone(), MINING_REWARD)]);
+        fork.mine();
+        blockchain.add_block(fork.clone()).unwrap();
+        let mut fork2 = Block::new(fork.hash.clone(), vec![Transaction::get_coinbase(miner, MINING_REWARD)]);
+        fork2.mine();
+        let disconnected = match blockchain.add_block(fork2) {
+            Ok(BlockStatus::Reorg { disconnected }) => disconnected,
+            other => panic!("Expected a reorg, got {:?}", other)
+        };
+
+        mempool.reorganize(&disconnected, &blockchain);
+        assert!(mempool.contains(&tx.hash()));
+        assert_eq!(mempool.get(&tx.hash()).unwrap().fee, 4);
+    }
+}
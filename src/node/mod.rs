@@ -1,11 +1,18 @@
+use std::ops::Range;
 use crate::{
-    blockchain::{block::Block, transaction::Transaction, BlockError, Blockchain, TransactionError, MINING_REWARD}, 
+    blockchain::{block::{Block, BlockHeader, MAX_BLOCK_SIZE}, encoding::Encode, transaction::Transaction, BlockError, BlockStatus, Blockchain},
     ecdsa::{ECDSAPrivateKey, ECDSAPublicKey}, sha256::Sha256, user::User
 };
+use light::{MerkleProof, TransactionFilter};
+use mempool::{Mempool, MempoolError};
+
+pub mod light;
+pub mod mempool;
 
 pub struct Node {
     blockchain: Blockchain,
-    current_transactions: Vec<Transaction>,
+    mempool: Mempool,
+    miner_key: ECDSAPublicKey, // Receives the coinbase of mined blocks
     pub user: User
 }
 
@@ -13,70 +20,80 @@ impl Node {
     pub fn new(name: &str, history: Blockchain, keys: (ECDSAPublicKey, ECDSAPrivateKey)) -> Self {
         Node {
             blockchain: history,
-            current_transactions: Vec::new(),
+            mempool: Mempool::default(),
+            miner_key: keys.0.clone(),
             user: User::new(name, keys),
         }
     }
 
-    pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
-        self.blockchain.verify_new_transaction(&transaction)?;
-        for input in &transaction.inputs {
-            self.blockchain.set_output_spent(&input.txid, input.vout, true);
-        }
-        self.current_transactions.push(transaction);
+    // Pays the rewards of the blocks mined from now on to another key than the node's own
+    pub fn set_miner_key(&mut self, miner_key: ECDSAPublicKey) {
+        self.miner_key = miner_key;
+    }
+
+    pub fn add_transaction(&mut self, transaction: Transaction) -> Result<(), MempoolError> {
+        self.mempool.add(transaction, &self.blockchain)?;
         Ok(())
     }
 
+    // Also removes every pending transaction spending outputs of this one
     pub fn remove_transaction(&mut self, txid: &Sha256) -> Result<(), ()> {
-        if let Some(pos) = self.current_transactions.iter().position(|tx| tx.hash() == *txid) {
-            let transaction = self.current_transactions.remove(pos);
-            for input in &transaction.inputs {
-                self.blockchain.set_output_spent(&input.txid, input.vout, false);
-            }
-            Ok(())
-        } else {
+        if self.mempool.remove(txid).is_empty() {
             Err(())
+        } else {
+            Ok(())
         }
     }
 
     pub fn clear_current_transactions(&mut self) {
-        for tx in self.current_transactions.clone() {
-            let _ = self.remove_transaction(&tx.hash());
-        }
+        self.mempool.clear();
     }
 
-    pub fn mine(&mut self) -> Block {
-        let coinbase = Transaction::get_coinbase(self.user.public_key.clone(), MINING_REWARD);
-        let mut block = self.blockchain.create_block(coinbase.clone(), self.current_transactions.clone());
-        block.mine();
-        self.clear_current_transactions();
-        self.blockchain.add_block(block.clone()).unwrap();
-        self.user.update_funds(&coinbase);
-        block
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
     }
 
-    pub fn accept_block(&mut self, block: Block) -> Result<(), BlockError> {
-        let transactions = block.merkle_tree.transactions();
+    pub fn blockchain(&self) -> &Blockchain {
+        &self.blockchain
+    }
 
-        // Remove confirmed transactions from current transactions
-        for tx in block.merkle_tree.transactions() {
-            if self.current_transactions.iter().any(|t| t.hash() == tx.hash()) {
-                self.remove_transaction(&tx.hash()).unwrap();
-            }
-        }
+    pub fn mine(&mut self) -> Result<Block, BlockError> {
+        let mut block = self.block_template()?;
+        block.mine();
+        self.accept_mined_block(block.clone())?;
+        Ok(block)
+    }
 
-        let res = self.blockchain.add_block(block.clone());
+    // The next block on top of the active chain, still to be mined. The
+    // coinbase claims the block subsidy and the fees of every included
+    // transaction. Fails if the reward does not fit in an output value.
+    pub fn block_template(&self) -> Result<Block, BlockError> {
+        // The coinbase has the same size whatever it pays
+        let coinbase = Transaction::get_coinbase(self.miner_key.clone(), 0);
+        let base_size = self.blockchain.create_block(coinbase, vec![]).to_bytes().len();
+        let selected = self.mempool.select_for_block(MAX_BLOCK_SIZE.saturating_sub(base_size));
+        let fees = selected.iter().try_fold(0u64, |fees, entry| fees.checked_add(entry.fee));
+        let reward = fees.and_then(|fees| self.blockchain.next_block_subsidy().checked_add(fees)).ok_or(BlockError::InvalidCoinbase)?;
+        let transactions = selected.into_iter().map(|entry| entry.transaction.clone()).collect();
+        let coinbase = Transaction::get_coinbase(self.miner_key.clone(), reward);
+        Ok(self.blockchain.create_block(coinbase, transactions))
+    }
 
-        if res.is_err() {
-            // Add all transactions back to current transactions
-            for tx in transactions {
-                self.current_transactions.push(tx.clone());
-            }
+    // A block mined from block_template, whose coinbase may pay the node's user
+    pub fn accept_mined_block(&mut self, block: Block) -> Result<BlockStatus, BlockError> {
+        let status = self.accept_block(block.clone())?;
+        self.user.update_funds(&block.merkle_tree.transactions()[0]);
+        Ok(status)
+    }
 
-            return Err(res.err().unwrap());
+    pub fn accept_block(&mut self, block: Block) -> Result<BlockStatus, BlockError> {
+        let status = self.blockchain.add_block(block.clone())?;
+        match &status {
+            BlockStatus::Extended => self.mempool.remove_for_block(&block),
+            BlockStatus::Fork => {},
+            BlockStatus::Reorg { disconnected } => self.mempool.reorganize(disconnected, &self.blockchain)
         }
-
-        Ok(())
+        Ok(status)
     }
 
     pub fn is_transaction_confirmed(&self, tx: &Transaction) -> bool {
@@ -87,6 +104,31 @@ impl Node {
         self.blockchain.get_user_funds(user)
     }
 
+    // At most max headers of the active chain after the locator, see HeaderChain::locator
+    pub fn get_headers(&self, locator: &[Sha256], max: usize) -> Vec<BlockHeader> {
+        self.blockchain.headers_after(locator, max)
+    }
+
+    // Proofs for the transactions in the active chain blocks at heights that
+    // match the filter, in chain order
+    pub fn get_merkle_proofs(&self, filter: &TransactionFilter, heights: Range<usize>) -> Vec<MerkleProof> {
+        let mut filter = filter.clone();
+        let mut proofs = Vec::new();
+        let end = heights.end.min(self.blockchain.blocks.len());
+        for block in self.blockchain.blocks.get(heights.start..end).unwrap_or_default() {
+            for tx in block.merkle_tree.transactions() {
+                if filter.matches(tx) {
+                    proofs.push(MerkleProof {
+                        block_hash: block.hash.clone(),
+                        transaction: tx.clone(),
+                        branch: block.merkle_tree.get_branch_hashes(tx.clone()).unwrap(),
+                    });
+                }
+            }
+        }
+        proofs
+    }
+
     // Might need to find the block in another way in the future
     pub fn get_verifiyng_transaction_branch(&self, tx: Transaction, block_idx: usize) -> Option<Vec<(Sha256, usize)>> {
         if block_idx >= self.blockchain.blocks.len() {
@@ -98,7 +140,7 @@ impl Node {
 
 #[cfg(test)]
 mod tests {
-    use crate::{ecdsa, user::Fund};
+    use crate::{blockchain::{consensus::ConsensusParams, TransactionError, MINING_REWARD}, ecdsa, user::Fund};
 
     use super::*;
 
@@ -116,13 +158,20 @@ mod tests {
         let blockchain = Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD));
         let mut node = Node::new("Miner", blockchain, keys);
         
-        let block = node.mine();
+        let block = node.mine().unwrap();
         assert_eq!(block.merkle_tree.transactions().len(), 1);
         assert_eq!(block.merkle_tree.transactions()[0].outputs[0].value, MINING_REWARD);
         assert!(node.user.get_funds() == MINING_REWARD); // Previous is ignored if not queried
 
         node.user.update_funds_from_chain(&node.get_funds_from_chain(&node.user.public_key));
         assert!(node.user.get_funds() == 2 * MINING_REWARD);
+
+        // The rewards can go to a separate key
+        let (miner_key, _) = ecdsa::generate_keypair();
+        node.set_miner_key(miner_key.clone());
+        node.mine().unwrap();
+        assert_eq!(node.user.get_funds(), 2 * MINING_REWARD);
+        assert_eq!(node.get_funds_from_chain(&miner_key).len(), 1);
     }
 
     #[test]
@@ -140,7 +189,7 @@ mod tests {
 
         // Verify chain can only be called with no current transactions
         // Mining the block will ensure this
-        node.mine();
+        node.mine().unwrap();
         assert_eq!(node.blockchain.verify_chain(), Ok(()));
     }
 
@@ -161,7 +210,7 @@ mod tests {
         let recievers = vec![(recipient_keys.0, 3 * MINING_REWARD)]; // More than available funds
         let transaction = node.user.try_transaction(&recievers).unwrap();
         
-        assert_eq!(node.add_transaction(transaction), Err(TransactionError::InsufficientFunds));
+        assert_eq!(node.add_transaction(transaction), Err(MempoolError::Invalid(TransactionError::InsufficientFunds)));
 
         // Ensure the transaction was not added to the blockchain
         assert_eq!(node.blockchain.verify_chain(), Ok(()));
@@ -184,9 +233,9 @@ mod tests {
         let transaction2 = node.user.try_transaction(&recievers2).unwrap();
         
         assert!(node.add_transaction(transaction1).is_ok());
-        assert_eq!(node.add_transaction(transaction2), Err(TransactionError::InsufficientFunds));
+        assert_eq!(node.add_transaction(transaction2), Err(MempoolError::Invalid(TransactionError::InsufficientFunds)));
 
-        node.mine();
+        node.mine().unwrap();
 
         // Ensure the first transaction was mined and the second was not added
         // Check the entire chain
@@ -207,7 +256,7 @@ mod tests {
         
         assert!(node.add_transaction(transaction).is_ok());
         
-        node.mine();
+        node.mine().unwrap();
         
         // Now only two unspent transactions should remain: the second coinbase and the transaction to the recipient
         assert_eq!(node.blockchain.get_utxo().len(), 2);
@@ -231,7 +280,7 @@ mod tests {
         let input = transaction.inputs[0].clone();
         transaction.add_input(input);
 
-        assert_eq!(node.add_transaction(transaction), Err(TransactionError::InvalidSignature));
+        assert_eq!(node.add_transaction(transaction), Err(MempoolError::Invalid(TransactionError::InvalidSignature)));
     }
 
     #[test]
@@ -247,11 +296,79 @@ mod tests {
         let transaction = node.user.try_transaction(&recievers).unwrap();
 
         assert!(node.add_transaction(transaction).is_ok());
-        node.mine();
+        node.mine().unwrap();
 
         // Manually change the blockchain to create an invalid state
-        node.blockchain.blocks[0].nonce += 1;
+        node.blockchain.blocks[0].header.nonce += 1;
 
         assert_eq!(node.blockchain.verify_chain(), Err(BlockError::InvalidHash));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_accept_competing_blocks() {
+        let keys = ecdsa::generate_keypair();
+        let blockchain = Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD));
+        let mut history = Blockchain::empty();
+        history.add_block(blockchain.blocks[0].clone()).unwrap();
+        let mut node1 = Node::new("Node1", blockchain, keys);
+        let mut node2 = Node::new("Node2", history, ecdsa::generate_keypair());
+
+        node1.mine().unwrap();
+        let block1 = node2.mine().unwrap();
+        let block2 = node2.mine().unwrap();
+
+        assert!(matches!(node1.accept_block(block1), Ok(BlockStatus::Fork)));
+        assert!(matches!(node1.accept_block(block2.clone()), Ok(BlockStatus::Reorg { .. })));
+        assert_eq!(node1.blockchain.blocks.last().unwrap().hash, block2.hash);
+        assert_eq!(node1.blockchain.verify_chain(), Ok(()));
+    }
+
+    #[test]
+    fn test_mining_collects_fees() {
+        let keys = ecdsa::generate_keypair();
+        let blockchain = Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD));
+        let mut node = Node::new("Miner", blockchain, keys);
+        node.user.update_funds_from_chain(&node.get_funds_from_chain(&node.user.public_key));
+
+        let recipient = ecdsa::generate_keypair().0;
+        let transaction = node.user.try_transaction_with_fee(&[(recipient.clone(), 30)], 5).unwrap();
+        assert_eq!(transaction.outputs[1].value, MINING_REWARD - 35);
+        assert_eq!(node.blockchain.verify_new_transaction(&transaction), Ok(5));
+        node.add_transaction(transaction).unwrap();
+
+        let block = node.mine().unwrap();
+        assert_eq!(block.merkle_tree.transactions()[0].outputs[0].value, MINING_REWARD + 5);
+        assert_eq!(node.get_funds_from_chain(&recipient)[0].2, 30);
+        assert_eq!(node.blockchain.verify_chain(), Ok(()));
+    }
+
+    #[test]
+    fn test_coinbase_claiming_too_much() {
+        let keys = ecdsa::generate_keypair();
+        let blockchain = Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD));
+        let mut node = Node::new("Miner", blockchain, keys);
+        node.user.update_funds_from_chain(&node.get_funds_from_chain(&node.user.public_key));
+
+        let transaction = node.user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 30)], 5).unwrap();
+        let coinbase = Transaction::get_coinbase(node.user.public_key.clone(), MINING_REWARD + 6);
+        let mut block = node.blockchain.create_block(coinbase, vec![transaction]);
+        block.mine();
+        assert_eq!(node.accept_block(block).err(), Some(BlockError::InvalidCoinbase));
+    }
+
+    #[test]
+    fn test_mining_with_overflowing_fees() {
+        let keys = ecdsa::generate_keypair();
+        let params = ConsensusParams { initial_reward: u64::MAX, ..ConsensusParams::default() };
+        let blockchain = Blockchain::new_with_params(Transaction::get_coinbase(keys.0.clone(), u64::MAX), params);
+        let mut node = Node::new("Miner", blockchain, keys);
+        node.user.update_funds_from_chain(&node.get_funds_from_chain(&node.user.public_key));
+
+        // The subsidy and the fees do not fit in the coinbase
+        let transaction = node.user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 30)], 5).unwrap();
+        node.add_transaction(transaction).unwrap();
+        assert_eq!(node.mine().err(), Some(BlockError::InvalidCoinbase));
+        assert_eq!(node.blockchain().blocks.len(), 1);
+        assert_eq!(node.mempool().len(), 1);
+    }
+}
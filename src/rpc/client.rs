@@ -0,0 +1,84 @@
+use std::net::SocketAddr;
+use super::{http::{self, HttpError}, json::Json, RpcError};
+
+#[derive(Debug)]
+pub enum RpcClientError {
+    Http(HttpError),
+    Status(u16),
+    InvalidResponse,
+    Rpc(RpcError),
+}
+
+impl From<HttpError> for RpcClientError {
+    fn from(e: HttpError) -> Self {
+        RpcClientError::Http(e)
+    }
+}
+
+// Calls the methods of an RpcServer, one connection per call
+pub struct RpcClient {
+    addr: SocketAddr,
+    next_id: u64,
+}
+
+impl RpcClient {
+    pub fn new(addr: SocketAddr) -> Self {
+        RpcClient { addr, next_id: 0 }
+    }
+
+    pub fn call(&mut self, method: &str, params: Vec<Json>) -> Result<Json, RpcClientError> {
+        self.next_id += 1;
+        let request = Json::object([
+            ("jsonrpc", "2.0".into()),
+            ("method", method.into()),
+            ("params", params.into()),
+            ("id", self.next_id.into()),
+        ]);
+        let (status, body) = http::post(self.addr, &request.to_string())?;
+        if status != 200 {
+            return Err(RpcClientError::Status(status));
+        }
+        let response = Json::parse(&body).map_err(|_| RpcClientError::InvalidResponse)?;
+        if response.get("id").and_then(Json::as_u64) != Some(self.next_id) {
+            return Err(RpcClientError::InvalidResponse);
+        }
+        if let Some(error) = response.get("error") {
+            let (Some(Json::Int(code)), Some(message)) = (error.get("code"), error.get("message").and_then(Json::as_str)) else {
+                return Err(RpcClientError::InvalidResponse);
+            };
+            return Err(RpcClientError::Rpc(RpcError {
+                code: *code as i64,
+                message: message.to_string(),
+                data: error.get("data").cloned(),
+            }));
+        }
+        response.get("result").cloned().ok_or(RpcClientError::InvalidResponse)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::{
+        blockchain::{transaction::Transaction, Blockchain, MINING_REWARD},
+        ecdsa, node::Node, rpc::{RpcServer, INVALID_PARAMS, METHOD_NOT_FOUND}
+    };
+    use super::*;
+
+    #[test]
+    fn test_rpc_client() {
+        let keys = ecdsa::generate_keypair();
+        let blockchain = Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD));
+        let handle = Arc::new(Mutex::new(Node::new("Node", blockchain, keys)));
+        let server = RpcServer::start(handle, "127.0.0.1:0").unwrap();
+
+        let mut client = RpcClient::new(server.local_addr());
+        let info = client.call("getchaininfo", vec![]).unwrap();
+        assert_eq!(info.get("blocks").and_then(Json::as_u64), Some(1));
+        assert!(matches!(client.call("unknown", vec![]), Err(RpcClientError::Rpc(RpcError { code: METHOD_NOT_FOUND, .. }))));
+        match client.call("getblock", vec!["zz".into()]) {
+            Err(RpcClientError::Rpc(error)) => assert_eq!((error.code, error.data), (INVALID_PARAMS, None)),
+            result => panic!("unexpected {:?}", result)
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    time::Duration
+};
+
+// Requests with a larger body are refused before it is read
+pub const MAX_BODY_SIZE: usize = 4_000_000;
+// Responses can be much larger than requests, getblock returns a whole block
+// hex encoded at twice its size. The limit only guards the client against a
+// server that never stops sending.
+pub const MAX_RESPONSE_SIZE: usize = 64_000_000;
+const MAX_HEADER_LINES: usize = 100;
+const MAX_LINE_LENGTH: usize = 8192;
+
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    Io(io::Error),
+    Malformed,
+    BodyTooLarge,
+}
+
+impl From<io::Error> for HttpError {
+    fn from(e: io::Error) -> Self {
+        HttpError::Io(e)
+    }
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, HttpError> {
+    let mut line = Vec::new();
+    reader.take(MAX_LINE_LENGTH as u64).read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(HttpError::Malformed);
+    }
+    let line = String::from_utf8(line).map_err(|_| HttpError::Malformed)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Reads the header lines up to the empty line and returns the content length
+fn read_headers<R: BufRead>(reader: &mut R) -> Result<usize, HttpError> {
+    let mut content_length = 0;
+    for _ in 0..MAX_HEADER_LINES {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            return Ok(content_length);
+        }
+        let (name, value) = line.split_once(':').ok_or(HttpError::Malformed)?;
+        if name.trim().eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().map_err(|_| HttpError::Malformed)?;
+        }
+    }
+    Err(HttpError::Malformed)
+}
+
+fn read_body<R: BufRead>(reader: &mut R, content_length: usize, max_size: usize) -> Result<Vec<u8>, HttpError> {
+    if content_length > max_size {
+        return Err(HttpError::BodyTooLarge);
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(body)
+}
+
+// Only what a JSON-RPC client sends is supported, a single request with a
+// Content-Length and no chunked encoding
+pub fn read_request<R: Read>(stream: R) -> Result<Request, HttpError> {
+    let mut reader = BufReader::new(stream);
+    let request_line = read_line(&mut reader)?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(path), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(HttpError::Malformed);
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(HttpError::Malformed);
+    }
+    let (method, path) = (method.to_string(), path.to_string());
+    let content_length = read_headers(&mut reader)?;
+    let body = read_body(&mut reader, content_length, MAX_BODY_SIZE)?;
+    Ok(Request { method, path, body })
+}
+
+// Every response closes the connection
+pub fn write_response<W: Write>(mut stream: W, status: u16, body: &str) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error"
+    };
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, reason, body.len(), body)?;
+    stream.flush()
+}
+
+// Sends body in a POST request and returns the status and body of the response
+pub fn post(addr: SocketAddr, body: &str) -> Result<(u16, String), HttpError> {
+    let mut stream = TcpStream::connect_timeout(&addr, REQUEST_TIMEOUT)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    write!(stream, "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        addr, body.len(), body)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let status_line = read_line(&mut reader)?;
+    let status = status_line.split(' ').nth(1).and_then(|status| status.parse().ok()).ok_or(HttpError::Malformed)?;
+    let content_length = read_headers(&mut reader)?;
+    let body = read_body(&mut reader, content_length, MAX_RESPONSE_SIZE)?;
+    Ok((status, String::from_utf8(body).map_err(|_| HttpError::Malformed)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use super::*;
+
+    #[test]
+    fn test_response_larger_than_request_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let request = read_request(&stream).unwrap();
+            assert_eq!(request.body, b"{}");
+            write_response(&stream, 200, &"0".repeat(MAX_BODY_SIZE + 1)).unwrap();
+        });
+
+        let (status, body) = post(addr, "{}").unwrap();
+        assert_eq!(status, 200);
+        assert_eq!(body.len(), MAX_BODY_SIZE + 1);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,410 @@
+// Nesting deeper than this is rejected instead of overflowing the stack
+const MAX_DEPTH: usize = 64;
+
+// Integers are kept apart from floats so amounts and heights survive a round
+// trip exactly. Object fields keep the order they were given in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i128),
+    Float(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum JsonError {
+    UnexpectedEnd,
+    UnexpectedChar(usize),
+    InvalidNumber,
+    InvalidEscape,
+    TooDeep,
+}
+
+impl Json {
+    pub fn parse(input: &str) -> Result<Json, JsonError> {
+        let mut parser = Parser { input, pos: 0 };
+        let value = parser.parse_value(0)?;
+        parser.skip_whitespace();
+        if parser.pos < input.len() {
+            return Err(JsonError::UnexpectedChar(parser.pos));
+        }
+        Ok(value)
+    }
+
+    pub fn object<const N: usize>(fields: [(&str, Json); N]) -> Json {
+        Json::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    // The first field with the given key, None if this is not an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Json::Null)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(value) => Some(*value),
+            _ => None
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Int(value) => u64::try_from(*value).ok(),
+            _ => None
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(value) => Some(value),
+            _ => None
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None
+        }
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Self {
+        Json::Bool(value)
+    }
+}
+
+impl From<u32> for Json {
+    fn from(value: u32) -> Self {
+        Json::Int(value as i128)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(value: u64) -> Self {
+        Json::Int(value as i128)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Self {
+        Json::Int(value as i128)
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Self {
+        Json::Int(value as i128)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Self {
+        Json::String(value.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Self {
+        Json::String(value)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Self {
+        Json::Array(values)
+    }
+}
+
+impl<T: Into<Json>> From<Option<T>> for Json {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(Json::Null, Into::into)
+    }
+}
+
+fn write_string(f: &mut std::fmt::Formatter<'_>, s: &str) -> std::fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?
+        }
+    }
+    write!(f, "\"")
+}
+
+// Compact serialization, without any whitespace
+impl std::fmt::Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Int(value) => write!(f, "{}", value),
+            // Debug keeps the fraction of whole numbers so they parse back as floats
+            Json::Float(value) if value.is_finite() => write!(f, "{:?}", value),
+            Json::Float(_) => write!(f, "null"),
+            Json::String(value) => write_string(f, value),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), JsonError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(_) => Err(JsonError::UnexpectedChar(self.pos)),
+            None => Err(JsonError::UnexpectedEnd)
+        }
+    }
+
+    fn expect_literal(&mut self, literal: &str, value: Json) -> Result<Json, JsonError> {
+        if self.input[self.pos..].starts_with(literal) {
+            self.pos += literal.len();
+            Ok(value)
+        } else {
+            Err(JsonError::UnexpectedChar(self.pos))
+        }
+    }
+
+    fn parse_value(&mut self, depth: usize) -> Result<Json, JsonError> {
+        if depth > MAX_DEPTH {
+            return Err(JsonError::TooDeep);
+        }
+        self.skip_whitespace();
+        match self.peek() {
+            None => Err(JsonError::UnexpectedEnd),
+            Some(b'n') => self.expect_literal("null", Json::Null),
+            Some(b't') => self.expect_literal("true", Json::Bool(true)),
+            Some(b'f') => self.expect_literal("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::String(self.parse_string()?)),
+            Some(b'[') => self.parse_array(depth),
+            Some(b'{') => self.parse_object(depth),
+            Some(b'-' | b'0'..=b'9') => self.parse_number(),
+            Some(_) => Err(JsonError::UnexpectedChar(self.pos))
+        }
+    }
+
+    fn parse_array(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.expect(b'[')?;
+        let mut values = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(self.parse_value(depth + 1)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Json::Array(values));
+                }
+                Some(_) => return Err(JsonError::UnexpectedChar(self.pos)),
+                None => return Err(JsonError::UnexpectedEnd)
+            }
+        }
+    }
+
+    fn parse_object(&mut self, depth: usize) -> Result<Json, JsonError> {
+        self.expect(b'{')?;
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(b':')?;
+            fields.push((key, self.parse_value(depth + 1)?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                Some(_) => return Err(JsonError::UnexpectedChar(self.pos)),
+                None => return Err(JsonError::UnexpectedEnd)
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self.input.get(self.pos..self.pos + 4).ok_or(JsonError::UnexpectedEnd)?;
+        let value = u32::from_str_radix(digits, 16).map_err(|_| JsonError::InvalidEscape)?;
+        if !digits.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(JsonError::InvalidEscape);
+        }
+        self.pos += 4;
+        Ok(value)
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        self.expect(b'"')?;
+        let mut value = String::new();
+        loop {
+            // Copies everything up to the next quote or escape at once
+            let start = self.pos;
+            while !matches!(self.peek(), Some(b'"' | b'\\') | None) {
+                if self.peek().is_some_and(|c| c < 0x20) {
+                    return Err(JsonError::UnexpectedChar(self.pos));
+                }
+                self.pos += 1;
+            }
+            value.push_str(&self.input[start..self.pos]);
+
+            match self.peek() {
+                None => return Err(JsonError::UnexpectedEnd),
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(value);
+                }
+                _ => self.pos += 1
+            }
+            let escape = self.peek().ok_or(JsonError::UnexpectedEnd)?;
+            self.pos += 1;
+            match escape {
+                b'"' => value.push('"'),
+                b'\\' => value.push('\\'),
+                b'/' => value.push('/'),
+                b'b' => value.push('\u{8}'),
+                b'f' => value.push('\u{c}'),
+                b'n' => value.push('\n'),
+                b'r' => value.push('\r'),
+                b't' => value.push('\t'),
+                b'u' => {
+                    let mut code = self.parse_hex4()?;
+                    // Characters outside the basic plane come as a surrogate pair
+                    if (0xD800..0xDC00).contains(&code) {
+                        if !self.input[self.pos..].starts_with("\\u") {
+                            return Err(JsonError::InvalidEscape);
+                        }
+                        self.pos += 2;
+                        let low = self.parse_hex4()?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return Err(JsonError::InvalidEscape);
+                        }
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                    }
+                    value.push(char::from_u32(code).ok_or(JsonError::InvalidEscape)?);
+                }
+                _ => return Err(JsonError::InvalidEscape)
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Json, JsonError> {
+        let start = self.pos;
+        let mut is_float = false;
+        while let Some(c) = self.peek() {
+            match c {
+                b'0'..=b'9' | b'-' | b'+' => {}
+                b'.' | b'e' | b'E' => is_float = true,
+                _ => break
+            }
+            self.pos += 1;
+        }
+        let text = &self.input[start..self.pos];
+        let digits = text.strip_prefix('-').unwrap_or(text);
+        if !digits.starts_with(|c: char| c.is_ascii_digit()) || (digits.starts_with('0') && digits[1..].starts_with(|c: char| c.is_ascii_digit())) {
+            return Err(JsonError::InvalidNumber);
+        }
+        if !is_float {
+            if let Ok(value) = text.parse::<i128>() {
+                return Ok(Json::Int(value));
+            }
+        }
+        text.parse::<f64>().map(Json::Float).map_err(|_| JsonError::InvalidNumber)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_serialize() {
+        let json = Json::parse(r#" {"method": "getblock", "params": [12, "ab\"\\\u00e9\ud83d\ude00", -1.5e3, true, null], "id": {}} "#).unwrap();
+        assert_eq!(json.get("method").and_then(Json::as_str), Some("getblock"));
+        let params = json.get("params").and_then(Json::as_array).unwrap();
+        assert_eq!(params[0].as_u64(), Some(12));
+        assert_eq!(params[1].as_str(), Some("ab\"\\\u{e9}\u{1F600}"));
+        assert_eq!(params[2], Json::Float(-1500.0));
+        assert_eq!(params[3].as_bool(), Some(true));
+        assert!(params[4].is_null());
+        assert_eq!(json.get("id"), Some(&Json::Object(vec![])));
+
+        // Serializing and parsing again gives the same value
+        let serialized = json.to_string();
+        assert_eq!(serialized, "{\"method\":\"getblock\",\"params\":[12,\"ab\\\"\\\\\u{e9}\u{1F600}\",-1500.0,true,null],\"id\":{}}");
+        assert_eq!(Json::parse(&serialized), Ok(json));
+        assert_eq!(Json::parse("18446744073709551615").unwrap().as_u64(), Some(u64::MAX));
+
+        assert_eq!(Json::parse("[1, 2"), Err(JsonError::UnexpectedEnd));
+        assert_eq!(Json::parse("[1 2]"), Err(JsonError::UnexpectedChar(3)));
+        assert_eq!(Json::parse("{\"a\": 1} x"), Err(JsonError::UnexpectedChar(9)));
+        assert_eq!(Json::parse("012"), Err(JsonError::InvalidNumber));
+        assert_eq!(Json::parse("\"\\x\""), Err(JsonError::InvalidEscape));
+        assert_eq!(Json::parse(&"[".repeat(100)), Err(JsonError::TooDeep));
+    }
+}
@@ -0,0 +1,656 @@
+use std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex},
+    thread::{self, JoinHandle}, time::Duration
+};
+use http::{read_request, write_response, HttpError, REQUEST_TIMEOUT};
+use json::Json;
+use crate::{
+    blockchain::{
+        address::{self, Address, AddressError, Recipient}, block::Block, encoding::{Decode, Encode}, script::{Script, ScriptError},
+        transaction::Transaction,
+        BlockError, BlockStatus, TransactionError
+    },
+    ecdsa::ECDSAPublicKey, net::Network, node::{mempool::MempoolError, Node}, sha256::Sha256, util
+};
+
+pub mod client;
+pub mod http;
+pub mod json;
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// At most this many blocks are mined by a single generate call
+pub const MAX_GENERATE: u64 = 100;
+
+// Error codes defined by JSON-RPC 2.0
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+
+// Lookups of blocks and transactions that do not exist
+pub const NOT_FOUND: i64 = -5;
+// Hex or binary data that does not decode to what the method expects
+pub const DESERIALIZATION_ERROR: i64 = -22;
+
+// Rejected transactions, one code per TransactionError and MempoolError variant
+pub const TX_INVALID_SIGNATURE: i64 = -101;
+pub const TX_INSUFFICIENT_FUNDS: i64 = -102;
+pub const TX_UNALLOWED: i64 = -103;
+pub const TX_MISMATCHED_OUTPUT: i64 = -104;
+pub const TX_INVALID_SCRIPT: i64 = -105;
+pub const TX_LOCK_TIME_NOT_REACHED: i64 = -106;
+pub const TX_SEQUENCE_LOCK_NOT_REACHED: i64 = -107;
+pub const TX_ALREADY_KNOWN: i64 = -110;
+pub const TX_FEE_TOO_LOW: i64 = -111;
+
+// Rejected blocks, one code per BlockError variant
+pub const BLOCK_INVALID_HASH: i64 = -201;
+pub const BLOCK_INVALID_MERKLE_ROOT: i64 = -202;
+pub const BLOCK_INVALID_PREVIOUS_HASH: i64 = -203;
+pub const BLOCK_INVALID_COINBASE: i64 = -204;
+pub const BLOCK_INVALID_DIFFICULTY: i64 = -205;
+pub const BLOCK_INVALID_TIMESTAMP: i64 = -206;
+pub const BLOCK_INVALID_TRANSACTIONS: i64 = -207;
+pub const BLOCK_DUPLICATE: i64 = -208;
+pub const BLOCK_STORAGE: i64 = -209;
+pub const BLOCK_TOO_LARGE: i64 = -210;
+
+// The error member of a response. data carries details such as the script
+// error behind TX_INVALID_SCRIPT.
+#[derive(Debug, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<Json>,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> Self {
+        RpcError { code, message: message.to_string(), data: None }
+    }
+
+    fn with_data(mut self, data: Json) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    fn invalid_params(message: &str) -> Self {
+        RpcError::new(INVALID_PARAMS, message)
+    }
+
+    fn to_json(&self) -> Json {
+        let mut error = vec![
+            ("code".to_string(), Json::from(self.code)),
+            ("message".to_string(), Json::from(self.message.as_str())),
+        ];
+        if let Some(data) = &self.data {
+            error.push(("data".to_string(), data.clone()));
+        }
+        Json::Object(error)
+    }
+}
+
+fn script_error_name(e: &ScriptError) -> String {
+    format!("{:?}", e)
+}
+
+impl From<TransactionError> for RpcError {
+    fn from(e: TransactionError) -> Self {
+        match e {
+            TransactionError::InvalidSignature => RpcError::new(TX_INVALID_SIGNATURE, "invalid signature"),
+            TransactionError::InvalidInputSignature(txid, input) => RpcError::new(TX_INVALID_SIGNATURE, "invalid signature")
+                .with_data(format!("{}:{}", txid, input).into()),
+            TransactionError::InsufficientFunds => RpcError::new(TX_INSUFFICIENT_FUNDS, "inputs missing or spent, or outputs exceed inputs"),
+            TransactionError::UnallowedTransaction => RpcError::new(TX_UNALLOWED, "transaction not allowed"),
+            TransactionError::MismatchedOutput => RpcError::new(TX_MISMATCHED_OUTPUT, "mismatched output"),
+            TransactionError::InvalidScript(e) => RpcError::new(TX_INVALID_SCRIPT, "script verification failed")
+                .with_data(script_error_name(&e).into()),
+            TransactionError::LockTimeNotReached => RpcError::new(TX_LOCK_TIME_NOT_REACHED, "lock time not reached"),
+            TransactionError::SequenceLockNotReached => RpcError::new(TX_SEQUENCE_LOCK_NOT_REACHED, "relative lock time not reached")
+        }
+    }
+}
+
+impl From<MempoolError> for RpcError {
+    fn from(e: MempoolError) -> Self {
+        match e {
+            MempoolError::AlreadyKnown => RpcError::new(TX_ALREADY_KNOWN, "transaction already known"),
+            MempoolError::FeeTooLow => RpcError::new(TX_FEE_TOO_LOW, "fee too low"),
+            MempoolError::Invalid(e) => e.into()
+        }
+    }
+}
+
+impl From<BlockError> for RpcError {
+    fn from(e: BlockError) -> Self {
+        match e {
+            BlockError::InvalidHash => RpcError::new(BLOCK_INVALID_HASH, "hash does not meet the target"),
+            BlockError::InvalidMerkleRoot => RpcError::new(BLOCK_INVALID_MERKLE_ROOT, "invalid merkle root"),
+            BlockError::InvalidPreviousBlockHash => RpcError::new(BLOCK_INVALID_PREVIOUS_HASH, "previous block unknown"),
+            BlockError::InvalidCoinbase => RpcError::new(BLOCK_INVALID_COINBASE, "invalid coinbase"),
+            BlockError::InvalidDifficulty => RpcError::new(BLOCK_INVALID_DIFFICULTY, "invalid difficulty"),
+            BlockError::InvalidTimestamp => RpcError::new(BLOCK_INVALID_TIMESTAMP, "invalid timestamp"),
+            BlockError::TooLarge => RpcError::new(BLOCK_TOO_LARGE, "block too large"),
+            BlockError::InvalidTransactions(errors) => {
+                // The transaction errors are reported in the same form as for sendrawtransaction
+                let errors = errors.into_iter().map(|e| RpcError::from(e).to_json()).collect::<Vec<Json>>();
+                RpcError::new(BLOCK_INVALID_TRANSACTIONS, "invalid transactions").with_data(errors.into())
+            }
+            BlockError::DuplicateBlock => RpcError::new(BLOCK_DUPLICATE, "block already known"),
+            BlockError::Storage(e) => RpcError::new(BLOCK_STORAGE, "storage error").with_data(e.into())
+        }
+    }
+}
+
+// What the server needs from the node it serves. A bare Node behind a mutex
+// works, a Network additionally announces new transactions and blocks to its peers.
+pub trait NodeHandle: Send + Sync + 'static {
+    fn with_node<T, F: FnOnce(&mut Node) -> T>(&self, f: F) -> T;
+    fn submit_transaction(&self, transaction: Transaction) -> Result<(), MempoolError>;
+    fn submit_block(&self, block: Block) -> Result<BlockStatus, BlockError>;
+    // None if mining was given up, see Network::mine
+    fn mine(&self) -> Result<Option<Block>, BlockError>;
+}
+
+impl NodeHandle for Mutex<Node> {
+    fn with_node<T, F: FnOnce(&mut Node) -> T>(&self, f: F) -> T {
+        f(&mut self.lock().unwrap())
+    }
+
+    fn submit_transaction(&self, transaction: Transaction) -> Result<(), MempoolError> {
+        self.lock().unwrap().add_transaction(transaction)
+    }
+
+    fn submit_block(&self, block: Block) -> Result<BlockStatus, BlockError> {
+        self.lock().unwrap().accept_block(block)
+    }
+
+    fn mine(&self) -> Result<Option<Block>, BlockError> {
+        self.lock().unwrap().mine().map(Some)
+    }
+}
+
+impl NodeHandle for Network {
+    fn with_node<T, F: FnOnce(&mut Node) -> T>(&self, f: F) -> T {
+        Network::with_node(self, f)
+    }
+
+    fn submit_transaction(&self, transaction: Transaction) -> Result<(), MempoolError> {
+        Network::submit_transaction(self, transaction)
+    }
+
+    fn submit_block(&self, block: Block) -> Result<BlockStatus, BlockError> {
+        Network::submit_block(self, block)
+    }
+
+    fn mine(&self) -> Result<Option<Block>, BlockError> {
+        Network::mine(self, || false)
+    }
+}
+
+// The params of a request, given either by position or by name
+struct Params<'a> {
+    params: Option<&'a Json>,
+}
+
+impl<'a> Params<'a> {
+    fn get(&self, idx: usize, name: &str) -> Option<&'a Json> {
+        match self.params? {
+            Json::Array(values) => values.get(idx),
+            params => params.get(name)
+        }.filter(|value| !value.is_null())
+    }
+
+    fn required(&self, idx: usize, name: &str) -> Result<&'a Json, RpcError> {
+        self.get(idx, name).ok_or_else(|| RpcError::invalid_params(&format!("missing parameter {}", name)))
+    }
+
+    fn hash(&self, idx: usize, name: &str) -> Result<Sha256, RpcError> {
+        parse_hash(self.required(idx, name)?)
+    }
+
+    fn hex(&self, idx: usize, name: &str) -> Result<Vec<u8>, RpcError> {
+        self.required(idx, name)?.as_str()
+            .and_then(util::hex_decode)
+            .ok_or_else(|| RpcError::invalid_params(&format!("{} must be a hex string", name)))
+    }
+}
+
+fn parse_hash(value: &Json) -> Result<Sha256, RpcError> {
+    value.as_str()
+        .and_then(util::hex_decode)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map(Sha256::from_bytes)
+        .ok_or_else(|| RpcError::invalid_params("expected a 32 byte hash in hex"))
+}
+
+fn decode<T: Decode>(bytes: &[u8]) -> Result<T, RpcError> {
+    T::from_bytes(bytes).map_err(|e| RpcError::new(DESERIALIZATION_ERROR, "decoding failed").with_data(format!("{:?}", e).into()))
+}
+
+fn transaction_json(tx: &Transaction) -> Json {
+    let inputs = tx.inputs.iter().map(|input| Json::object([
+        ("txid", input.txid.to_string().into()),
+        ("vout", input.vout.into()),
+        ("script_sig", util::hex_encode(input.script_sig.bytes()).into()),
+        ("sequence", input.sequence.into()),
+    ])).collect::<Vec<Json>>();
+    let outputs = tx.outputs.iter().map(|output| Json::object([
+        ("value", output.value.into()),
+        ("script_pubkey", util::hex_encode(output.script_pubkey.bytes()).into()),
+        ("address", Address::from_script(&output.script_pubkey, address::Network::Main).map(|address| address.to_string()).into()),
+    ])).collect::<Vec<Json>>();
+    Json::object([
+        ("txid", tx.hash().to_string().into()),
+        ("hex", util::hex_encode(&tx.to_bytes()).into()),
+        ("coinbase", tx.is_coinbase().into()),
+        ("lock_time", tx.lock_time.into()),
+        ("inputs", inputs.into()),
+        ("outputs", outputs.into()),
+    ])
+}
+
+// Looks a block up by height on the active chain or by hash anywhere in the
+// block tree, height is None for blocks off the active chain
+fn find_block<'a>(node: &'a Node, id: &Json) -> Result<(&'a Block, Option<usize>), RpcError> {
+    let blockchain = node.blockchain();
+    let block = match id.as_u64() {
+        Some(height) => blockchain.blocks.get(height as usize),
+        None => blockchain.get_block(&parse_hash(id)?)
+    };
+    let block = block.ok_or_else(|| RpcError::new(NOT_FOUND, "block not found"))?;
+    Ok((block, blockchain.block_height(&block.hash)))
+}
+
+fn get_block(node: &Node, params: &Params) -> Result<Json, RpcError> {
+    let (block, height) = find_block(node, params.required(0, "block")?)?;
+    let confirmations = height.map_or(0, |height| node.blockchain().blocks.len() - height);
+    let header = &block.header;
+    let transactions = block.merkle_tree.transactions().iter().map(|tx| tx.hash().to_string().into()).collect::<Vec<Json>>();
+    Ok(Json::object([
+        ("hash", block.hash.to_string().into()),
+        ("height", height.into()),
+        ("confirmations", confirmations.into()),
+        ("version", (header.version as u32).into()),
+        ("previous_block_hash", header.previous_block_hash.to_string().into()),
+        ("merkle_root", header.merkle_root.to_string().into()),
+        ("timestamp", header.timestamp.into()),
+        ("difficulty", header.difficulty.into()),
+        ("nonce", header.nonce.into()),
+        ("transactions", transactions.into()),
+        ("hex", util::hex_encode(&block.to_bytes()).into()),
+    ]))
+}
+
+// Pending transactions are found in the mempool, confirmed ones through the
+// height of the block that contains them
+fn get_transaction(node: &Node, params: &Params) -> Result<Json, RpcError> {
+    let txid = params.hash(0, "txid")?;
+    let (mut json, fields) = if let Some(entry) = node.mempool().get(&txid) {
+        (transaction_json(&entry.transaction), vec![
+            ("confirmations", Json::from(0u64)),
+            ("fee", entry.fee.into()),
+        ])
+    } else {
+        let blockchain = node.blockchain();
+        let height = blockchain.transaction_height(&txid).ok_or_else(|| RpcError::new(NOT_FOUND, "transaction not found"))?;
+        let block = &blockchain.blocks[height];
+        let tx = block.merkle_tree.transactions().iter().find(|tx| tx.hash() == txid).unwrap();
+        (transaction_json(tx), vec![
+            ("confirmations", (blockchain.blocks.len() - height).into()),
+            ("block_hash", block.hash.to_string().into()),
+            ("height", height.into()),
+        ])
+    };
+    if let Json::Object(object) = &mut json {
+        object.extend(fields.into_iter().map(|(key, value)| (key.to_string(), value)));
+    }
+    Ok(json)
+}
+
+// The output script paid to, given by an address or by a public key in hex
+fn parse_recipient(params: &Params) -> Result<Script, RpcError> {
+    let value = params.required(0, "address")?.as_str()
+        .ok_or_else(|| RpcError::invalid_params("address must be a string"))?;
+    match Address::parse_for(value, address::Network::Main) {
+        Ok(address) => return Ok(address.script_pubkey()),
+        Err(AddressError::WrongNetwork) => return Err(RpcError::invalid_params("address of another network")),
+        Err(_) => {}
+    }
+    let pubkey: ECDSAPublicKey = util::hex_decode(value)
+        .and_then(|bytes| ECDSAPublicKey::from_bytes(&bytes).ok())
+        .ok_or_else(|| RpcError::invalid_params("expected an address or a public key in hex"))?;
+    Ok(pubkey.script_pubkey())
+}
+
+fn list_unspent(node: &Node, params: &Params) -> Result<Json, RpcError> {
+    let funds = node.blockchain().get_script_funds(&parse_recipient(params)?);
+    Ok(funds.into_iter().map(|(txid, vout, value)| Json::object([
+        ("txid", txid.to_string().into()),
+        ("vout", vout.into()),
+        ("value", value.into()),
+    ])).collect::<Vec<Json>>().into())
+}
+
+fn get_balance(node: &Node, params: &Params) -> Result<Json, RpcError> {
+    let funds = node.blockchain().get_script_funds(&parse_recipient(params)?);
+    Ok(funds.iter().map(|(_, _, value)| value).sum::<u64>().into())
+}
+
+// The branch proves the transaction is committed to by the merkle root in the
+// header, see MerkleTree::verify_transaction_branch
+fn get_merkle_proof(node: &Node, params: &Params) -> Result<Json, RpcError> {
+    let txid = params.hash(0, "txid")?;
+    let blockchain = node.blockchain();
+    let height = blockchain.transaction_height(&txid).ok_or_else(|| RpcError::new(NOT_FOUND, "transaction not in the active chain"))?;
+    let block = &blockchain.blocks[height];
+    let tx = block.merkle_tree.transactions().iter().find(|tx| tx.hash() == txid).unwrap();
+    let branch = node.get_verifiyng_transaction_branch(tx.clone(), height).unwrap();
+    let branch = branch.into_iter().map(|(hash, side)| Json::object([
+        ("hash", hash.to_string().into()),
+        ("side", side.into()),
+    ])).collect::<Vec<Json>>();
+    Ok(Json::object([
+        ("txid", txid.to_string().into()),
+        ("hex", util::hex_encode(&tx.to_bytes()).into()),
+        ("block_hash", block.hash.to_string().into()),
+        ("height", height.into()),
+        ("merkle_root", block.header.merkle_root.to_string().into()),
+        ("branch", branch.into()),
+    ]))
+}
+
+fn get_chain_info(node: &Node) -> Json {
+    let blockchain = node.blockchain();
+    let tip = blockchain.blocks.last();
+    Json::object([
+        ("blocks", blockchain.blocks.len().into()),
+        ("headers", blockchain.headers().best_len().into()),
+        ("best_block_hash", tip.map(|block| block.hash.to_string()).into()),
+        ("difficulty", tip.map(|block| block.header.difficulty).into()),
+        ("next_difficulty", blockchain.next_difficulty(tip.map(|block| &block.hash)).into()),
+        ("median_time_past", tip.map(|block| blockchain.median_time_past(&block.hash)).into()),
+        ("total_work", Json::Int(blockchain.total_work() as i128)),
+        ("total_supply", blockchain.total_supply().into()),
+        ("next_subsidy", blockchain.next_block_subsidy().into()),
+    ])
+}
+
+fn get_mempool_info(node: &Node) -> Json {
+    let mempool = node.mempool();
+    Json::object([
+        ("transactions", mempool.len().into()),
+        ("bytes", mempool.size().into()),
+        ("total_fees", mempool.total_fees().into()),
+    ])
+}
+
+fn dispatch<H: NodeHandle>(handle: &H, method: &str, params: &Params) -> Result<Json, RpcError> {
+    match method {
+        "getblock" => handle.with_node(|node| get_block(node, params)),
+        "getblockhash" => {
+            let height = params.required(0, "height")?.as_u64().ok_or_else(|| RpcError::invalid_params("height must be a number"))?;
+            handle.with_node(|node| {
+                let block = node.blockchain().blocks.get(height as usize).ok_or_else(|| RpcError::new(NOT_FOUND, "block not found"))?;
+                Ok(block.hash.to_string().into())
+            })
+        }
+        "gettransaction" => handle.with_node(|node| get_transaction(node, params)),
+        "getbalance" => handle.with_node(|node| get_balance(node, params)),
+        "listunspent" => handle.with_node(|node| list_unspent(node, params)),
+        "getmerkleproof" => handle.with_node(|node| get_merkle_proof(node, params)),
+        "getchaininfo" => Ok(handle.with_node(|node| get_chain_info(node))),
+        "getmempoolinfo" => Ok(handle.with_node(|node| get_mempool_info(node))),
+        "sendrawtransaction" => {
+            let tx: Transaction = decode(&params.hex(0, "hex")?)?;
+            let txid = tx.hash();
+            handle.submit_transaction(tx)?;
+            Ok(txid.to_string().into())
+        }
+        "submitblock" => {
+            let block: Block = decode(&params.hex(0, "hex")?)?;
+            let status = match handle.submit_block(block)? {
+                BlockStatus::Extended => "extended",
+                BlockStatus::Fork => "fork",
+                BlockStatus::Reorg { .. } => "reorg"
+            };
+            Ok(status.into())
+        }
+        // Mines on top of the active chain, paying the node's own key
+        "generate" => {
+            let count = match params.get(0, "count") {
+                Some(count) => count.as_u64().filter(|count| (1..=MAX_GENERATE).contains(count))
+                    .ok_or_else(|| RpcError::invalid_params(&format!("count must be between 1 and {}", MAX_GENERATE)))?,
+                None => 1
+            };
+            // Stops early if the node shuts down
+            let mut hashes = Vec::new();
+            while hashes.len() < count as usize {
+                match handle.mine()? {
+                    Some(block) => hashes.push(block.hash.to_string().into()),
+                    None => break
+                }
+            }
+            Ok(Json::from(hashes))
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, "method not found"))
+    }
+}
+
+fn response(id: Json, result: Result<Json, RpcError>) -> Json {
+    let (key, value) = match result {
+        Ok(result) => ("result", result),
+        Err(e) => ("error", e.to_json())
+    };
+    Json::object([("jsonrpc", "2.0".into()), (key, value), ("id", id)])
+}
+
+// Returns None for a notification, a request without an id
+fn handle_request<H: NodeHandle>(handle: &H, request: &Json) -> Option<Json> {
+    let Json::Object(_) = request else {
+        return Some(response(Json::Null, Err(RpcError::new(INVALID_REQUEST, "request must be an object"))));
+    };
+    let id = request.get("id").cloned();
+    let method = request.get("method").and_then(Json::as_str);
+    let params = request.get("params");
+    let result = match (method, params) {
+        (None, _) => Err(RpcError::new(INVALID_REQUEST, "missing method")),
+        (Some(_), Some(params)) if !matches!(params, Json::Array(_) | Json::Object(_)) =>
+            Err(RpcError::new(INVALID_REQUEST, "params must be an array or an object")),
+        (Some(method), params) => dispatch(handle, method, &Params { params })
+    };
+    Some(response(id?, result))
+}
+
+// A batch is answered with an array holding the responses to its requests
+fn handle_body<H: NodeHandle>(handle: &H, body: &[u8]) -> Option<Json> {
+    let request = match std::str::from_utf8(body).ok().map(Json::parse) {
+        Some(Ok(request)) => request,
+        _ => return Some(response(Json::Null, Err(RpcError::new(PARSE_ERROR, "parse error"))))
+    };
+    match request {
+        Json::Array(requests) if requests.is_empty() => Some(response(Json::Null, Err(RpcError::new(INVALID_REQUEST, "empty batch")))),
+        Json::Array(requests) => {
+            let responses: Vec<Json> = requests.iter().filter_map(|request| handle_request(handle, request)).collect();
+            (!responses.is_empty()).then_some(Json::Array(responses))
+        }
+        request => handle_request(handle, &request)
+    }
+}
+
+// JSON-RPC 2.0 over HTTP POST. Every connection gets its own thread and is
+// closed after a single request. Params can be given by position or by name,
+// blocks by height or by hash and binary data such as transactions is
+// exchanged in the hex of its encoding.
+pub struct RpcServer {
+    local_addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    listener_thread: Option<JoinHandle<()>>,
+}
+
+impl RpcServer {
+    pub fn start<H: NodeHandle, A: ToSocketAddrs>(handle: Arc<H>, addr: A) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let listener_thread = thread::spawn(move || run_listener(handle, listener, thread_running));
+        Ok(RpcServer { local_addr, running, listener_thread: Some(listener_thread) })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn shutdown(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.listener_thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for RpcServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn run_listener<H: NodeHandle>(handle: Arc<H>, listener: TcpListener, running: Arc<AtomicBool>) {
+    while running.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let handle = handle.clone();
+                thread::spawn(move || serve_connection(&*handle, stream));
+            }
+            Err(_) => thread::sleep(ACCEPT_POLL_INTERVAL)
+        }
+    }
+}
+
+fn serve_connection<H: NodeHandle>(handle: &H, stream: TcpStream) {
+    if stream.set_nonblocking(false).is_err() || stream.set_read_timeout(Some(REQUEST_TIMEOUT)).is_err() {
+        return;
+    }
+    let (status, body) = match read_request(&stream) {
+        Ok(request) if request.method != "POST" => (405, String::new()),
+        Ok(request) => match handle_body(handle, &request.body) {
+            Some(response) => (200, response.to_string()),
+            None => (204, String::new())
+        },
+        Err(HttpError::BodyTooLarge) => (413, String::new()),
+        Err(HttpError::Malformed) => (400, String::new()),
+        Err(HttpError::Io(_)) => return
+    };
+    let _ = write_response(&stream, status, &body);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{blockchain::{merkle::MerkleTree, Blockchain, MINING_REWARD}, ecdsa};
+    use super::*;
+
+    fn call(server: &RpcServer, body: &str) -> Json {
+        let (status, body) = http::post(server.local_addr(), body).unwrap();
+        assert_eq!(status, 200);
+        Json::parse(&body).unwrap()
+    }
+
+    fn request(server: &RpcServer, method: &str, params: Json) -> Result<Json, i64> {
+        let request = Json::object([("jsonrpc", "2.0".into()), ("method", method.into()), ("params", params), ("id", 1u64.into())]);
+        let response = call(server, &request.to_string());
+        match response.get("error") {
+            Some(error) => match error.get("code") {
+                Some(Json::Int(code)) => Err(*code as i64),
+                _ => panic!("error without a code")
+            },
+            None => Ok(response.get("result").unwrap().clone())
+        }
+    }
+
+    #[test]
+    fn test_rpc_server() {
+        let keys = ecdsa::generate_keypair();
+        let pubkey = util::hex_encode(&keys.0.to_bytes());
+        let address = Address::from_pubkey(&keys.0, address::Network::Main);
+        let blockchain = Blockchain::new(Transaction::get_coinbase(keys.0.clone(), MINING_REWARD));
+        let handle = Arc::new(Mutex::new(Node::new("Node", blockchain, keys)));
+        let server = RpcServer::start(handle.clone(), "127.0.0.1:0").unwrap();
+
+        let mined = request(&server, "generate", Json::Array(vec![2u64.into()])).unwrap();
+        let info = request(&server, "getchaininfo", Json::Array(vec![])).unwrap();
+        assert_eq!(info.get("blocks").and_then(Json::as_u64), Some(3));
+        assert_eq!(info.get("best_block_hash"), mined.as_array().unwrap().last());
+
+        // Blocks by height or by hash, params by position or by name
+        let by_height = request(&server, "getblock", Json::Array(vec![1u64.into()])).unwrap();
+        let by_hash = request(&server, "getblock", Json::object([("block", mined.as_array().unwrap()[0].clone())])).unwrap();
+        assert_eq!(by_height, by_hash);
+        assert_eq!(by_height.get("confirmations").and_then(Json::as_u64), Some(2));
+        let hex = util::hex_decode(by_height.get("hex").and_then(Json::as_str).unwrap()).unwrap();
+        assert_eq!(Block::from_bytes(&hex).unwrap().hash.to_string(), by_height.get("hash").and_then(Json::as_str).unwrap());
+        assert_eq!(request(&server, "getblock", Json::Array(vec![9u64.into()])), Err(NOT_FOUND));
+
+        let balance = request(&server, "getbalance", Json::Array(vec![pubkey.as_str().into()])).unwrap();
+        assert_eq!(balance.as_u64(), Some(3 * MINING_REWARD));
+        assert_eq!(request(&server, "listunspent", Json::Array(vec![pubkey.as_str().into()])).unwrap().as_array().unwrap().len(), 3);
+        assert_eq!(request(&server, "getbalance", Json::object([("address", address.to_base58().into())])), Ok(balance));
+        assert_eq!(request(&server, "getbalance", Json::Array(vec!["bk1qqqq".into()])), Err(INVALID_PARAMS));
+        let testnet = Address { network: address::Network::Test, ..address.clone() };
+        assert_eq!(request(&server, "listunspent", Json::Array(vec![testnet.to_string().into()])), Err(INVALID_PARAMS));
+
+        // A submitted transaction shows up in the mempool and is confirmed by mining
+        let tx = handle.with_node(|node| node.user.try_transaction_with_fee(&[(ecdsa::generate_keypair().0, 10)], 2)).unwrap();
+        let tx_hex = util::hex_encode(&tx.to_bytes());
+        let txid = request(&server, "sendrawtransaction", Json::Array(vec![tx_hex.as_str().into()])).unwrap();
+        assert_eq!(txid.as_str(), Some(tx.hash().to_string().as_str()));
+        assert_eq!(request(&server, "sendrawtransaction", Json::Array(vec![tx_hex.as_str().into()])), Err(TX_ALREADY_KNOWN));
+        assert_eq!(request(&server, "sendrawtransaction", Json::Array(vec!["00".into()])), Err(DESERIALIZATION_ERROR));
+        let mempool = request(&server, "getmempoolinfo", Json::Array(vec![])).unwrap();
+        assert_eq!(mempool.get("total_fees").and_then(Json::as_u64), Some(2));
+        let pending = request(&server, "gettransaction", Json::Array(vec![txid.clone()])).unwrap();
+        assert_eq!(pending.get("confirmations").and_then(Json::as_u64), Some(0));
+
+        request(&server, "generate", Json::Array(vec![])).unwrap();
+        let confirmed = request(&server, "gettransaction", Json::Array(vec![txid.clone()])).unwrap();
+        assert_eq!(confirmed.get("height").and_then(Json::as_u64), Some(3));
+        assert_eq!(confirmed.get("hex"), pending.get("hex"));
+        let change = &confirmed.get("outputs").and_then(Json::as_array).unwrap()[1];
+        assert_eq!(change.get("address").and_then(Json::as_str), Some(address.to_string().as_str()));
+
+        // The merkle proof checks out against the root in the header
+        let proof = request(&server, "getmerkleproof", Json::Array(vec![txid])).unwrap();
+        let branch = proof.get("branch").and_then(Json::as_array).unwrap().iter()
+            .map(|step| (parse_hash(step.get("hash").unwrap()).unwrap(), step.get("side").and_then(Json::as_u64).unwrap() as usize))
+            .collect();
+        let root = parse_hash(proof.get("merkle_root").unwrap()).unwrap();
+        assert!(MerkleTree::verify_transaction_branch(tx.clone(), branch, root));
+
+        // A spent input is rejected with the code of the transaction error
+        let mut spent = tx.clone();
+        spent.outputs[0].value += 1;
+        let spent_hex = util::hex_encode(&spent.to_bytes());
+        assert_eq!(request(&server, "sendrawtransaction", Json::Array(vec![spent_hex.into()])), Err(TX_INSUFFICIENT_FUNDS));
+        let block = handle.with_node(|node| node.blockchain().blocks[1].clone());
+        let block_hex = util::hex_encode(&block.to_bytes());
+        assert_eq!(request(&server, "submitblock", Json::Array(vec![block_hex.into()])), Err(BLOCK_DUPLICATE));
+
+        // Protocol errors, batches and notifications
+        assert_eq!(request(&server, "unknown", Json::Array(vec![])), Err(METHOD_NOT_FOUND));
+        assert_eq!(request(&server, "getblock", Json::Array(vec![])), Err(INVALID_PARAMS));
+        let error = call(&server, "{\"method\": ");
+        assert_eq!(error.get("error").and_then(|error| error.get("code")), Some(&Json::Int(PARSE_ERROR as i128)));
+        assert!(error.get("id").unwrap().is_null());
+        let batch = call(&server, r#"[{"jsonrpc": "2.0", "method": "getmempoolinfo", "id": 1}, {"jsonrpc": "2.0", "method": "getchaininfo"}, {"id": "x"}]"#);
+        let batch = batch.as_array().unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].get("result").and_then(|info| info.get("transactions")).and_then(Json::as_u64), Some(0));
+        assert_eq!(batch[1].get("id").and_then(Json::as_str), Some("x"));
+        assert_eq!(http::post(server.local_addr(), r#"{"jsonrpc": "2.0", "method": "generate"}"#).unwrap(), (204, String::new()));
+        assert_eq!(handle.with_node(|node| node.blockchain().blocks.len()), 5);
+    }
+}
@@ -0,0 +1,307 @@
+use crate::{
+    ecdsa::{point::{coordinate_bytes, shamir_multiply, AffinePoint, JacobianPoint}, secp256k1::{self, BARRET_MU_N}, ECDSAPrivateKey},
+    math::{big_int::{BigInt, BigIntMod}, random}, sha256::Sha256
+};
+
+// Schnorr signatures on secp256k1 as specified in BIP 340. Public keys are
+// only the x coordinate, the point is the one with an even y. Private keys
+// are the same scalars as for ECDSA, a key whose point has an odd y signs
+// with its negation.
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SchnorrPublicKey {
+    pub key: AffinePoint,
+}
+
+// r is the x coordinate of the nonce point R, s = k + e * d mod N
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SchnorrSignature {
+    pub r: BigInt<4>,
+    pub s: BigInt<4>,
+}
+
+impl SchnorrPublicKey {
+    pub fn from_private_key(private_key: &ECDSAPrivateKey) -> Self {
+        SchnorrPublicKey { key: even_y(secp256k1::G.scalar_multiply(private_key.key).to_affine()) }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 32] {
+        coordinate_bytes(&self.key.x)
+    }
+
+    // None if x is not the x coordinate of a point on the curve
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 32 {
+            return None;
+        }
+        AffinePoint::decompress(BigInt::from_bytes_be(bytes), false).map(|key| SchnorrPublicKey { key })
+    }
+}
+
+impl SchnorrSignature {
+    pub fn to_bytes(&self) -> [u8; 64] {
+        let mut bytes = [0; 64];
+        bytes[..32].copy_from_slice(&coordinate_bytes(&self.r));
+        bytes[32..].copy_from_slice(&coordinate_bytes(&self.s));
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 64 {
+            return None;
+        }
+        Some(SchnorrSignature { r: BigInt::from_bytes_be(&bytes[..32]), s: BigInt::from_bytes_be(&bytes[32..]) })
+    }
+}
+
+impl std::fmt::Display for SchnorrPublicKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.to_bytes() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+// SHA-256(SHA-256(tag) || SHA-256(tag) || data), so hashes made for one
+// purpose can not be mistaken for hashes made for another
+pub fn tagged_hash(tag: &str, data: &[u8]) -> Sha256 {
+    let tag = Sha256::hash(tag.as_bytes());
+    let mut input = Vec::with_capacity(64 + data.len());
+    input.extend_from_slice(tag.bytes());
+    input.extend_from_slice(tag.bytes());
+    input.extend_from_slice(data);
+    Sha256::hash(&input)
+}
+
+fn even_y(point: AffinePoint) -> AffinePoint {
+    if point.y.is_odd() {
+        AffinePoint::new(point.x, negate(point.y, secp256k1::P))
+    } else {
+        point
+    }
+}
+
+fn negate(value: BigInt<4>, modulo: BigInt<4>) -> BigInt<4> {
+    if value == BigInt::from_num(0) {
+        return value;
+    }
+    (modulo.resize::<12>() - value.resize()).resize()
+}
+
+fn scalar(value: BigInt<4>) -> BigIntMod<12> {
+    BigIntMod::<12>::new_reduce(value.resize(), secp256k1::N.resize(), BARRET_MU_N)
+}
+
+// e = H_challenge(r || P || m) mod N
+fn challenge(r: &BigInt<4>, public_key: &SchnorrPublicKey, message: &[u8]) -> BigIntMod<12> {
+    let mut data = coordinate_bytes(r).to_vec();
+    data.extend_from_slice(&public_key.to_bytes());
+    data.extend_from_slice(message);
+    scalar(tagged_hash("BIP0340/challenge", &data).to_bigint().resize())
+}
+
+// Signs with fresh auxiliary randomness mixed into the nonce, as BIP 340
+// recommends against side channel attacks. The nonce still depends on the
+// key and the message, so a broken random number generator does not leak
+// the key.
+pub fn sign(message: &[u8], private_key: &ECDSAPrivateKey) -> SchnorrSignature {
+    let mut aux = [0u8; 32];
+    random::get_random_bytes(&mut aux).expect("Failed to get random bytes");
+    sign_with_aux(message, private_key, &aux)
+}
+
+pub fn sign_with_aux(message: &[u8], private_key: &ECDSAPrivateKey, aux: &[u8; 32]) -> SchnorrSignature {
+    let point = secp256k1::G.scalar_multiply(private_key.key).to_affine();
+    let d = if point.y.is_odd() { negate(private_key.key, secp256k1::N) } else { private_key.key };
+    let public_key = SchnorrPublicKey { key: even_y(point) };
+
+    let mut data: Vec<u8> = coordinate_bytes(&d).iter()
+        .zip(tagged_hash("BIP0340/aux", aux).bytes())
+        .map(|(d, t)| d ^ t)
+        .collect();
+    data.extend_from_slice(&public_key.to_bytes());
+    data.extend_from_slice(message);
+    let k = scalar(tagged_hash("BIP0340/nonce", &data).to_bigint().resize());
+    assert!(k.integer != BigInt::from_num(0), "Schnorr nonce is zero");
+
+    let r_point = secp256k1::G.scalar_multiply(k.integer.resize()).to_affine();
+    let k = if r_point.y.is_odd() { scalar(negate(k.integer.resize(), secp256k1::N)) } else { k };
+    let e = challenge(&r_point.x, &public_key, message);
+    let s = k + e * scalar(d);
+    SchnorrSignature { r: r_point.x, s: s.integer.resize() }
+}
+
+// Valid if s * G - e * P is a point with an even y whose x is r
+pub fn verify(signature: &SchnorrSignature, message: &[u8], public_key: &SchnorrPublicKey) -> bool {
+    if signature.r >= secp256k1::P || signature.s >= secp256k1::N {
+        return false;
+    }
+    let e = challenge(&signature.r, public_key, message);
+    let minus_e = negate(e.integer.resize(), secp256k1::N);
+    let r_point = shamir_multiply(signature.s, &public_key.key, minus_e);
+    if r_point.is_infinity() {
+        return false;
+    }
+    let r_point = r_point.to_affine();
+    !r_point.y.is_odd() && r_point.x == signature.r
+}
+
+// Checks many signatures at once with the batch verification of BIP 340:
+// with random weights a_i, with a_0 = 1,
+//
+//     (sum a_i * s_i) * G - sum a_i * R_i - sum (a_i * e_i) * P_i = infinity
+//
+// is one multi scalar multiplication instead of two scalar multiplications
+// per signature. The weights keep invalid signatures from cancelling each
+// other out. They come from a hash of the whole batch, so they can not be
+// known before the signatures are chosen. True for all valid signatures,
+// false if any is invalid, which one has to be found with verify.
+pub fn verify_batch(items: &[(&[u8], SchnorrSignature, &SchnorrPublicKey)]) -> bool {
+    let mut seed = Vec::new();
+    for (message, signature, public_key) in items {
+        seed.extend_from_slice(&public_key.to_bytes());
+        seed.extend_from_slice(&signature.to_bytes());
+        seed.extend_from_slice(&(message.len() as u64).to_be_bytes());
+        seed.extend_from_slice(message);
+    }
+    let seed = Sha256::hash(&seed);
+
+    let mut g_scalar = scalar(BigInt::from_num(0));
+    let mut terms = Vec::with_capacity(2 * items.len() + 1);
+    for (i, (message, signature, public_key)) in items.iter().enumerate() {
+        if signature.r >= secp256k1::P || signature.s >= secp256k1::N {
+            return false;
+        }
+        let Some(r_point) = AffinePoint::decompress(signature.r, false) else {
+            return false;
+        };
+        let a = if i == 0 { scalar(BigInt::from_num(1)) } else { batch_weight(&seed, i) };
+        let e = challenge(&signature.r, public_key, message);
+        g_scalar = g_scalar + a * scalar(signature.s);
+        terms.push((r_point, negate(a.integer.resize(), secp256k1::N)));
+        terms.push((public_key.key, negate((a * e).integer.resize(), secp256k1::N)));
+    }
+    terms.push((secp256k1::G, g_scalar.integer.resize()));
+    JacobianPoint::multi_scalar_multiply(&terms).is_infinity()
+}
+
+// 128 bits are enough for the chance of a forgery passing to be negligible
+fn batch_weight(seed: &Sha256, i: usize) -> BigIntMod<12> {
+    let mut data = seed.bytes().to_vec();
+    data.extend_from_slice(&(i as u64).to_be_bytes());
+    scalar(BigInt::from_bytes_be(&tagged_hash("BIP0340/batch", &data).bytes()[..16]))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::hex_decode;
+    use super::*;
+
+    #[test]
+    fn test_bip340_vectors() {
+        // Index 0 to 3 of the BIP 340 test vectors: secret key, public key,
+        // auxiliary randomness, message and signature
+        let signing = [
+            ("0000000000000000000000000000000000000000000000000000000000000003",
+                "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000000000000000000000000000",
+                "e907831f80848d1069a5371b402410364bdf1c5f8307b0084c55f1ce2dca821525f66a4a85ea8b71e482a74f382d2ce5ebeee8fdb2172f477df4900d310536c0"),
+            ("b7e151628aed2a6abf7158809cf4f3c762e7160f38b4da56a784d9045190cfef",
+                "dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89",
+                "6896bd60eeae296db48a229ff71dfe071bde413e6d43f917dc8dcf8c78de33418906d11ac976abccb20b091292bff4ea897efcb639ea871cfa95f6de339e4b0a"),
+            ("c90fdaa22168c234c4c6628b80dc1cd129024e088a67cc74020bbea63b14e5c9",
+                "dd308afec5777e13121fa72b9cc1b7cc0139715309b086c960e18fd969774eb8",
+                "c87aa53824b4d7ae2eb035a2b5bbbccc080e76cdc6d1692c4b0b62d798e6d906",
+                "7e2d58d8b3bcdf1abadec7829054f90dda9805aab56c77333024b9d0a508b75c",
+                "5831aaeed7b44bb74e5eab94ba9d4294c49bcf2a60728d8b4c200f50dd313c1bab745879a5ad954a72c45a91c3a51d3c7adea98d82f8481e0e1e03674a6f3fb7"),
+            ("0b432b2677937381aef05bb02a66ecd012773062cf3fa2549e44f58ed2401710",
+                "25d1dff95105f5253c4022f628a996ad3a0d95fbf21d468a1b33f8c160d8f517",
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+                "7eb0509757e246f19449885651611cb965ecc1a187dd51b64fda1edc9637d5ec97582b9cb13db3933705b32ba982af5af25fd78881ebb32771fc5922efc66ea3"),
+        ];
+        for (secret, public, aux, message, expected) in signing {
+            let private_key = ECDSAPrivateKey { key: BigInt::from_hex_string(secret) };
+            let public_key = SchnorrPublicKey::from_private_key(&private_key);
+            assert_eq!(public_key.to_bytes().to_vec(), hex_decode(public).unwrap());
+            let message = hex_decode(message).unwrap();
+            let signature = sign_with_aux(&message, &private_key, &hex_decode(aux).unwrap().try_into().unwrap());
+            assert_eq!(signature.to_bytes().to_vec(), hex_decode(expected).unwrap());
+            assert!(verify(&signature, &message, &public_key));
+            assert!(verify(&sign(&message, &private_key), &message, &public_key));
+        }
+
+        // Index 4 to 14, verification only
+        let public = "dff1d77f2a671c5f36183726db2341be58feae1da2deced843240f7b502ba659";
+        let message = "243f6a8885a308d313198a2e03707344a4093822299f31d0082efa98ec4e6c89";
+        let verification = [
+            ("d69c3509bb99e412e68b0fe8544e72837dfa30746d8be2aa65975f29d22dc7b9", "4df3c3f68fcc83b27e9d42c90431a72499f17875c81a599b566c9889b9696703",
+                "00000000000000000000003b78ce563f89a0ed9414f5aa28ad0d96d6795f9c6376afb1548af603b3eb45c9f8207dee1060cb71c04e80f593060b07d28308d7f4", true),
+            // Public key not on the curve
+            ("eefdea4cdb677750a420fee807eacf21eb9898ae79b9768766e4faa04a2d4a34", message,
+                "6cff5c3ba86c69ea4b7376f31a9bcb4f74c1976089b2d9963da2e5543e17776969e89b4c5564d00349106b8497785dd7d1d713a8ae82b32fa79d5f7fc407d39b", false),
+            // R has an odd y
+            (public, message,
+                "fff97bd5755eeea420453a14355235d382f6472f8568a18b2f057a14602975563cc27944640ac607cd107ae10923d9ef7a73c643e166be5ebeafa34b1ac553e2", false),
+            // Negated message
+            (public, message,
+                "1fa62e331edbc21c394792d2ab1100a7b432b013df3f6ff4f99fcb33e0e1515f28890b3edb6e7189b630448b515ce4f8622a954cfe545735aaea5134fccdb2bd", false),
+            // Negated s
+            (public, message,
+                "6cff5c3ba86c69ea4b7376f31a9bcb4f74c1976089b2d9963da2e5543e177769961764b3aa9b2ffcb6ef947b6887a226e8d7c93e00c5ed0c1834ff0d0c2e6da6", false),
+            // s * G - e * P is infinite
+            (public, message,
+                "0000000000000000000000000000000000000000000000000000000000000000123dda8328af9c23a94c1feecfd123ba4fb73476f0d594dcb65c6425bd186051", false),
+            (public, message,
+                "00000000000000000000000000000000000000000000000000000000000000017615fbaf5ae28864013c099742deadb4dba87f11ac6754f93780d5a1837cf197", false),
+            // r is not the x coordinate of a point on the curve
+            (public, message,
+                "4a298dacae57395a15d0795ddbfd1dcb564da82b0f269bc70a74f8220429ba1d69e89b4c5564d00349106b8497785dd7d1d713a8ae82b32fa79d5f7fc407d39b", false),
+            // r is equal to P
+            (public, message,
+                "fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f69e89b4c5564d00349106b8497785dd7d1d713a8ae82b32fa79d5f7fc407d39b", false),
+            // s is equal to N
+            (public, message,
+                "6cff5c3ba86c69ea4b7376f31a9bcb4f74c1976089b2d9963da2e5543e177769fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364141", false),
+            // Public key exceeds P
+            ("fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc30", message,
+                "6cff5c3ba86c69ea4b7376f31a9bcb4f74c1976089b2d9963da2e5543e17776969e89b4c5564d00349106b8497785dd7d1d713a8ae82b32fa79d5f7fc407d39b", false),
+        ];
+        for (public, message, signature, valid) in verification {
+            let signature = SchnorrSignature::from_bytes(&hex_decode(signature).unwrap()).unwrap();
+            let message = hex_decode(message).unwrap();
+            let result = SchnorrPublicKey::from_bytes(&hex_decode(public).unwrap())
+                .is_some_and(|public_key| verify(&signature, &message, &public_key));
+            assert_eq!(result, valid);
+        }
+    }
+
+    #[test]
+    fn test_verify_batch() {
+        let keys: Vec<ECDSAPrivateKey> = (0..4).map(|_| crate::ecdsa::generate_keypair().1).collect();
+        let public_keys: Vec<SchnorrPublicKey> = keys.iter().map(SchnorrPublicKey::from_private_key).collect();
+        let messages: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 10 * i as usize]).collect();
+        let mut items: Vec<(&[u8], SchnorrSignature, &SchnorrPublicKey)> = (0..4)
+            .map(|i| (messages[i].as_slice(), sign(&messages[i], &keys[i]), &public_keys[i]))
+            .collect();
+        assert!(verify_batch(&items));
+        assert!(verify_batch(&[]));
+
+        // One signature for the wrong message fails the whole batch
+        items[2].0 = &messages[1];
+        assert!(!verify_batch(&items));
+        let invalid: Vec<usize> = (0..4).filter(|&i| !verify(&items[i].1, items[i].0, items[i].2)).collect();
+        assert_eq!(invalid, vec![2]);
+
+        // Two invalid signatures whose errors would cancel out without weights
+        items[2].0 = &messages[2];
+        let delta = scalar(BigInt::from_num(1));
+        items[0].1.s = (scalar(items[0].1.s) + delta).integer.resize();
+        items[1].1.s = (scalar(items[1].1.s) - delta).integer.resize();
+        assert!(!verify_batch(&items));
+    }
+}
@@ -16,6 +16,8 @@ const K: [u32; 64] = [
     0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2
 ];
 
+const BLOCK_SIZE: usize = 64;
+
 #[derive(Eq, Hash, Clone, Debug)]
 pub struct Sha256 {
     hash: [u8; 32],
@@ -26,6 +28,25 @@ impl Sha256 {
         Self { hash: sha256(input) }
     }
 
+    // HMAC-SHA256 as in RFC 2104, keys longer than a block are hashed first
+    pub fn hmac(key: &[u8], message: &[u8]) -> Self {
+        let mut block = [0u8; BLOCK_SIZE];
+        if key.len() > BLOCK_SIZE {
+            block[..32].copy_from_slice(&sha256(key));
+        } else {
+            block[..key.len()].copy_from_slice(key);
+        }
+        let mut inner: Vec<u8> = block.iter().map(|byte| byte ^ 0x36).collect();
+        inner.extend_from_slice(message);
+        let mut outer: Vec<u8> = block.iter().map(|byte| byte ^ 0x5c).collect();
+        outer.extend_from_slice(&sha256(&inner));
+        Self { hash: sha256(&outer) }
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self { hash: bytes }
+    }
+
     pub fn to_bigint(&self) -> BigInt {
         BigInt::from_bytes_be(&self.hash)
     }
@@ -34,10 +55,9 @@ impl Sha256 {
         &self.hash
     }
 
-    pub fn is_valid(&self, difficulty: u64) -> bool {
-        let target = 0xFFFFFFFFFFFFFFFF >> difficulty;
-        let hash_value = u64::from_be_bytes(self.hash[0..8].try_into().unwrap());
-        hash_value < target
+    // The hash is read as a 256-bit big endian number and has to be at most target
+    pub fn is_valid(&self, target: &BigInt<4>) -> bool {
+        BigInt::<4>::from_bytes_be(&self.hash) <= *target
     }
 }
 
@@ -141,4 +161,15 @@ mod tests {
         let hex = format!("{}", hash);
         assert_eq!(hex, "4ce9215919a36e7fe07886afb320057110b57e14275176b92140b2a90e4816ef");
     }
+
+    #[test]
+    fn test_hmac_rfc4231() {
+        let hmac = Sha256::hmac(&[0x0b; 20], b"Hi There");
+        assert_eq!(hmac.to_string(), "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7");
+        let hmac = Sha256::hmac(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(hmac.to_string(), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        // A key longer than the block size
+        let hmac = Sha256::hmac(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First");
+        assert_eq!(hmac.to_string(), "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54");
+    }
 }
\ No newline at end of file
@@ -1,8 +1,12 @@
-use crate::{blockchain::{merkle::MerkleTree, transaction::{Transaction, TxInput, TxOutput}}, ecdsa::{self, point::AffinePoint, ECDSAPrivateKey, ECDSAPublicKey}, sha256::Sha256};
+use crate::{blockchain::{address::Recipient, merkle::MerkleTree, script::Script, transaction::{Transaction, TxInput, TxOutput, SEQUENCE_FINAL}}, ecdsa::{self, point::AffinePoint, ECDSAPrivateKey, ECDSAPublicKey}, schnorr::{self, SchnorrSignature}, sha256::Sha256};
 
-#[derive(Debug)]
+pub mod multisig;
+
+#[derive(Debug, PartialEq)]
 pub enum UserError {
     InsufficientFunds,
+    NotASigner,
+    NotEnoughSignatures,
 }
 
 // txid is the hash of the transaction where this fund is from
@@ -31,19 +35,32 @@ impl User {
         }
     }
 
-    pub fn try_transaction(&self, recievers: &Vec<(ECDSAPublicKey, u64)>) -> Result<Transaction, UserError> {
+    // Recievers are given by public key or by address
+    pub fn try_transaction<R: Recipient>(&self, recievers: &[(R, u64)]) -> Result<Transaction, UserError> {
+        self.try_transaction_with_fee(recievers, 0)
+    }
+
+    pub fn try_transaction_with_fee<R: Recipient>(&self, recievers: &[(R, u64)], fee: u64) -> Result<Transaction, UserError> {
+        let outputs: Vec<(Script, u64)> = recievers.iter().map(|(reciever, value)| (reciever.script_pubkey(), *value)).collect();
+        self.try_script_transaction(&outputs, fee)
+    }
+
+    // Pays to arbitrary locking scripts, e.g. a shared multisig output.
+    // Whatever the inputs carry on top of the outputs goes to the miner, so
+    // the fee is left out of the change output
+    pub fn try_script_transaction(&self, outputs: &[(Script, u64)], fee: u64) -> Result<Transaction, UserError> {
         let mut total_input = 0;
-        let total_output: u64 = recievers.iter().map(|(_, value)| *value).sum();
+        let total_output: u64 = outputs.iter().map(|(_, value)| *value).sum::<u64>() + fee;
         let mut transaction = Transaction::new();
         for fund in &self.funds {
             total_input += fund.value;
             transaction.add_input(self.get_input(fund));
             if total_input >= total_output {
 
-                for (reciever, value) in recievers {
+                for (script, value) in outputs {
                     transaction.add_output(TxOutput {
                         value: *value,
-                        script_pubkey: reciever.clone(),
+                        script_pubkey: script.clone(),
                         spent: false,
                     });
                 }
@@ -52,7 +69,7 @@ impl User {
                 if change != 0 {
                     transaction.add_output(TxOutput {
                         value: change,
-                        script_pubkey: self.public_key.clone(),
+                        script_pubkey: Script::p2pkh(&self.public_key),
                         spent: false,
                     });
                 }
@@ -68,26 +85,46 @@ impl User {
         TxInput {
             txid: fund.txid.clone(),
             vout: fund.vout,
-            script_sig: (AffinePoint::infinity(), self.public_key.clone()),
+            script_sig: Script::new(),
+            sequence: SEQUENCE_FINAL,
         }
     }
 
+    // Signature over one input, script_code is the locking script of the
+    // output it spends. Several users can sign the same input this way.
+    pub fn sign_input(&self, transaction: &Transaction, idx: usize, script_code: &Script) -> AffinePoint {
+        let hash = transaction.get_input_hash(idx, script_code);
+        ecdsa::sign(hash.bytes(), &self.private_key)
+    }
+
+    // Same for an input spending an output locked to the Schnorr key of this
+    // user, see Script::schnorr
+    pub fn sign_schnorr_input(&self, transaction: &Transaction, idx: usize, script_code: &Script) -> SchnorrSignature {
+        let hash = transaction.get_input_hash(idx, script_code);
+        schnorr::sign(hash.bytes(), &self.private_key)
+    }
+
+    // Every input spends a pay to public key hash output of this user. The
+    // signatures carry the recovery id so blocks can check them in a batch.
     fn sign_transaction(&self, transaction: &Transaction) -> Transaction {
+        let script_code = Script::p2pkh(&self.public_key);
         let mut signed_transaction = transaction.clone();
         for (i, input) in signed_transaction.inputs.iter_mut().enumerate() {
-            let hash = transaction.get_input_hash(i, &self.public_key);
-            input.script_sig.0 = ecdsa::sign(hash.bytes(), &self.private_key);
+            let hash = transaction.get_input_hash(i, &script_code);
+            let signature = ecdsa::sign_recoverable(hash.bytes(), &self.private_key);
+            input.script_sig = Script::p2pkh_unlock_recoverable(&signature, &self.public_key);
         }
         signed_transaction
     }
 
     pub fn update_funds(&mut self, tx: &Transaction) {
         let txid = tx.hash();
+        let script = Script::p2pkh(&self.public_key);
         let mut value = 0;
         let mut vout = 0;
 
         for (i, output) in tx.outputs.iter().enumerate() {
-            if output.script_pubkey == self.public_key {
+            if output.script_pubkey == script {
                 value += output.value;
                 vout = i as u32;
             }
@@ -133,6 +170,10 @@ impl User {
 
 #[cfg(test)]
 mod tests {
+    use crate::{
+        blockchain::{address::{Address, Network}, script::Instruction, Blockchain, TransactionError, MINING_REWARD},
+        ecdsa::RecoverableSignature, schnorr::SchnorrPublicKey
+    };
     use super::*;
 
     #[test]
@@ -178,10 +219,20 @@ mod tests {
         assert_eq!(transaction.outputs[0].value, 50);
         assert_eq!(transaction.outputs[1].value, user.get_funds() - 50);
         
+        let script_code = Script::p2pkh(&user.public_key);
         for (i, input) in transaction.inputs.iter().enumerate() {
-            let hash = transaction.get_input_hash(i, &user.public_key);
-            assert!(ecdsa::verify(input.script_sig.0, hash.bytes(), &user.public_key));
+            let hash = transaction.get_input_hash(i, &script_code);
+            let signature = match input.script_sig.instructions().next() {
+                Some(Ok(Instruction::Push(data))) => RecoverableSignature::from_bytes(data).unwrap(),
+                _ => panic!("Expected a signature push")
+            };
+            assert!(ecdsa::verify_recoverable(&signature, hash.bytes(), &user.public_key));
         }
+
+        // Paying the address of a key locks the coins the same way
+        let address = Address::from_pubkey(&recievers[0].0, Network::Main);
+        let by_address = user.try_transaction(&[(address, 50)]).unwrap();
+        assert_eq!(by_address.outputs[0].script_pubkey, transaction.outputs[0].script_pubkey);
     }
 
     #[test]
@@ -202,4 +253,31 @@ mod tests {
         let recievers3 = vec![(ecdsa::generate_keypair().0, 50)];
         assert!(user.try_transaction(&recievers3).is_ok()); // Should succeed with remaining funds
     }
+
+    #[test]
+    fn test_schnorr_spend() {
+        let mut alice = User::new("Alice", ecdsa::generate_keypair());
+        let bob = User::new("Bob", ecdsa::generate_keypair());
+        let mut blockchain = Blockchain::new(Transaction::get_coinbase(alice.public_key.clone(), MINING_REWARD));
+        alice.update_funds_from_chain(&blockchain.get_user_funds(&alice.public_key));
+
+        // Alice pays to the Schnorr key of Bob instead of his ECDSA key
+        let bob_key = SchnorrPublicKey::from_private_key(&bob.private_key);
+        let payment = alice.try_transaction(&[(bob_key.clone(), 30)]).unwrap();
+        let mut block = blockchain.create_block(Transaction::get_coinbase(alice.public_key.clone(), MINING_REWARD), vec![payment]);
+        block.mine();
+        blockchain.add_block(block).unwrap();
+        let lock = Script::schnorr(&bob_key);
+        let (txid, vout, value) = blockchain.get_script_funds(&lock)[0].clone();
+
+        let mut spend = Transaction::new();
+        spend.add_input(TxInput { txid, vout, script_sig: Script::new(), sequence: SEQUENCE_FINAL });
+        spend.add_output(TxOutput { value, script_pubkey: Script::p2pkh(&bob.public_key), spent: false });
+        let signature = alice.sign_schnorr_input(&spend, 0, &lock);
+        spend.inputs[0].script_sig = Script::schnorr_unlock(&signature);
+        assert_eq!(blockchain.verify_new_transaction(&spend), Err(TransactionError::InvalidSignature));
+        let signature = bob.sign_schnorr_input(&spend, 0, &lock);
+        spend.inputs[0].script_sig = Script::schnorr_unlock(&signature);
+        assert_eq!(blockchain.verify_new_transaction(&spend), Ok(0));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,104 @@
+use crate::{blockchain::{script::Script, transaction::Transaction}, ecdsa::{point::AffinePoint, ECDSAPublicKey}};
+use super::{User, UserError};
+
+// Collects the signatures for an input spending an output locked with
+// Script::multisig. Every signer signs the same input hash, the unlocking
+// script is built once enough of them have signed.
+pub struct MultisigSpend {
+    pub transaction: Transaction,
+    pub input: usize,
+    required: usize,
+    pubkeys: Vec<ECDSAPublicKey>,
+    signatures: Vec<Option<AffinePoint>>,
+}
+
+impl MultisigSpend {
+    pub fn new(transaction: Transaction, input: usize, required: usize, pubkeys: Vec<ECDSAPublicKey>) -> Self {
+        let signatures = vec![None; pubkeys.len()];
+        MultisigSpend { transaction, input, required, pubkeys, signatures }
+    }
+
+    pub fn script_code(&self) -> Script {
+        Script::multisig(self.required, &self.pubkeys)
+    }
+
+    pub fn add_signature(&mut self, user: &User) -> Result<(), UserError> {
+        let idx = self.pubkeys.iter().position(|pubkey| *pubkey == user.public_key).ok_or(UserError::NotASigner)?;
+        self.signatures[idx] = Some(user.sign_input(&self.transaction, self.input, &self.script_code()));
+        Ok(())
+    }
+
+    pub fn signature_count(&self) -> usize {
+        self.signatures.iter().filter(|signature| signature.is_some()).count()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.signature_count() >= self.required
+    }
+
+    // The signatures have to be in the same order as the keys in the script,
+    // extra signatures beyond the required ones are left out
+    pub fn finalize(mut self) -> Result<Transaction, UserError> {
+        if !self.is_complete() {
+            return Err(UserError::NotEnoughSignatures);
+        }
+        let signatures: Vec<AffinePoint> = self.signatures.iter().flatten().take(self.required).copied().collect();
+        self.transaction.inputs[self.input].script_sig = Script::multisig_unlock(&signatures);
+        Ok(self.transaction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        blockchain::{transaction::{TxInput, TxOutput, SEQUENCE_FINAL}, Blockchain, TransactionError, MINING_REWARD},
+        ecdsa
+    };
+    use super::*;
+
+    #[test]
+    fn test_treasury_spend() {
+        let mut alice = User::new("Alice", ecdsa::generate_keypair());
+        let bob = User::new("Bob", ecdsa::generate_keypair());
+        let carol = User::new("Carol", ecdsa::generate_keypair());
+        let mut blockchain = Blockchain::new(Transaction::get_coinbase(alice.public_key.clone(), MINING_REWARD));
+        alice.update_funds_from_chain(&blockchain.get_user_funds(&alice.public_key));
+
+        // Alice funds a 2 of 3 treasury
+        let pubkeys = vec![alice.public_key.clone(), bob.public_key.clone(), carol.public_key.clone()];
+        let treasury = Script::multisig(2, &pubkeys);
+        let deposit = alice.try_script_transaction(&[(treasury.clone(), 30)], 0).unwrap();
+        let mut block = blockchain.create_block(Transaction::get_coinbase(alice.public_key.clone(), MINING_REWARD), vec![deposit]);
+        block.mine();
+        blockchain.add_block(block).unwrap();
+        let funds = blockchain.get_script_funds(&treasury);
+        assert_eq!(funds.len(), 1);
+        let (txid, vout, value) = funds[0].clone();
+
+        let recipient = ecdsa::generate_keypair().0;
+        let mut tx = Transaction::new();
+        tx.add_input(TxInput { txid, vout, script_sig: Script::new(), sequence: SEQUENCE_FINAL });
+        tx.add_output(TxOutput { value, script_pubkey: Script::p2pkh(&recipient), spent: false });
+
+        let mut spend = MultisigSpend::new(tx.clone(), 0, 2, pubkeys.clone());
+        assert_eq!(spend.add_signature(&User::new("Mallory", ecdsa::generate_keypair())), Err(UserError::NotASigner));
+        spend.add_signature(&carol).unwrap();
+        assert!(!spend.is_complete());
+
+        // Signing twice does not count as two signers
+        let mut once = MultisigSpend::new(tx.clone(), 0, 2, pubkeys.clone());
+        once.add_signature(&carol).unwrap();
+        once.add_signature(&carol).unwrap();
+        assert_eq!(once.finalize().err(), Some(UserError::NotEnoughSignatures));
+
+        let signature = carol.sign_input(&tx, 0, &treasury);
+        let mut repeated = tx.clone();
+        repeated.inputs[0].script_sig = Script::multisig_unlock(&[signature, signature]);
+        assert_eq!(blockchain.verify_new_transaction(&repeated), Err(TransactionError::InvalidSignature));
+
+        // Signatures are ordered by key even if Alice signs last
+        spend.add_signature(&alice).unwrap();
+        let signed = spend.finalize().unwrap();
+        assert_eq!(blockchain.verify_new_transaction(&signed), Ok(0));
+    }
+}
@@ -0,0 +1,108 @@
+use crate::sha256::Sha256;
+
+// Leaves out 0, O, I and l, which are easily mistaken for each other
+const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+const CHECKSUM_LEN: usize = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum Base58Error {
+    InvalidCharacter(char),
+    InvalidChecksum,
+    TooShort,
+}
+
+// The data as a big endian number in base 58, every leading zero byte is
+// kept as a leading '1'
+pub fn encode(data: &[u8]) -> String {
+    let zeros = data.iter().take_while(|&&byte| byte == 0).count();
+    let mut digits: Vec<u8> = Vec::new(); // Least significant first
+    for &byte in &data[zeros..] {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    let mut encoded = "1".repeat(zeros);
+    encoded.extend(digits.iter().rev().map(|&digit| ALPHABET[digit as usize] as char));
+    encoded
+}
+
+pub fn decode(s: &str) -> Result<Vec<u8>, Base58Error> {
+    let zeros = s.chars().take_while(|&c| c == '1').count();
+    let mut bytes: Vec<u8> = Vec::new(); // Least significant first
+    for c in s.chars().skip(zeros) {
+        let mut carry = ALPHABET.iter().position(|&digit| digit as char == c).ok_or(Base58Error::InvalidCharacter(c))? as u32;
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = carry as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    let mut decoded = vec![0; zeros];
+    decoded.extend(bytes.iter().rev());
+    Ok(decoded)
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    Sha256::hash(Sha256::hash(payload).bytes()).bytes()[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+// Base58 with the first 4 bytes of the double SHA-256 of the payload appended
+pub fn encode_check(payload: &[u8]) -> String {
+    let mut data = payload.to_vec();
+    data.extend_from_slice(&checksum(payload));
+    encode(&data)
+}
+
+pub fn decode_check(s: &str) -> Result<Vec<u8>, Base58Error> {
+    let mut data = decode(s)?;
+    if data.len() < CHECKSUM_LEN {
+        return Err(Base58Error::TooShort);
+    }
+    let expected = data.split_off(data.len() - CHECKSUM_LEN);
+    if checksum(&data) != *expected {
+        return Err(Base58Error::InvalidChecksum);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::util::hex_decode;
+    use super::*;
+
+    #[test]
+    fn test_base58_vectors() {
+        let vectors = [
+            ("", ""),
+            ("61", "2g"),
+            ("626262", "a3gV"),
+            ("0000287fb4cd", "11233QC4"),
+            ("48656c6c6f20576f726c6421", "2NEpo7TZRRrLZSi2U"),
+            ("00000000000000000000", "1111111111"),
+        ];
+        for (hex, encoded) in vectors {
+            let data = hex_decode(hex).unwrap();
+            assert_eq!(encode(&data), encoded);
+            assert_eq!(decode(encoded), Ok(data));
+        }
+        assert_eq!(decode("3SEo3LWLoPntC0"), Err(Base58Error::InvalidCharacter('0')));
+
+        let address = "1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa";
+        let payload = decode_check(address).unwrap();
+        assert_eq!(payload, hex_decode("0062e907b15cbf27d5425399ebf6f0fb50ebb88f18").unwrap());
+        assert_eq!(encode_check(&payload), address);
+        assert_eq!(decode_check("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb"), Err(Base58Error::InvalidChecksum));
+        assert_eq!(decode_check("1A"), Err(Base58Error::TooShort));
+    }
+}
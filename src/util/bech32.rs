@@ -0,0 +1,161 @@
+// Bech32 as specified in BIP 173. A human readable part, the separator '1',
+// the data in base 32 and a 6 character BCH checksum that detects any error
+// in up to 4 characters.
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+const SEPARATOR: char = '1';
+const CHECKSUM_LEN: usize = 6;
+pub const MAX_LENGTH: usize = 90;
+
+#[derive(Debug, PartialEq)]
+pub enum Bech32Error {
+    MixedCase,
+    InvalidCharacter(char),
+    MissingSeparator,
+    InvalidLength,
+    InvalidChecksum,
+    InvalidPadding,
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    let mut checksum = 1u32;
+    for &value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ffffff) << 5) ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|c| c & 31));
+    expanded
+}
+
+fn create_checksum(hrp: &str, data: &[u8]) -> [u8; CHECKSUM_LEN] {
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(data);
+    values.extend_from_slice(&[0; CHECKSUM_LEN]);
+    let checksum = polymod(&values) ^ 1;
+    std::array::from_fn(|i| ((checksum >> (5 * (5 - i))) & 31) as u8)
+}
+
+// Regroups the bits of data from from bits per value to to bits per value.
+// Without padding the leftover bits have to be fewer than from and all zero.
+pub fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let mut acc = 0u32;
+    let mut bits = 0;
+    let mut converted = Vec::new();
+    let max = (1 << to) - 1;
+    for &value in data {
+        if (value as u32) >> from != 0 {
+            return None;
+        }
+        acc = (acc << from) | value as u32;
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            converted.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            converted.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || ((acc << (to - bits)) & max) != 0 {
+        return None;
+    }
+    Some(converted)
+}
+
+// Encodes 5 bit values, hrp has to be lower case
+pub fn encode_5bit(hrp: &str, data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(hrp.len() + 1 + data.len() + CHECKSUM_LEN);
+    encoded.push_str(hrp);
+    encoded.push(SEPARATOR);
+    for &value in data.iter().chain(create_checksum(hrp, data).iter()) {
+        encoded.push(CHARSET[value as usize] as char);
+    }
+    encoded
+}
+
+// Returns the lower case human readable part and the 5 bit values
+pub fn decode_5bit(s: &str) -> Result<(String, Vec<u8>), Bech32Error> {
+    if s.len() > MAX_LENGTH {
+        return Err(Bech32Error::InvalidLength);
+    }
+    if let Some(c) = s.chars().find(|c| !(33..=126).contains(&(*c as u32))) {
+        return Err(Bech32Error::InvalidCharacter(c));
+    }
+    if s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase()) {
+        return Err(Bech32Error::MixedCase);
+    }
+    let s = s.to_ascii_lowercase();
+    let (hrp, data) = s.rsplit_once(SEPARATOR).ok_or(Bech32Error::MissingSeparator)?;
+    if hrp.is_empty() || data.len() < CHECKSUM_LEN {
+        return Err(Bech32Error::InvalidLength);
+    }
+    let data = data.chars()
+        .map(|c| CHARSET.iter().position(|&digit| digit as char == c).map(|value| value as u8).ok_or(Bech32Error::InvalidCharacter(c)))
+        .collect::<Result<Vec<u8>, Bech32Error>>()?;
+
+    let mut values = hrp_expand(hrp);
+    values.extend_from_slice(&data);
+    if polymod(&values) != 1 {
+        return Err(Bech32Error::InvalidChecksum);
+    }
+    Ok((hrp.to_string(), data[..data.len() - CHECKSUM_LEN].to_vec()))
+}
+
+pub fn encode(hrp: &str, data: &[u8]) -> String {
+    encode_5bit(hrp, &convert_bits(data, 8, 5, true).unwrap())
+}
+
+pub fn decode(s: &str) -> Result<(String, Vec<u8>), Bech32Error> {
+    let (hrp, data) = decode_5bit(s)?;
+    let data = convert_bits(&data, 5, 8, false).ok_or(Bech32Error::InvalidPadding)?;
+    Ok((hrp, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bech32_vectors() {
+        let valid = [
+            "A12UEL5L",
+            "a12uel5l",
+            "an83characterlonghumanreadablepartthatcontainsthenumber1andtheexcludedcharactersbio1tt5tgs",
+            "abcdef1qpzry9x8gf2tvdw0s3jn54khce6mua7lmqqqxw",
+            "11qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqc8247j",
+            "split1checkupstagehandshakeupstreamerranterredcaperred2y9e3w",
+            "?1ezyfcl",
+        ];
+        for s in valid {
+            let (hrp, data) = decode_5bit(s).unwrap();
+            assert_eq!(encode_5bit(&hrp, &data), s.to_ascii_lowercase());
+        }
+
+        assert_eq!(decode_5bit("pzry9x0s0muk"), Err(Bech32Error::MissingSeparator));
+        assert_eq!(decode_5bit("1pzry9x0s0muk"), Err(Bech32Error::InvalidLength));
+        assert_eq!(decode_5bit("x1b4n0q5v"), Err(Bech32Error::InvalidCharacter('b')));
+        assert_eq!(decode_5bit("li1dgmt3"), Err(Bech32Error::InvalidLength));
+        assert_eq!(decode_5bit("A1G7SGD8"), Err(Bech32Error::InvalidChecksum));
+        assert_eq!(decode_5bit("a12UEL5L"), Err(Bech32Error::MixedCase));
+        assert_eq!(decode_5bit(&format!("a1{}", "q".repeat(90))), Err(Bech32Error::InvalidLength));
+
+        // Bytes round trip through the 5 bit groups
+        let data: Vec<u8> = (0..32).collect();
+        let encoded = encode("test", &data);
+        assert_eq!(decode(&encoded), Ok(("test".to_string(), data)));
+        assert_eq!(decode("a12uel5l"), Ok(("a".to_string(), vec![])));
+        assert_eq!(decode(&encode_5bit("a", &[31])), Err(Bech32Error::InvalidPadding));
+    }
+}
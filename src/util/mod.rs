@@ -1,6 +1,9 @@
 use crate::math::big_int::BigInt;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+pub mod base58;
+pub mod bech32;
+
 const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
 
 pub fn base64_encode(data: &[u8]) -> String {
@@ -141,6 +144,25 @@ pub fn der_decode<const T: usize>(der: &[u8]) -> Vec<BigInt<T>> {
     fields
 }
 
+pub fn hex_encode(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Accepts upper and lower case digits, returns None for anything else
+pub fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let high = (pair[0] as char).to_digit(16)?;
+            let low = (pair[1] as char).to_digit(16)?;
+            Some((high * 16 + low) as u8)
+        })
+        .collect()
+}
+
 pub fn timestamp() -> u64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)